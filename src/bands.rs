@@ -0,0 +1,104 @@
+//! Standard 5.8 GHz video channel tables, in MHz.
+
+pub struct Band {
+    pub name: &'static str,
+    /// Single-letter code used on VTX labels and OSD menus.
+    pub code: char,
+    pub channels: [u16; 8],
+}
+
+pub const BANDS: &[Band] = &[
+    Band {
+        name: "Raceband",
+        code: 'R',
+        channels: [5658, 5695, 5732, 5769, 5806, 5843, 5880, 5917],
+    },
+    Band {
+        name: "Fatshark",
+        code: 'F',
+        channels: [5740, 5760, 5780, 5800, 5820, 5840, 5860, 5880],
+    },
+    Band {
+        name: "Boscam A",
+        code: 'A',
+        channels: [5865, 5845, 5825, 5805, 5785, 5765, 5745, 5725],
+    },
+    Band {
+        name: "Boscam B",
+        code: 'B',
+        channels: [5733, 5752, 5771, 5790, 5809, 5828, 5847, 5866],
+    },
+    Band {
+        name: "Boscam E",
+        code: 'E',
+        channels: [5705, 5685, 5665, 5645, 5885, 5905, 5925, 5945],
+    },
+    Band {
+        name: "Low Race",
+        code: 'L',
+        channels: [5362, 5399, 5436, 5473, 5510, 5547, 5584, 5621],
+    },
+];
+
+/// Range the RX5808 can be tuned to.
+pub const MIN_FREQUENCY: u16 = 5300;
+pub const MAX_FREQUENCY: u16 = 6000;
+
+/// Look up a band by its code or name (case-insensitive).
+pub fn band(name: &str) -> Option<&'static Band> {
+    BANDS.iter().find(|band| {
+        band.name.eq_ignore_ascii_case(name)
+            || (name.len() == 1 && name.starts_with(|c: char| c.eq_ignore_ascii_case(&band.code)))
+    })
+}
+
+/// Frequency of a 1-based channel in the given band.
+pub fn frequency(band_name: &str, channel: u8) -> Option<u16> {
+    let band = band(band_name)?;
+    let index = usize::from(channel).checked_sub(1)?;
+    band.channels.get(index).copied()
+}
+
+/// Every band and 1-based channel that uses `frequency`, e.g. `R7` and `F8`
+/// both map to 5880 MHz.
+pub fn channels_for(frequency: u16) -> Vec<(&'static Band, u8)> {
+    BANDS
+        .iter()
+        .flat_map(|band| {
+            band.channels
+                .iter()
+                .enumerate()
+                .filter(move |(_, f)| **f == frequency)
+                .map(move |(i, _)| (band, i as u8 + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_by_code_and_name() {
+        assert_eq!(frequency("R", 1), Some(5658));
+        assert_eq!(frequency("raceband", 8), Some(5917));
+        assert_eq!(frequency("Boscam E", 4), Some(5645));
+        assert_eq!(frequency("l", 2), Some(5399));
+    }
+
+    #[test]
+    fn rejects_out_of_range_channels() {
+        assert_eq!(frequency("R", 0), None);
+        assert_eq!(frequency("R", 9), None);
+        assert_eq!(frequency("X", 1), None);
+    }
+
+    #[test]
+    fn finds_shared_frequencies() {
+        let codes: Vec<_> = channels_for(5880)
+            .into_iter()
+            .map(|(band, channel)| format!("{}{}", band.code, channel))
+            .collect();
+        assert_eq!(codes, vec!["R7", "F8"]);
+    }
+}
@@ -0,0 +1,338 @@
+//! Maps a node's `millis()` clock onto the host clock.
+//!
+//! Each sync sample pairs a node clock reading with the host times at which
+//! the request was sent and the answer received. The node is assumed to have
+//! read its clock halfway through that round trip. Round trips well above the
+//! fastest one seen are queueing on the USB link rather than transfer time, so
+//! those samples are left out. A least-squares line through the rest gives the
+//! offset of the node clock and its drift against the host.
+//!
+//! Streamed samples carry the node clock without a request to time. They are
+//! fed as readings sent and received at their arrival; among those the one
+//! delayed least on the way wins, so the fit follows the fastest transfers.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Number of samples the estimate is fitted over.
+const WINDOW: usize = 32;
+/// Samples closer together than this compete for one place in the window; the
+/// one with the fastest round trip is kept. Spreading the window out is what
+/// makes the drift measurable.
+const SAMPLE_SPACING: Duration = Duration::from_secs(1);
+/// Extra round trip, on top of twice the fastest one, a sample may take and
+/// still be used.
+const RTT_SLACK: Duration = Duration::from_millis(2);
+/// Node clock span the window must cover before drift is estimated; below it
+/// the node clock is assumed to run at the host rate.
+const MIN_DRIFT_SPAN: f64 = 5.0;
+
+/// One read of the node clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockReading {
+    /// Host time the request was written.
+    pub sent: Instant,
+    /// Node uptime in milliseconds.
+    pub millis: u32,
+    /// Host time the answer was received.
+    pub received: Instant,
+}
+
+impl ClockReading {
+    pub fn round_trip(&self) -> Duration {
+        self.received.saturating_duration_since(self.sent)
+    }
+
+    /// Host time at which the node most likely read its clock.
+    fn midpoint(&self) -> Instant {
+        self.sent + self.round_trip() / 2
+    }
+}
+
+/// Current fit of the node clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockEstimate {
+    /// How much faster the node clock runs than the host clock, in parts per
+    /// million.
+    pub drift_ppm: f64,
+    /// Fastest round trip in the window.
+    pub round_trip: Duration,
+    /// Samples the fit was made from.
+    pub samples: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SyncSample {
+    /// Node time in seconds, with `millis()` wraps unrolled.
+    node: f64,
+    /// Host time in seconds since the sync epoch.
+    host: f64,
+    round_trip: Duration,
+}
+
+impl SyncSample {
+    /// Host time past node time; lower means less delay between the node
+    /// reading its clock and the host receiving it.
+    fn delay(&self) -> f64 {
+        self.host - self.node
+    }
+}
+
+/// `host = intercept + slope * node`, both in seconds.
+#[derive(Debug, Clone, Copy)]
+struct Fit {
+    intercept: f64,
+    slope: f64,
+}
+
+/// Clock sync state of one node.
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    epoch: Option<Instant>,
+    /// Last node reading, as received and with wraps unrolled.
+    last_millis: Option<(u32, u64)>,
+    samples: VecDeque<SyncSample>,
+    fit: Option<Fit>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget everything, e.g. once the node has been power cycled and its
+    /// clock restarted.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Feed a clock reading and refit. A node clock that goes backwards means
+    /// the node restarted, so the older samples are dropped.
+    pub fn add(&mut self, reading: ClockReading) {
+        let epoch = *self.epoch.get_or_insert(reading.sent);
+        let node = match self.unroll(reading.millis) {
+            Some(node) => node,
+            None => {
+                self.reset();
+                return self.add(reading);
+            }
+        };
+        self.last_millis = Some((reading.millis, node));
+
+        let sample = SyncSample {
+            node: node as f64 / 1000.0,
+            host: seconds_since(epoch, reading.midpoint()),
+            round_trip: reading.round_trip(),
+        };
+        match self.samples.back_mut() {
+            Some(last) if sample.host - last.host < SAMPLE_SPACING.as_secs_f64() => {
+                if (sample.round_trip, sample.delay()) < (last.round_trip, last.delay()) {
+                    *last = sample;
+                }
+            }
+            _ => {
+                self.samples.push_back(sample);
+                if self.samples.len() > WINDOW {
+                    self.samples.pop_front();
+                }
+            }
+        }
+        self.fit = self.refit();
+    }
+
+    /// Node reading with wraps unrolled, relative to the last reading. `None`
+    /// when the clock went backwards.
+    fn unroll(&self, millis: u32) -> Option<u64> {
+        let Some((last, last_node)) = self.last_millis else {
+            return Some(millis as u64);
+        };
+        let delta = millis.wrapping_sub(last) as i32;
+        (delta >= 0).then(|| last_node + delta as u64)
+    }
+
+    fn refit(&self) -> Option<Fit> {
+        let fastest = self.samples.iter().map(|s| s.round_trip).min()?;
+        let limit = fastest * 2 + RTT_SLACK;
+        let used: Vec<&SyncSample> = self
+            .samples
+            .iter()
+            .filter(|s| s.round_trip <= limit)
+            .collect();
+
+        let n = used.len() as f64;
+        let mean_node = used.iter().map(|s| s.node).sum::<f64>() / n;
+        let mean_host = used.iter().map(|s| s.host).sum::<f64>() / n;
+        let span = used.last()?.node - used.first()?.node;
+        let slope = if span < MIN_DRIFT_SPAN {
+            1.0
+        } else {
+            let (covariance, variance) = used.iter().fold((0.0, 0.0), |(c, v), s| {
+                let dn = s.node - mean_node;
+                (c + dn * (s.host - mean_host), v + dn * dn)
+            });
+            covariance / variance
+        };
+        Some(Fit {
+            intercept: mean_host - slope * mean_node,
+            slope,
+        })
+    }
+
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        let fit = self.fit?;
+        Some(ClockEstimate {
+            drift_ppm: (1.0 / fit.slope - 1.0) * 1e6,
+            round_trip: self.samples.iter().map(|s| s.round_trip).min()?,
+            samples: self.samples.len(),
+        })
+    }
+
+    /// Host time of a node timestamp taken close to the last reading, or
+    /// `None` before the first reading.
+    pub fn to_host(&self, millis: u32) -> Option<Instant> {
+        let (epoch, fit, (last, last_node)) = (self.epoch?, self.fit?, self.last_millis?);
+        let node = last_node as f64 + millis.wrapping_sub(last) as i32 as f64;
+        let host = fit.intercept + fit.slope * node / 1000.0;
+        if host >= 0.0 {
+            epoch.checked_add(Duration::from_secs_f64(host))
+        } else {
+            epoch.checked_sub(Duration::from_secs_f64(-host))
+        }
+    }
+}
+
+fn seconds_since(epoch: Instant, instant: Instant) -> f64 {
+    match instant.checked_duration_since(epoch) {
+        Some(after) => after.as_secs_f64(),
+        None => -epoch.duration_since(instant).as_secs_f64(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read of a node whose clock started `offset_ms` before `start` and runs
+    /// `drift_ppm` fast, `at_ms` after `start`, with the given round trip.
+    fn reading(
+        start: Instant,
+        at_ms: f64,
+        offset_ms: f64,
+        drift_ppm: f64,
+        rtt_ms: f64,
+    ) -> ClockReading {
+        let sent = start + Duration::from_secs_f64(at_ms / 1000.0);
+        let millis = (at_ms + rtt_ms / 2.0) * (1.0 + drift_ppm / 1e6) + offset_ms;
+        ClockReading {
+            sent,
+            millis: millis.round() as u64 as u32,
+            received: sent + Duration::from_secs_f64(rtt_ms / 1000.0),
+        }
+    }
+
+    fn error_ms(sync: &ClockSync, millis: u32, expected: Instant) -> f64 {
+        let host = sync.to_host(millis).unwrap();
+        seconds_since(expected, host) * 1000.0
+    }
+
+    #[test]
+    fn compensates_round_trip() {
+        let start = Instant::now();
+        let mut sync = ClockSync::new();
+        sync.add(reading(start, 0.0, 5000.0, 0.0, 4.0));
+
+        // The node read its clock 2 ms after the request went out.
+        assert!(error_ms(&sync, 5002, start + Duration::from_millis(2)).abs() < 0.01);
+        assert!(error_ms(&sync, 6002, start + Duration::from_millis(1002)).abs() < 0.01);
+    }
+
+    #[test]
+    fn estimates_drift() {
+        let start = Instant::now();
+        let mut sync = ClockSync::new();
+        for step in 0..20 {
+            sync.add(reading(start, step as f64 * 1500.0, 123_456.0, 500.0, 2.0));
+        }
+
+        let estimate = sync.estimate().unwrap();
+        assert!((estimate.drift_ppm - 500.0).abs() < 20.0, "{:?}", estimate);
+        assert_eq!(estimate.samples, 20);
+
+        // One minute on, the drift is extrapolated.
+        let node = reading(start, 60_000.0, 123_456.0, 500.0, 0.0).millis;
+        assert!(error_ms(&sync, node, start + Duration::from_secs(60)).abs() < 1.0);
+    }
+
+    #[test]
+    fn ignores_slow_round_trips() {
+        let start = Instant::now();
+        let mut sync = ClockSync::new();
+        for second in 0..10 {
+            let rtt = if second % 3 == 1 { 40.0 } else { 2.0 };
+            // A delayed request: the node answers late, as if sent 20 ms after
+            // it really was.
+            let mut read = reading(start, second as f64 * 1000.0 + rtt - 2.0, 0.0, 0.0, 2.0);
+            read.sent = start + Duration::from_millis(second * 1000);
+            sync.add(read);
+        }
+
+        assert_eq!(
+            sync.estimate().unwrap().round_trip,
+            Duration::from_millis(2)
+        );
+        assert!(error_ms(&sync, 10_000, start + Duration::from_secs(10)).abs() < 0.5);
+    }
+
+    #[test]
+    fn keeps_the_fastest_sample_in_each_spacing() {
+        let start = Instant::now();
+        let mut sync = ClockSync::new();
+        sync.add(reading(start, 0.0, 0.0, 0.0, 8.0));
+        sync.add(reading(start, 10.0, 0.0, 0.0, 2.0));
+        sync.add(reading(start, 20.0, 0.0, 0.0, 6.0));
+
+        let estimate = sync.estimate().unwrap();
+        assert_eq!(estimate.samples, 1);
+        assert_eq!(estimate.round_trip, Duration::from_millis(2));
+    }
+
+    #[test]
+    fn keeps_the_least_delayed_arrival() {
+        let start = Instant::now();
+        let mut sync = ClockSync::new();
+        // Frames sampled 10 ms apart arrive together in one read.
+        for (sampled, delay) in [(0.0, 25.0), (10.0, 15.0), (20.0, 5.0)] {
+            let at = start + Duration::from_secs_f64((sampled + delay) / 1000.0);
+            sync.add(ClockReading {
+                sent: at,
+                millis: 7000 + sampled as u32,
+                received: at,
+            });
+        }
+
+        assert_eq!(sync.estimate().unwrap().samples, 1);
+        assert!(error_ms(&sync, 7000, start + Duration::from_millis(5)).abs() < 0.01);
+    }
+
+    #[test]
+    fn unrolls_millis_wrap() {
+        let start = Instant::now();
+        let mut sync = ClockSync::new();
+        sync.add(reading(start, 0.0, u32::MAX as f64 - 999.0, 0.0, 0.0));
+        sync.add(reading(start, 2000.0, u32::MAX as f64 - 999.0, 0.0, 0.0));
+
+        assert_eq!(sync.estimate().unwrap().samples, 2);
+        assert!(error_ms(&sync, 1000, start + Duration::from_secs(2)).abs() < 0.01);
+    }
+
+    #[test]
+    fn restarts_when_the_node_clock_goes_back() {
+        let start = Instant::now();
+        let mut sync = ClockSync::new();
+        sync.add(reading(start, 0.0, 50_000.0, 0.0, 0.0));
+        sync.add(reading(start, 2000.0, -2000.0, 0.0, 0.0));
+
+        assert_eq!(sync.estimate().unwrap().samples, 1);
+        assert!(error_ms(&sync, 0, start + Duration::from_secs(2)).abs() < 0.01);
+    }
+}
@@ -0,0 +1,154 @@
+//! Finding timing nodes among the serial ports of the machine.
+//!
+//! Nodes are Arduinos behind a USB-serial chip, so candidates are the USB
+//! ports whose VID/PID belong to one of those chips. A candidate is only a
+//! node if it answers the version command with node firmware, or times out
+//! on it like firmware predating the command, see [`Firmware::detect`].
+
+use crate::firmware::{Firmware, FirmwareError};
+use crate::protocol::{self, ProtocolError};
+use serialport::{SerialPort, SerialPortType};
+use std::io::{self, Read};
+use std::thread;
+use std::time::Duration;
+
+/// USB-serial chips found on Arduino boards and clones: VID, PID and name.
+pub const KNOWN_CHIPS: &[(u16, u16, &str)] = &[
+    (0x1A86, 0x7523, "CH340"),
+    (0x1A86, 0x5523, "CH341"),
+    (0x10C4, 0xEA60, "CP210x"),
+    (0x0403, 0x6001, "FT232R"),
+    (0x0403, 0x6015, "FT231X"),
+    (0x067B, 0x2303, "PL2303"),
+    (0x2341, 0x0043, "Arduino Uno"),
+    (0x2341, 0x0001, "Arduino Uno"),
+    (0x2341, 0x0042, "Arduino Mega 2560"),
+    (0x2341, 0x8036, "Arduino Leonardo"),
+];
+
+/// Name of the USB-serial chip with this VID/PID, if it is a known one.
+pub fn chip_name(vid: u16, pid: u16) -> Option<&'static str> {
+    KNOWN_CHIPS
+        .iter()
+        .find(|(known_vid, known_pid, _)| *known_vid == vid && *known_pid == pid)
+        .map(|(_, _, name)| *name)
+}
+
+/// A serial port that may have a node behind it.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub port_name: String,
+    pub vid: u16,
+    pub pid: u16,
+    pub chip: &'static str,
+    /// USB serial number. Follows the device when it is plugged into another
+    /// port, unlike the port name.
+    pub serial_number: Option<String>,
+}
+
+/// A candidate found running node firmware.
+#[derive(Debug, Clone)]
+pub struct DiscoveredNode {
+    pub candidate: Candidate,
+    pub firmware: Firmware,
+}
+
+/// USB ports with a known USB-serial chip, sorted by port name.
+pub fn candidates() -> Result<Vec<Candidate>, serialport::Error> {
+    let mut candidates: Vec<Candidate> = serialport::available_ports()?
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            SerialPortType::UsbPort(info) => Some(Candidate {
+                chip: chip_name(info.vid, info.pid)?,
+                port_name: port.port_name,
+                vid: info.vid,
+                pid: info.pid,
+                serial_number: info.serial_number,
+            }),
+            _ => None,
+        })
+        .collect();
+    candidates.sort_by(|a, b| a.port_name.cmp(&b.port_name));
+    // macOS lists each device as both /dev/tty.* and /dev/cu.*; keep one.
+    candidates.retain(|candidate| !candidate.port_name.starts_with("/dev/tty."));
+    Ok(candidates)
+}
+
+/// The candidate port currently holding the device with this USB serial
+/// number.
+pub fn find_by_serial_number(serial_number: &str) -> Result<Option<Candidate>, serialport::Error> {
+    Ok(candidates()?
+        .into_iter()
+        .find(|candidate| candidate.serial_number.as_deref() == Some(serial_number)))
+}
+
+/// Open a node's serial port and discard whatever the Arduino printed while
+/// booting (opening the port resets it).
+pub fn open_port(port_name: &str, baud_rate: u32) -> io::Result<Box<dyn SerialPort>> {
+    let mut port = serialport::new(port_name, baud_rate)
+        .dtr_on_open(true)
+        .timeout(Duration::from_millis(5000))
+        .data_bits(serialport::DataBits::Eight)
+        .flow_control(serialport::FlowControl::None)
+        .parity(serialport::Parity::None)
+        .stop_bits(serialport::StopBits::One)
+        .open()?;
+
+    let mut discard_buffer = [0u8; 256];
+    while let Ok(bytes_read) = port.read(&mut discard_buffer) {
+        if bytes_read == 0 {
+            break;
+        }
+        println!("Cleared {} bytes from input buffer", bytes_read);
+    }
+
+    println!("Arduino should be ready now");
+    Ok(port)
+}
+
+/// Open the candidate and detect its firmware from the version command.
+pub fn probe(candidate: &Candidate, baud_rate: u32) -> Result<Firmware, FirmwareError> {
+    let mut port = open_port(&candidate.port_name, baud_rate).map_err(ProtocolError::from)?;
+    Firmware::detect(protocol::read_version(&mut port))
+}
+
+/// Probe every candidate, in parallel since opening a port waits for the
+/// Arduino to boot, and return those running node firmware.
+pub fn discover(baud_rate: u32) -> Result<Vec<DiscoveredNode>, serialport::Error> {
+    let candidates = candidates()?;
+    let results: Vec<_> = thread::scope(|scope| {
+        let probes: Vec<_> = candidates
+            .iter()
+            .map(|candidate| scope.spawn(move || probe(candidate, baud_rate)))
+            .collect();
+        probes.into_iter().map(|probe| probe.join()).collect()
+    });
+
+    let mut nodes = Vec::new();
+    for (candidate, result) in candidates.into_iter().zip(results) {
+        match result {
+            Ok(Ok(firmware)) => nodes.push(DiscoveredNode {
+                candidate,
+                firmware,
+            }),
+            Ok(Err(e)) => eprintln!(
+                "{} ({}): no node firmware: {}",
+                candidate.port_name, candidate.chip, e
+            ),
+            Err(_) => eprintln!("{}: probe panicked", candidate.port_name),
+        }
+    }
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_chips() {
+        assert_eq!(chip_name(0x1A86, 0x7523), Some("CH340"));
+        assert_eq!(chip_name(0x0403, 0x6001), Some("FT232R"));
+        assert_eq!(chip_name(0x05AC, 0x8286), None);
+    }
+}
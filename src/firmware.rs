@@ -0,0 +1,226 @@
+//! Firmware versions and what each of them can do.
+//!
+//! The version command answers with a marker byte, `0x25`, followed by the
+//! API level of the firmware. Each level adds to the one before it:
+//!
+//! | Level | Adds                                                  |
+//! |-------|-------------------------------------------------------|
+//! | 1     | RSSI in the peak response, frequency read and tune    |
+//! | 2     | lap counter in the peak response, `millis()` read     |
+//! | 3     | several receiver slots per Arduino                    |
+//! | 4     | pushed sample stream, see [`crate::stream`]           |
+//!
+//! Firmware that predates the version command times out on it and is run at
+//! level 1. A level newer than this build knows is run with the newest known
+//! capabilities, which later levels keep. An answer without the marker is not
+//! node firmware at all and is refused.
+
+use crate::protocol::{NodeCommand, ProtocolError};
+use std::fmt;
+
+const VERSION_MARKER: u8 = 0x25;
+/// Newest API level this build knows about.
+pub const LATEST_API_LEVEL: u8 = 4;
+/// Version word reported by firmware at [`LATEST_API_LEVEL`].
+pub const LATEST_VERSION: u16 = u16::from_be_bytes([VERSION_MARKER, LATEST_API_LEVEL]);
+
+/// What a firmware supports beyond the level 1 basics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Answers [`NodeCommand::ReadMillis`].
+    pub millis: bool,
+    /// Fills `lap_id` and `ms_val` in the peak response; they are zero
+    /// otherwise.
+    pub lap_counter: bool,
+    /// Answers [`NodeCommand::ReadSlotCount`] and [`NodeCommand::SelectSlot`].
+    pub slots: bool,
+    /// Answers [`NodeCommand::SetStreaming`] and pushes stream frames.
+    pub streaming: bool,
+    /// Most receivers one Arduino can drive.
+    pub max_nodes: u8,
+}
+
+impl Capabilities {
+    fn for_level(level: u8) -> Self {
+        Capabilities {
+            millis: level >= 2,
+            lap_counter: level >= 2,
+            slots: level >= 3,
+            streaming: level >= 4,
+            max_nodes: if level >= 3 { 8 } else { 1 },
+        }
+    }
+
+    pub fn supports(&self, command: &NodeCommand) -> bool {
+        match command {
+            NodeCommand::ReadMillis => self.millis,
+            NodeCommand::ReadSlotCount | NodeCommand::SelectSlot(_) => self.slots,
+            NodeCommand::SetStreaming(_) => self.streaming,
+            NodeCommand::ReadPeak
+            | NodeCommand::ReadVersion
+            | NodeCommand::ReadFrequency
+            | NodeCommand::SetFrequency(_) => true,
+        }
+    }
+}
+
+/// Firmware detected on a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Firmware {
+    /// Version word as reported, `None` when the firmware has no version
+    /// command.
+    pub version: Option<u16>,
+    /// API level the device is driven at.
+    pub api_level: u8,
+    /// Whether this build knows the reported level; unknown newer levels are
+    /// driven at [`LATEST_API_LEVEL`].
+    pub known: bool,
+    pub capabilities: Capabilities,
+}
+
+#[derive(Debug)]
+pub enum FirmwareError {
+    /// The device could not be talked to.
+    Protocol(ProtocolError),
+    /// The device answered with a version that is not node firmware.
+    Unsupported(u16),
+}
+
+impl fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirmwareError::Protocol(e) => write!(f, "{}", e),
+            FirmwareError::Unsupported(version) => {
+                write!(f, "unsupported firmware version 0x{:04X}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FirmwareError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FirmwareError::Protocol(e) => Some(e),
+            FirmwareError::Unsupported(_) => None,
+        }
+    }
+}
+
+impl From<ProtocolError> for FirmwareError {
+    fn from(e: ProtocolError) -> Self {
+        FirmwareError::Protocol(e)
+    }
+}
+
+impl Firmware {
+    /// Firmware without the version command.
+    pub fn legacy() -> Self {
+        Firmware {
+            version: None,
+            api_level: 1,
+            known: true,
+            capabilities: Capabilities::for_level(1),
+        }
+    }
+
+    pub fn from_version(version: u16) -> Result<Self, FirmwareError> {
+        let [marker, level] = version.to_be_bytes();
+        if marker != VERSION_MARKER || level == 0 {
+            return Err(FirmwareError::Unsupported(version));
+        }
+        let api_level = level.min(LATEST_API_LEVEL);
+        Ok(Firmware {
+            version: Some(version),
+            api_level,
+            known: level <= LATEST_API_LEVEL,
+            capabilities: Capabilities::for_level(api_level),
+        })
+    }
+
+    /// Firmware from the outcome of the version command. A timeout means the
+    /// firmware predates the command.
+    pub fn detect(version: Result<u16, ProtocolError>) -> Result<Self, FirmwareError> {
+        match version {
+            Ok(version) => Firmware::from_version(version),
+            Err(ProtocolError::Timeout) => Ok(Firmware::legacy()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl fmt::Display for Firmware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.version {
+            Some(version) if self.known => {
+                write!(f, "0x{:04X} (API level {})", version, self.api_level)
+            }
+            Some(version) => write!(
+                f,
+                "0x{:04X} (unknown, driven at API level {})",
+                version, self.api_level
+            ),
+            None => write!(f, "legacy (API level {})", self.api_level),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_levels_to_capabilities() {
+        let firmware = Firmware::from_version(0x2503).unwrap();
+        assert_eq!(firmware.api_level, 3);
+        assert!(firmware.known);
+        assert!(firmware.capabilities.supports(&NodeCommand::SelectSlot(2)));
+        assert_eq!(firmware.capabilities.max_nodes, 8);
+        assert!(!firmware.capabilities.streaming);
+
+        let firmware = Firmware::from_version(0x2504).unwrap();
+        assert!(
+            firmware
+                .capabilities
+                .supports(&NodeCommand::SetStreaming(true))
+        );
+
+        let firmware = Firmware::from_version(0x2502).unwrap();
+        assert!(firmware.capabilities.supports(&NodeCommand::ReadMillis));
+        assert!(!firmware.capabilities.supports(&NodeCommand::ReadSlotCount));
+        assert_eq!(firmware.capabilities.max_nodes, 1);
+    }
+
+    #[test]
+    fn drives_newer_levels_at_the_latest_known() {
+        let firmware = Firmware::from_version(0x2509).unwrap();
+        assert!(!firmware.known);
+        assert_eq!(firmware.api_level, LATEST_API_LEVEL);
+        assert_eq!(firmware.version, Some(0x2509));
+    }
+
+    #[test]
+    fn refuses_versions_without_the_marker() {
+        assert!(matches!(
+            Firmware::from_version(0x0103),
+            Err(FirmwareError::Unsupported(0x0103))
+        ));
+        assert!(matches!(
+            Firmware::from_version(0x2500),
+            Err(FirmwareError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn treats_a_timeout_as_legacy_firmware() {
+        let firmware = Firmware::detect(Err(ProtocolError::Timeout)).unwrap();
+        assert_eq!(firmware, Firmware::legacy());
+        assert!(!firmware.capabilities.supports(&NodeCommand::ReadMillis));
+        assert!(matches!(
+            Firmware::detect(Err(ProtocolError::Checksum {
+                expected: 1,
+                received: 2
+            })),
+            Err(FirmwareError::Protocol(_))
+        ));
+    }
+}
@@ -0,0 +1,274 @@
+//! Nodes on an I2C bus, as on Raspberry Pi boards carrying several Arduinos.
+//!
+//! Each Arduino answers at its own bus address and speaks the same commands
+//! as on the serial line: the host writes a command frame in one write
+//! transfer, then reads the response frame in one read transfer. [`I2cNode`]
+//! wraps one address of a bus in `Read + Write`, so the functions in
+//! [`crate::protocol`] drive it unchanged.
+//!
+//! A node with no response ready does not hold the bus; the read clocks in
+//! `0xFF` from the pulled-up data line. Such a read is reported as a timeout,
+//! as silence would be on the serial line, so firmware detection and retries
+//! behave the same on both.
+//!
+//! A bus is shared by all the nodes on it; wrap it in `Arc<Mutex<_>>`, which
+//! is itself a bus, and hand each node a clone.
+
+use crate::protocol::{self, NodeCommand, NodeResponse, ProtocolError};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Byte read from a device that has nothing to send: SDA stays pulled up.
+const IDLE_BYTE: u8 = 0xFF;
+
+/// Transfers addressed to one device at a time.
+pub trait I2cBus: Send {
+    /// Write `bytes` to the device at `address` in one transfer.
+    fn write(&mut self, address: u8, bytes: &[u8]) -> io::Result<()>;
+
+    /// Fill `buffer` from the device at `address` in one transfer.
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> io::Result<()>;
+}
+
+impl<B: I2cBus> I2cBus for Arc<Mutex<B>> {
+    fn write(&mut self, address: u8, bytes: &[u8]) -> io::Result<()> {
+        lock(self)?.write(address, bytes)
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> io::Result<()> {
+        lock(self)?.read(address, buffer)
+    }
+}
+
+fn lock<B>(bus: &Mutex<B>) -> io::Result<std::sync::MutexGuard<'_, B>> {
+    bus.lock()
+        .map_err(|_| io::Error::other("I2C bus poisoned by a panicking user"))
+}
+
+/// One node on a bus.
+pub struct I2cNode<B> {
+    bus: B,
+    address: u8,
+}
+
+impl<B: I2cBus> I2cNode<B> {
+    pub fn new(bus: B, address: u8) -> Self {
+        I2cNode { bus, address }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+}
+
+impl<B: I2cBus> Read for I2cNode<B> {
+    /// Reads exactly `buf.len()` bytes: an I2C read always clocks out the
+    /// requested length.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.bus.read(self.address, buf)?;
+        if buf.iter().all(|&byte| byte == IDLE_BYTE) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "node had no response ready",
+            ));
+        }
+        Ok(buf.len())
+    }
+}
+
+impl<B: I2cBus> Write for I2cNode<B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bus.write(self.address, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A Linux I2C adapter through its `/dev/i2c-N` character device.
+pub struct LinuxBus {
+    file: File,
+    /// Address the adapter is currently set to talk to.
+    address: Option<u8>,
+}
+
+impl LinuxBus {
+    /// Open the adapter at `path`, e.g. `/dev/i2c-1` on a Raspberry Pi.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::options().read(true).write(true).open(path)?;
+        Ok(LinuxBus {
+            file,
+            address: None,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn select(&mut self, address: u8) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        /// `I2C_SLAVE` from `linux/i2c-dev.h`.
+        const I2C_SLAVE: libc::c_ulong = 0x0703;
+
+        if self.address == Some(address) {
+            return Ok(());
+        }
+        // SAFETY: the descriptor is open for as long as `self.file` lives, and
+        // I2C_SLAVE takes its argument by value.
+        let result = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                I2C_SLAVE as _,
+                address as libc::c_ulong,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.address = Some(address);
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn select(&mut self, _address: u8) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "i2c-dev is only available on Linux",
+        ))
+    }
+}
+
+impl I2cBus for LinuxBus {
+    fn write(&mut self, address: u8, bytes: &[u8]) -> io::Result<()> {
+        self.select(address)?;
+        self.file.write_all(bytes)
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> io::Result<()> {
+        self.select(address)?;
+        self.file.read_exact(buffer)
+    }
+}
+
+type Handler = Box<dyn FnMut(NodeCommand) -> Result<NodeResponse, ProtocolError> + Send>;
+
+/// In-process bus for tests: each address is served by a handler playing the
+/// node, like the stand-in node on TCP. Transfers to an address nobody
+/// answers at fail as a real adapter does on a NACK.
+#[derive(Default)]
+pub struct FakeBus {
+    nodes: HashMap<u8, Handler>,
+    /// Response each node has ready for the next read.
+    pending: HashMap<u8, Vec<u8>>,
+}
+
+impl FakeBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer at `address` with `handler`. A handler error leaves the request
+    /// unanswered, and the next read returns an idle bus.
+    pub fn add_node<H>(&mut self, address: u8, handler: H)
+    where
+        H: FnMut(NodeCommand) -> Result<NodeResponse, ProtocolError> + Send + 'static,
+    {
+        self.nodes.insert(address, Box::new(handler));
+    }
+
+    fn handler(&mut self, address: u8) -> io::Result<&mut Handler> {
+        self.nodes.get_mut(&address).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no device acknowledged address 0x{:02X}", address),
+            )
+        })
+    }
+}
+
+impl I2cBus for FakeBus {
+    fn write(&mut self, address: u8, mut bytes: &[u8]) -> io::Result<()> {
+        let handler = self.handler(address)?;
+        let response = match protocol::read_request(&mut bytes) {
+            Ok(Some(command)) => handler(command).ok(),
+            Ok(None) => None,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        match response {
+            Some(response) => self.pending.insert(address, response.encode()),
+            None => self.pending.remove(&address),
+        };
+        Ok(())
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> io::Result<()> {
+        self.handler(address)?;
+        let response = self.pending.remove(&address).unwrap_or_default();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = response.get(i).copied().unwrap_or(IDLE_BYTE);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Peak;
+
+    fn bus() -> Arc<Mutex<FakeBus>> {
+        let mut bus = FakeBus::new();
+        for address in [0x08, 0x0A] {
+            let mut frequency = 5658;
+            bus.add_node(address, move |command| match command {
+                NodeCommand::ReadPeak => Ok(NodeResponse::Peak(Peak {
+                    lap_id: 1,
+                    ms_val: 250,
+                    rssi: address,
+                })),
+                NodeCommand::ReadFrequency => Ok(NodeResponse::Frequency(frequency)),
+                NodeCommand::SetFrequency(f) => {
+                    frequency = f;
+                    Ok(NodeResponse::Ack)
+                }
+                _ => Err(ProtocolError::Timeout),
+            });
+        }
+        Arc::new(Mutex::new(bus))
+    }
+
+    #[test]
+    fn addresses_nodes_sharing_a_bus() {
+        let bus = bus();
+        let mut first = I2cNode::new(bus.clone(), 0x08);
+        let mut second = I2cNode::new(bus, 0x0A);
+
+        assert_eq!(protocol::read_peak(&mut first).unwrap().rssi, 0x08);
+        assert_eq!(protocol::read_peak(&mut second).unwrap().rssi, 0x0A);
+        assert_eq!(protocol::set_frequency(&mut second, 5917).unwrap(), 5917);
+        assert_eq!(protocol::read_frequency(&mut first).unwrap(), 5658);
+    }
+
+    #[test]
+    fn unanswered_requests_time_out() {
+        let mut node = I2cNode::new(bus(), 0x08);
+        assert!(matches!(
+            protocol::read_millis(&mut node),
+            Err(ProtocolError::Timeout)
+        ));
+        assert_eq!(protocol::read_peak(&mut node).unwrap().ms_val, 250);
+    }
+
+    #[test]
+    fn fails_on_absent_addresses() {
+        let mut node = I2cNode::new(bus(), 0x09);
+        assert!(matches!(
+            protocol::read_peak(&mut node),
+            Err(ProtocolError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,8 @@
+pub mod bands;
+pub mod clock;
+pub mod discovery;
+pub mod firmware;
+pub mod i2c;
+pub mod protocol;
+pub mod standin;
+pub mod stream;
@@ -1,206 +1,527 @@
+//! Node diagnostics for the field: list ports, check firmware, watch RSSI,
+//! check the node clock and poke the node with raw bytes.
+
+use rustimer::clock::{ClockReading, ClockSync};
+use rustimer::discovery;
+use rustimer::firmware::Firmware;
+use rustimer::protocol::{self, ProtocolError};
 use serialport::{self, SerialPort};
-use std::io::{Read, Write};
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
 
 use serialport::{SerialPortType, available_ports};
 
-fn list_ports() {
-    match available_ports() {
-        Ok(mut ports) => {
-            // Let's output ports in a stable order to facilitate comparing the output from
-            // different runs (on different platforms, with different features, ...).
-            ports.sort_by_key(|i| i.port_name.clone());
-
-            match ports.len() {
-                0 => println!("No ports found."),
-                1 => println!("Found 1 port:"),
-                n => println!("Found {} ports:", n),
-            };
-
-            for p in ports {
-                println!("    {}", p.port_name);
-                match p.port_type {
-                    SerialPortType::UsbPort(info) => {
-                        println!("        Type: USB");
-                        println!("        VID: {:04x}", info.vid);
-                        println!("        PID: {:04x}", info.pid);
-                        #[cfg(feature = "usbportinfo-interface")]
-                        println!(
-                            "        Interface: {}",
-                            info.interface
-                                .as_ref()
-                                .map_or("".to_string(), |x| format!("{:02x}", *x))
-                        );
-                        println!(
-                            "        Serial Number: {}",
-                            info.serial_number.as_ref().map_or("", String::as_str)
-                        );
-                        println!(
-                            "        Manufacturer: {}",
-                            info.manufacturer.as_ref().map_or("", String::as_str)
-                        );
-                        println!(
-                            "        Product: {}",
-                            info.product.as_ref().map_or("", String::as_str)
-                        );
-                    }
-                    SerialPortType::BluetoothPort => {
-                        println!("        Type: Bluetooth");
-                    }
-                    SerialPortType::PciPort => {
-                        println!("        Type: PCI");
-                    }
-                    SerialPortType::Unknown => {
-                        println!("        Type: Unknown");
-                    }
-                }
+const USAGE: &str = "\
+Usage: rustimer [--port PORT] [--baud RATE] COMMAND
+
+Commands:
+    ports                           List serial ports
+    version                         Show the firmware and what it supports
+    monitor [--slot N] [--interval MS]
+                                    Show live RSSI until interrupted
+    clock [--seconds N]             Check the node clock against the host
+    raw HEX...                      Send bytes as given and dump the answer
+
+Options:
+    --port PORT     Port of the node; the first node found on USB otherwise
+    --baud RATE     Baud rate, 115200 by default
+";
+
+const DEFAULT_BAUD_RATE: u32 = 115200;
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(50);
+const DEFAULT_CLOCK_SECONDS: u64 = 30;
+/// How long `raw` waits for more bytes before taking the answer as complete.
+const RAW_QUIET: Duration = Duration::from_millis(300);
+/// Samples shown in the monitor sparkline.
+const SPARKLINE_WIDTH: usize = 40;
+/// Samples the monitor takes the noise floor over.
+const FLOOR_WINDOW: usize = 200;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    port: Option<String>,
+    baud_rate: u32,
+    command: Command,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Ports,
+    Version,
+    Monitor {
+        slot: Option<u8>,
+        interval: Duration,
+    },
+    Clock {
+        seconds: u64,
+    },
+    Raw {
+        bytes: Vec<u8>,
+    },
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut port = None;
+    let mut baud_rate = DEFAULT_BAUD_RATE;
+    let mut slot = None;
+    let mut interval = None;
+    let mut seconds = None;
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--port" => port = Some(value("--port")?),
+            "--baud" => baud_rate = parse_number("--baud", &value("--baud")?)?,
+            "--slot" => slot = Some(parse_number("--slot", &value("--slot")?)?),
+            "--interval" => {
+                interval = Some(Duration::from_millis(parse_number(
+                    "--interval",
+                    &value("--interval")?,
+                )?))
             }
+            "--seconds" => seconds = Some(parse_number("--seconds", &value("--seconds")?)?),
+            option if option.starts_with("--") => {
+                return Err(format!("unknown option {}", option));
+            }
+            _ => positional.push(arg),
         }
-        Err(e) => {
-            eprintln!("{:?}", e);
-            eprintln!("Error listing serial ports");
-        }
     }
+
+    let mut positional = positional.into_iter();
+    let name = positional.next().ok_or("no command given")?;
+    let command = match name.as_str() {
+        "ports" => Command::Ports,
+        "version" => Command::Version,
+        "monitor" => Command::Monitor {
+            slot,
+            interval: interval.unwrap_or(DEFAULT_INTERVAL),
+        },
+        "clock" => Command::Clock {
+            seconds: seconds.unwrap_or(DEFAULT_CLOCK_SECONDS),
+        },
+        "raw" => Command::Raw {
+            bytes: parse_hex(positional.by_ref())?,
+        },
+        other => return Err(format!("unknown command {}", other)),
+    };
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument {}", extra));
+    }
+    let misplaced = match command {
+        Command::Monitor { .. } => seconds.map(|_| "--seconds"),
+        Command::Clock { .. } => slot.map(|_| "--slot").or(interval.map(|_| "--interval")),
+        _ => slot
+            .map(|_| "--slot")
+            .or(interval.map(|_| "--interval"))
+            .or(seconds.map(|_| "--seconds")),
+    };
+    if let Some(option) = misplaced {
+        return Err(format!("{} does not apply to {}", option, name));
+    }
+
+    Ok(Options {
+        port,
+        baud_rate,
+        command,
+    })
 }
 
-// we receive bytes from the arduino that is a millis value in hex
-// eg : 0x00 0x00 0x0D 0x59 0x66  should return 874854
-fn convert_bytes_to_millis(bytes: [u8; 256]) -> u32 {
-    let mut millis = 0;
-    millis = (bytes[0] as u32) << 24;
-    millis += (bytes[1] as u32) << 16;
-    millis += (bytes[2] as u32) << 8;
-    millis += bytes[3] as u32;
-    millis
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} takes a number, not {}", name, value))
 }
 
-fn open_port(
-    port_name: &str,
-    baud_rate: u32,
-) -> Result<Box<dyn SerialPort>, Box<dyn std::error::Error>> {
-    let mut port = serialport::new(port_name, baud_rate)
-        .dtr_on_open(true)
-        .timeout(Duration::from_millis(5000)) // Increased to 5 seconds
-        .data_bits(serialport::DataBits::Eight)
-        .flow_control(serialport::FlowControl::None)
-        .parity(serialport::Parity::None)
-        .stop_bits(serialport::StopBits::One)
-        .open()?;
-
-    let mut discard_buffer = [0u8; 256];
-    while let Ok(bytes_read) = port.read(&mut discard_buffer) {
-        if bytes_read == 0 {
-            break;
+/// Bytes from hex arguments such as `02`, `0x02`, `2a01` or `"2a 01"`.
+fn parse_hex(args: impl Iterator<Item = String>) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for arg in args {
+        for word in arg.split_whitespace() {
+            let digits = word
+                .strip_prefix("0x")
+                .or_else(|| word.strip_prefix("0X"))
+                .unwrap_or(word);
+            if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("{} is not hex", word));
+            }
+            if digits.is_empty() || digits.len() % 2 != 0 {
+                return Err(format!("{} is not a whole number of hex bytes", word));
+            }
+            for i in (0..digits.len()).step_by(2) {
+                bytes.push(u8::from_str_radix(&digits[i..i + 2], 16).expect("checked hex digits"));
+            }
         }
-        println!("Cleared {} bytes from input buffer", bytes_read);
     }
-
-    println!("Arduino should be ready now");
-    Ok(port)
+    if bytes.is_empty() {
+        return Err("raw needs the bytes to send".to_string());
+    }
+    Ok(bytes)
 }
 
-fn send_and_read(
-    port: &mut Box<dyn SerialPort>,
-    data_to_send: &[u8],
-    debug: bool,
-) -> Result<[u8; 256], Box<dyn std::error::Error>> {
-    let mut response_buffer: [u8; 256] = [0u8; 256];
-    let mut total_bytes_read = 0;
-    port.write(&data_to_send)?;
-    port.flush()?; // Ensure data is actually sent
-
-    match port.read(&mut response_buffer[total_bytes_read..]) {
-        Ok(bytes_read) => {
-            if bytes_read > 0 {
-                total_bytes_read += bytes_read;
-
-                if debug {
-                    println!("Received {} bytes in this attempt", bytes_read);
-
-                    print!("Hex: ");
-                    for i in 0..total_bytes_read {
-                        print!("0x{:02X} ", response_buffer[i]);
-                    }
-                    println!();
-
-                    print!("ASCII: ");
-                    for i in 0..total_bytes_read {
-                        let byte = response_buffer[i];
-                        if byte >= 32 && byte <= 126 {
-                            print!("{}", byte as char);
-                        } else {
-                            print!(".");
-                        }
-                    }
-                    println!();
+fn list_ports() -> Result<(), Box<dyn std::error::Error>> {
+    let mut ports = available_ports()?;
+    // Let's output ports in a stable order to facilitate comparing the output from
+    // different runs (on different platforms, with different features, ...).
+    ports.sort_by_key(|i| i.port_name.clone());
+
+    match ports.len() {
+        0 => println!("No ports found."),
+        1 => println!("Found 1 port:"),
+        n => println!("Found {} ports:", n),
+    };
+
+    for p in ports {
+        println!("    {}", p.port_name);
+        match p.port_type {
+            SerialPortType::UsbPort(info) => {
+                println!("        Type: USB");
+                println!("        VID: {:04x}", info.vid);
+                println!("        PID: {:04x}", info.pid);
+                if let Some(chip) = discovery::chip_name(info.vid, info.pid) {
+                    println!("        Chip: {}", chip);
                 }
-                // Check if there might be more data coming
-                // std::thread::sleep(Duration::from_millis(100));
-                return Ok(response_buffer);
-            } else {
-                println!("No data in this attempt");
-                return Err("No data received".into());
+                #[cfg(feature = "usbportinfo-interface")]
+                println!(
+                    "        Interface: {}",
+                    info.interface
+                        .as_ref()
+                        .map_or("".to_string(), |x| format!("{:02x}", *x))
+                );
+                println!(
+                    "        Serial Number: {}",
+                    info.serial_number.as_ref().map_or("", String::as_str)
+                );
+                println!(
+                    "        Manufacturer: {}",
+                    info.manufacturer.as_ref().map_or("", String::as_str)
+                );
+                println!(
+                    "        Product: {}",
+                    info.product.as_ref().map_or("", String::as_str)
+                );
+            }
+            SerialPortType::BluetoothPort => {
+                println!("        Type: Bluetooth");
+            }
+            SerialPortType::PciPort => {
+                println!("        Type: PCI");
+            }
+            SerialPortType::Unknown => {
+                println!("        Type: Unknown");
             }
         }
-        Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
-            println!("Timeout");
-            return Err("Read timeout".into());
+    }
+    Ok(())
+}
+
+/// Open the port given on the command line, or the first node found on USB.
+fn open(options: &Options) -> Result<Box<dyn SerialPort>, Box<dyn std::error::Error>> {
+    let port_name = find_port(options)?;
+    Ok(discovery::open_port(&port_name, options.baud_rate)?)
+}
+
+fn find_port(options: &Options) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(port_name) = &options.port {
+        return Ok(port_name.clone());
+    }
+
+    let node = discovery::discover(options.baud_rate)?
+        .into_iter()
+        .next()
+        .ok_or("no node found, pass --port")?;
+    println!(
+        "Found node on {} ({}, firmware {})",
+        node.candidate.port_name, node.candidate.chip, node.firmware
+    );
+    Ok(node.candidate.port_name)
+}
+
+fn read_firmware(port: &mut Box<dyn SerialPort>) -> Result<Firmware, Box<dyn std::error::Error>> {
+    Ok(Firmware::detect(protocol::read_version(port))?)
+}
+
+fn version(port: &mut Box<dyn SerialPort>) -> Result<(), Box<dyn std::error::Error>> {
+    let firmware = read_firmware(port)?;
+    let capabilities = firmware.capabilities;
+    println!("firmware: {}", firmware);
+    println!("millis: {}", capabilities.millis);
+    println!("lap counter: {}", capabilities.lap_counter);
+    if capabilities.slots {
+        println!("slots: {}", protocol::read_slot_count(port)?);
+    } else {
+        println!("slots: none");
+    }
+    println!("max receivers: {}", capabilities.max_nodes);
+    println!("streaming: {}", capabilities.streaming);
+    match protocol::read_frequency(port) {
+        Ok(frequency) => println!("frequency: {} MHz", frequency),
+        Err(e) => println!("frequency: {}", e),
+    }
+    Ok(())
+}
+
+/// Running RSSI statistics for the monitor.
+struct RssiStats {
+    recent: VecDeque<u8>,
+    min: u8,
+    max: u8,
+}
+
+impl RssiStats {
+    fn new() -> Self {
+        RssiStats {
+            recent: VecDeque::with_capacity(FLOOR_WINDOW),
+            min: u8::MAX,
+            max: u8::MIN,
         }
-        Err(e) => {
-            println!("Error reading response: {}", e);
-            return Err(e.into());
+    }
+
+    fn add(&mut self, rssi: u8) {
+        if self.recent.len() == FLOOR_WINDOW {
+            self.recent.pop_front();
         }
+        self.recent.push_back(rssi);
+        self.min = self.min.min(rssi);
+        self.max = self.max.max(rssi);
+    }
+
+    /// RSSI with nothing near the gate: the 20th percentile of recent samples,
+    /// which passes do not move.
+    fn noise_floor(&self) -> u8 {
+        let mut sorted: Vec<u8> = self.recent.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted.get(sorted.len() / 5).copied().unwrap_or(0)
+    }
+
+    /// Last samples scaled between the lowest and highest RSSI seen.
+    fn sparkline(&self) -> String {
+        let range = (self.max.saturating_sub(self.min) as usize).max(1);
+        let skip = self.recent.len().saturating_sub(SPARKLINE_WIDTH);
+        let line: String = self
+            .recent
+            .iter()
+            .skip(skip)
+            .map(|&rssi| SPARKS[(rssi - self.min) as usize * (SPARKS.len() - 1) / range])
+            .collect();
+        format!("{:<width$}", line, width = SPARKLINE_WIDTH)
     }
 }
 
-fn read_ms(port: &mut Box<dyn SerialPort>) -> Result<u32, Box<dyn std::error::Error>> {
-    let data_to_send = [0x33];
-    let response_buffer = send_and_read(port, &data_to_send, false)?;
-    let millis = convert_bytes_to_millis(response_buffer);
-    Ok(millis)
+fn monitor(
+    port: &mut Box<dyn SerialPort>,
+    slot: Option<u8>,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(slot) = slot {
+        let firmware = read_firmware(port)?;
+        if !firmware.capabilities.slots {
+            return Err(format!("firmware {} has no receiver slots", firmware).into());
+        }
+        protocol::select_slot(port, slot)?;
+    }
+
+    let mut stats = RssiStats::new();
+    let mut stdout = io::stdout();
+    loop {
+        let started = Instant::now();
+        match protocol::read_peak(port) {
+            Ok(peak) => {
+                stats.add(peak.rssi);
+                write!(
+                    stdout,
+                    "\r{} rssi {:3}  min {:3}  max {:3}  floor {:3}  lap {:3}",
+                    stats.sparkline(),
+                    peak.rssi,
+                    stats.min,
+                    stats.max,
+                    stats.noise_floor(),
+                    peak.lap_id
+                )?;
+                stdout.flush()?;
+            }
+            Err(ProtocolError::Timeout) => println!("\nread timeout"),
+            Err(e) => return Err(e.into()),
+        }
+        std::thread::sleep(interval.saturating_sub(started.elapsed()));
+    }
 }
 
-fn read_version(port: &mut Box<dyn SerialPort>) -> Result<u32, Box<dyn std::error::Error>> {
-    let data_to_send = [0x3D];
-    let response_buffer = send_and_read(port, &data_to_send, true)?;
-    let version = response_buffer[1];
-    Ok(version.into())
+fn clock(port: &mut Box<dyn SerialPort>, seconds: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let firmware = read_firmware(port)?;
+    if !firmware.capabilities.millis {
+        return Err(format!("firmware {} has no clock to read", firmware).into());
+    }
+
+    let mut sync = ClockSync::new();
+    let start = Instant::now();
+    let mut first = None;
+    for second in 0..=seconds {
+        let sent = Instant::now();
+        let millis = protocol::read_millis(port)?;
+        let reading = ClockReading {
+            sent,
+            millis,
+            received: Instant::now(),
+        };
+        sync.add(reading);
+        let (first_host, first_millis) = *first.get_or_insert((sent, millis));
+
+        // Node time against host time since the first reading, as a cross
+        // check on the estimate.
+        let host_ms = sent.duration_since(first_host).as_secs_f64() * 1000.0;
+        let node_ms = millis.wrapping_sub(first_millis) as f64;
+        let offset_ms = node_ms - host_ms;
+        let estimate = sync.estimate().expect("estimate after a reading");
+        println!(
+            "{:4}s  millis {:10}  rtt {:5.1} ms  offset {:+7.1} ms  drift {:+8.1} ppm",
+            second,
+            millis,
+            reading.round_trip().as_secs_f64() * 1000.0,
+            offset_ms,
+            estimate.drift_ppm
+        );
+        if second < seconds {
+            std::thread::sleep(
+                (start + Duration::from_secs(second + 1)).saturating_duration_since(Instant::now()),
+            );
+        }
+    }
+
+    let estimate = sync.estimate().expect("estimate after a reading");
+    println!(
+        "drift {:+.1} ppm ({:.1} ms per hour) over {} samples, best round trip {:.1} ms",
+        estimate.drift_ppm,
+        estimate.drift_ppm * 3.6,
+        estimate.samples,
+        estimate.round_trip.as_secs_f64() * 1000.0
+    );
+    Ok(())
 }
 
-fn read_peak(port: &mut Box<dyn SerialPort>) -> Result<u32, Box<dyn std::error::Error>> {
-    let data_to_send = [0x0D]; // 0E
-    let response_buffer = send_and_read(port, &data_to_send, false)?;
+fn raw(port: &mut Box<dyn SerialPort>, bytes: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    println!("sent:");
+    print!("{}", hex_dump(bytes));
+    port.write_all(bytes)?;
+    port.flush()?;
 
-    let lap_id = response_buffer[0];
-    // ms_val is a 16 bit value
-    let ms_val = (response_buffer[1] as u16 * 256 + response_buffer[2] as u16) as u32;
-    let rssi = response_buffer[3];
+    port.set_timeout(RAW_QUIET)?;
+    let mut answer = Vec::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        match port.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => answer.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
 
-    println!("lap_id: {}, ms_val: {}, rssi: {}", lap_id, ms_val, rssi);
-    Ok(rssi as u32)
+    if answer.is_empty() {
+        println!("no answer");
+    } else {
+        println!("received:");
+        print!("{}", hex_dump(&answer));
+    }
+    Ok(())
+}
+
+/// Offset, hex and ASCII columns, 16 bytes a line.
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump.push_str(&format!(
+            "{:04x}  {:<47}  |{}|\n",
+            line * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    dump
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // list_ports();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprint!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    let port_name = "/dev/cu.usbserial-11230";
-    let baud_rate = 115200;
-    let mut port = open_port(port_name, baud_rate)?;
+    match &options.command {
+        Command::Ports => list_ports(),
+        Command::Version => version(&mut open(&options)?),
+        Command::Monitor { slot, interval } => monitor(&mut open(&options)?, *slot, *interval),
+        Command::Clock { seconds } => clock(&mut open(&options)?, *seconds),
+        Command::Raw { bytes } => raw(&mut open(&options)?, bytes),
+    }
+}
 
-    let millis = read_ms(&mut port)?;
-    println!("ms value: {}", millis);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // let version = read_version(&mut port)?;
-    // println!("version value: {}", version);
+    fn parse(args: &str) -> Result<Options, String> {
+        parse_args(args.split(' ').map(String::from))
+    }
 
-    for _ in 0..2 {
-        let peak = read_peak(&mut port)?;
-        // println!("peak value: {}", peak);
-        std::thread::sleep(Duration::from_millis(1000));
+    #[test]
+    fn parses_commands_and_options() {
+        let options = parse("--port /dev/ttyUSB0 monitor --slot 2 --baud 57600").unwrap();
+        assert_eq!(options.port.as_deref(), Some("/dev/ttyUSB0"));
+        assert_eq!(options.baud_rate, 57600);
+        assert_eq!(
+            options.command,
+            Command::Monitor {
+                slot: Some(2),
+                interval: DEFAULT_INTERVAL
+            }
+        );
+
+        assert!(parse("clock --slot 1").is_err());
+        assert!(parse("version extra").is_err());
+        assert!(parse("--baud fast version").is_err());
     }
 
-    let millis = read_ms(&mut port)?;
-    println!("ms value: {}", millis);
+    #[test]
+    fn parses_raw_hex() {
+        let options = parse("raw 0x02 2a01").unwrap();
+        assert_eq!(
+            options.command,
+            Command::Raw {
+                bytes: vec![0x02, 0x2a, 0x01]
+            }
+        );
+        assert!(parse("raw 2a0").is_err());
+        assert!(parse("raw zz").is_err());
+        assert!(parse("raw").is_err());
+    }
 
-    Ok(())
+    #[test]
+    fn dumps_hex_and_ascii() {
+        assert_eq!(
+            hex_dump(b"OK\x01"),
+            format!("0000  {:<47}  |OK.|\n", "4f 4b 01")
+        );
+    }
 }
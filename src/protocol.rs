@@ -0,0 +1,640 @@
+//! Wire protocol spoken by the timing nodes.
+//!
+//! The host writes a command byte, followed for write commands by their
+//! argument bytes and a checksum. Read commands are answered with a fixed
+//! number of payload bytes followed by a checksum byte (the wrapping sum of the
+//! payload); write commands are not answered. Every response is decoded into a
+//! [`NodeResponse`] so callers never have to index raw buffers.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const CMD_READ_FREQUENCY: u8 = 0x03;
+const CMD_READ_PEAK: u8 = 0x0D;
+const CMD_READ_MILLIS: u8 = 0x33;
+const CMD_READ_SLOT_COUNT: u8 = 0x39;
+const CMD_READ_VERSION: u8 = 0x3D;
+const CMD_SET_FREQUENCY: u8 = 0x51;
+const CMD_SET_STREAMING: u8 = 0x5B;
+const CMD_SELECT_SLOT: u8 = 0x7A;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeCommand {
+    /// Current lap counter, time since the last lap and the RSSI value.
+    ReadPeak,
+    /// Node uptime as reported by the Arduino `millis()`.
+    ReadMillis,
+    /// Firmware revision code.
+    ReadVersion,
+    /// Number of receiver slots driven by this node.
+    ReadSlotCount,
+    /// Route the following commands to the given receiver slot.
+    SelectSlot(u8),
+    /// Frequency the receiver is tuned to, in MHz.
+    ReadFrequency,
+    /// Tune the receiver to the given frequency in MHz.
+    SetFrequency(u16),
+    /// Start or stop pushing stream frames, see [`crate::stream`].
+    SetStreaming(bool),
+}
+
+/// Payload of the [`NodeCommand::ReadPeak`] response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peak {
+    pub lap_id: u8,
+    pub ms_val: u16,
+    pub rssi: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeResponse {
+    Peak(Peak),
+    Millis(u32),
+    Version(u16),
+    SlotCount(u8),
+    Frequency(u16),
+    /// Write commands have no payload in their response.
+    Ack,
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    /// The node did not answer at all before the port timeout.
+    Timeout,
+    /// The node answered with fewer bytes than the command requires.
+    ShortRead {
+        expected: usize,
+        received: usize,
+    },
+    /// The checksum byte does not match the payload.
+    Checksum {
+        expected: u8,
+        received: u8,
+    },
+    /// The response was decoded against a command it does not belong to.
+    UnexpectedResponse {
+        command: NodeCommand,
+        response: NodeResponse,
+    },
+    /// A request started with a byte that is no command code.
+    UnknownCommand(u8),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "serial I/O error: {}", e),
+            ProtocolError::Timeout => write!(f, "read timeout"),
+            ProtocolError::ShortRead { expected, received } => write!(
+                f,
+                "short read: expected {} bytes, received {}",
+                expected, received
+            ),
+            ProtocolError::Checksum { expected, received } => write!(
+                f,
+                "checksum mismatch: expected 0x{:02X}, received 0x{:02X}",
+                expected, received
+            ),
+            ProtocolError::UnexpectedResponse { command, response } => write!(
+                f,
+                "unexpected response {:?} to command {:?}",
+                response, command
+            ),
+            ProtocolError::UnknownCommand(code) => write!(f, "unknown command 0x{:02X}", code),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Serial ports report a read timeout as `TimedOut`, sockets on Unix as
+/// `WouldBlock`.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        if is_timeout(&e) {
+            ProtocolError::Timeout
+        } else {
+            ProtocolError::Io(e)
+        }
+    }
+}
+
+/// Checksum used by the firmware: the wrapping sum of all payload bytes.
+pub fn checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+impl NodeCommand {
+    pub fn code(&self) -> u8 {
+        match self {
+            NodeCommand::ReadPeak => CMD_READ_PEAK,
+            NodeCommand::ReadMillis => CMD_READ_MILLIS,
+            NodeCommand::ReadVersion => CMD_READ_VERSION,
+            NodeCommand::ReadSlotCount => CMD_READ_SLOT_COUNT,
+            NodeCommand::SelectSlot(_) => CMD_SELECT_SLOT,
+            NodeCommand::ReadFrequency => CMD_READ_FREQUENCY,
+            NodeCommand::SetFrequency(_) => CMD_SET_FREQUENCY,
+            NodeCommand::SetStreaming(_) => CMD_SET_STREAMING,
+        }
+    }
+
+    /// Argument bytes sent after the command code, checksum excluded.
+    pub fn arguments(&self) -> Vec<u8> {
+        match self {
+            NodeCommand::SelectSlot(slot) => vec![*slot],
+            NodeCommand::SetFrequency(frequency) => frequency.to_be_bytes().to_vec(),
+            NodeCommand::SetStreaming(on) => vec![u8::from(*on)],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Bytes to write on the wire for this command.
+    pub fn encode(&self) -> Vec<u8> {
+        let arguments = self.arguments();
+        let mut bytes = vec![self.code()];
+        if !arguments.is_empty() {
+            bytes.extend_from_slice(&arguments);
+            bytes.push(checksum(&arguments));
+        }
+        bytes
+    }
+
+    /// Number of payload bytes the node answers with, checksum excluded.
+    pub fn payload_len(&self) -> usize {
+        match self {
+            NodeCommand::ReadPeak => 4,
+            NodeCommand::ReadMillis => 4,
+            NodeCommand::ReadVersion => 2,
+            NodeCommand::ReadSlotCount => 1,
+            NodeCommand::SelectSlot(_) => 0,
+            NodeCommand::ReadFrequency => 2,
+            NodeCommand::SetFrequency(_) => 0,
+            NodeCommand::SetStreaming(_) => 0,
+        }
+    }
+
+    /// Total number of bytes the node answers with, checksum included.
+    pub fn response_len(&self) -> usize {
+        match self.payload_len() {
+            0 => 0,
+            n => n + 1,
+        }
+    }
+
+    /// Validate and decode a complete response frame for this command.
+    pub fn decode(&self, frame: &[u8]) -> Result<NodeResponse, ProtocolError> {
+        let expected_len = self.response_len();
+        if expected_len == 0 {
+            return Ok(NodeResponse::Ack);
+        }
+        if frame.len() < expected_len {
+            return Err(ProtocolError::ShortRead {
+                expected: expected_len,
+                received: frame.len(),
+            });
+        }
+
+        let (payload, rest) = frame[..expected_len].split_at(self.payload_len());
+        let expected = checksum(payload);
+        if rest[0] != expected {
+            return Err(ProtocolError::Checksum {
+                expected,
+                received: rest[0],
+            });
+        }
+
+        let response = match self {
+            NodeCommand::ReadPeak => NodeResponse::Peak(Peak {
+                lap_id: payload[0],
+                ms_val: u16::from_be_bytes([payload[1], payload[2]]),
+                rssi: payload[3],
+            }),
+            NodeCommand::ReadMillis => NodeResponse::Millis(u32::from_be_bytes([
+                payload[0], payload[1], payload[2], payload[3],
+            ])),
+            NodeCommand::ReadVersion => {
+                NodeResponse::Version(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            NodeCommand::ReadSlotCount => NodeResponse::SlotCount(payload[0]),
+            NodeCommand::ReadFrequency => {
+                NodeResponse::Frequency(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            NodeCommand::SelectSlot(_)
+            | NodeCommand::SetFrequency(_)
+            | NodeCommand::SetStreaming(_) => NodeResponse::Ack,
+        };
+        Ok(response)
+    }
+}
+
+impl NodeResponse {
+    /// Bytes a node sends for this response: payload followed by checksum.
+    /// Inverse of [`NodeCommand::decode`].
+    pub fn encode(&self) -> Vec<u8> {
+        let payload = match self {
+            NodeResponse::Peak(peak) => {
+                let ms = peak.ms_val.to_be_bytes();
+                vec![peak.lap_id, ms[0], ms[1], peak.rssi]
+            }
+            NodeResponse::Millis(millis) => millis.to_be_bytes().to_vec(),
+            NodeResponse::Version(version) => version.to_be_bytes().to_vec(),
+            NodeResponse::SlotCount(count) => vec![*count],
+            NodeResponse::Frequency(frequency) => frequency.to_be_bytes().to_vec(),
+            NodeResponse::Ack => return Vec::new(),
+        };
+        let mut frame = payload;
+        frame.push(checksum(&frame));
+        frame
+    }
+}
+
+/// Send `command` to the node and read back its full, validated response.
+pub fn transact<P: Read + Write + ?Sized>(
+    port: &mut P,
+    command: NodeCommand,
+) -> Result<NodeResponse, ProtocolError> {
+    port.write_all(&command.encode())?;
+    port.flush()?;
+    if command.response_len() == 0 {
+        return Ok(NodeResponse::Ack);
+    }
+
+    let mut frame = vec![0u8; command.response_len()];
+    let mut received = 0;
+    while received < frame.len() {
+        match port.read(&mut frame[received..]) {
+            Ok(0) => break,
+            Ok(n) => received += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref e) if is_timeout(e) && received > 0 => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if received == 0 {
+        return Err(ProtocolError::Timeout);
+    }
+    command.decode(&frame[..received])
+}
+
+/// Read one command as [`transact`] writes it, for code playing the node.
+/// Returns `None` when the stream ends between commands.
+pub fn read_request<R: Read + ?Sized>(
+    reader: &mut R,
+) -> Result<Option<NodeCommand>, ProtocolError> {
+    let mut code = [0u8; 1];
+    loop {
+        match reader.read(&mut code) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let command = match code[0] {
+        CMD_READ_PEAK => NodeCommand::ReadPeak,
+        CMD_READ_MILLIS => NodeCommand::ReadMillis,
+        CMD_READ_VERSION => NodeCommand::ReadVersion,
+        CMD_READ_SLOT_COUNT => NodeCommand::ReadSlotCount,
+        CMD_READ_FREQUENCY => NodeCommand::ReadFrequency,
+        CMD_SELECT_SLOT => NodeCommand::SelectSlot(read_arguments::<_, 1>(reader)?[0]),
+        CMD_SET_FREQUENCY => NodeCommand::SetFrequency(u16::from_be_bytes(read_arguments(reader)?)),
+        CMD_SET_STREAMING => NodeCommand::SetStreaming(read_arguments::<_, 1>(reader)?[0] != 0),
+        code => return Err(ProtocolError::UnknownCommand(code)),
+    };
+    Ok(Some(command))
+}
+
+/// Read `N` argument bytes and the checksum that follows them.
+fn read_arguments<R: Read + ?Sized, const N: usize>(
+    reader: &mut R,
+) -> Result<[u8; N], ProtocolError> {
+    let mut arguments = [0u8; N];
+    reader.read_exact(&mut arguments)?;
+    let mut received = [0u8; 1];
+    reader.read_exact(&mut received)?;
+    let expected = checksum(&arguments);
+    if received[0] != expected {
+        return Err(ProtocolError::Checksum {
+            expected,
+            received: received[0],
+        });
+    }
+    Ok(arguments)
+}
+
+pub fn read_peak<P: Read + Write + ?Sized>(port: &mut P) -> Result<Peak, ProtocolError> {
+    match transact(port, NodeCommand::ReadPeak)? {
+        NodeResponse::Peak(peak) => Ok(peak),
+        response => Err(ProtocolError::UnexpectedResponse {
+            command: NodeCommand::ReadPeak,
+            response,
+        }),
+    }
+}
+
+pub fn read_millis<P: Read + Write + ?Sized>(port: &mut P) -> Result<u32, ProtocolError> {
+    match transact(port, NodeCommand::ReadMillis)? {
+        NodeResponse::Millis(millis) => Ok(millis),
+        response => Err(ProtocolError::UnexpectedResponse {
+            command: NodeCommand::ReadMillis,
+            response,
+        }),
+    }
+}
+
+pub fn read_version<P: Read + Write + ?Sized>(port: &mut P) -> Result<u16, ProtocolError> {
+    match transact(port, NodeCommand::ReadVersion)? {
+        NodeResponse::Version(version) => Ok(version),
+        response => Err(ProtocolError::UnexpectedResponse {
+            command: NodeCommand::ReadVersion,
+            response,
+        }),
+    }
+}
+
+pub fn read_slot_count<P: Read + Write + ?Sized>(port: &mut P) -> Result<u8, ProtocolError> {
+    match transact(port, NodeCommand::ReadSlotCount)? {
+        NodeResponse::SlotCount(count) => Ok(count),
+        response => Err(ProtocolError::UnexpectedResponse {
+            command: NodeCommand::ReadSlotCount,
+            response,
+        }),
+    }
+}
+
+pub fn select_slot<P: Read + Write + ?Sized>(port: &mut P, slot: u8) -> Result<(), ProtocolError> {
+    transact(port, NodeCommand::SelectSlot(slot))?;
+    Ok(())
+}
+
+pub fn read_frequency<P: Read + Write + ?Sized>(port: &mut P) -> Result<u16, ProtocolError> {
+    match transact(port, NodeCommand::ReadFrequency)? {
+        NodeResponse::Frequency(frequency) => Ok(frequency),
+        response => Err(ProtocolError::UnexpectedResponse {
+            command: NodeCommand::ReadFrequency,
+            response,
+        }),
+    }
+}
+
+/// Tune the receiver and read the frequency back, so the caller learns what
+/// the node actually accepted.
+pub fn set_frequency<P: Read + Write + ?Sized>(
+    port: &mut P,
+    frequency: u16,
+) -> Result<u16, ProtocolError> {
+    transact(port, NodeCommand::SetFrequency(frequency))?;
+    read_frequency(port)
+}
+
+/// Start or stop the stream of pushed samples. Stopping does not take back
+/// frames already on their way.
+pub fn set_streaming<P: Read + Write + ?Sized>(
+    port: &mut P,
+    on: bool,
+) -> Result<(), ProtocolError> {
+    transact(port, NodeCommand::SetStreaming(on))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// In-memory port that records what was written and replays canned bytes.
+    struct FakePort {
+        written: Vec<u8>,
+        to_read: VecDeque<u8>,
+    }
+
+    impl FakePort {
+        fn new(to_read: &[u8]) -> Self {
+            FakePort {
+                written: Vec::new(),
+                to_read: to_read.iter().copied().collect(),
+            }
+        }
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.to_read.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout"));
+            }
+            // Hand out one byte at a time to exercise partial reads.
+            buf[0] = self.to_read.pop_front().unwrap();
+            Ok(1)
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encodes_single_byte_commands() {
+        assert_eq!(NodeCommand::ReadPeak.encode(), vec![0x0D]);
+        assert_eq!(NodeCommand::ReadMillis.encode(), vec![0x33]);
+        assert_eq!(NodeCommand::ReadVersion.encode(), vec![0x3D]);
+        assert_eq!(NodeCommand::ReadSlotCount.encode(), vec![0x39]);
+    }
+
+    #[test]
+    fn encodes_write_commands_with_checksum() {
+        assert_eq!(NodeCommand::SelectSlot(3).encode(), vec![0x7A, 0x03, 0x03]);
+        // 5658 MHz = 0x161A
+        assert_eq!(
+            NodeCommand::SetFrequency(5658).encode(),
+            vec![0x51, 0x16, 0x1A, 0x30]
+        );
+    }
+
+    #[test]
+    fn set_frequency_reads_back_tuned_frequency() {
+        let mut port = FakePort::new(&[0x16, 0x1A, 0x30]);
+        assert_eq!(set_frequency(&mut port, 5658).unwrap(), 5658);
+        assert_eq!(port.written, vec![0x51, 0x16, 0x1A, 0x30, 0x03]);
+    }
+
+    #[test]
+    fn socket_timeouts_are_timeouts() {
+        let e = io::Error::new(io::ErrorKind::WouldBlock, "timed out");
+        assert!(matches!(ProtocolError::from(e), ProtocolError::Timeout));
+    }
+
+    #[test]
+    fn write_commands_do_not_wait_for_a_response() {
+        let mut port = FakePort::new(&[]);
+        select_slot(&mut port, 2).unwrap();
+        assert_eq!(port.written, vec![0x7A, 0x02, 0x02]);
+    }
+
+    #[test]
+    fn reads_back_encoded_requests() {
+        let commands = [
+            NodeCommand::ReadPeak,
+            NodeCommand::ReadMillis,
+            NodeCommand::SelectSlot(3),
+            NodeCommand::SetFrequency(5658),
+            NodeCommand::SetStreaming(true),
+            NodeCommand::ReadVersion,
+        ];
+        let bytes: Vec<u8> = commands.iter().flat_map(|c| c.encode()).collect();
+        let mut reader = bytes.as_slice();
+        for command in commands {
+            assert_eq!(read_request(&mut reader).unwrap(), Some(command));
+        }
+        assert_eq!(read_request(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        assert!(matches!(
+            read_request(&mut [0xFFu8].as_slice()),
+            Err(ProtocolError::UnknownCommand(0xFF))
+        ));
+        assert!(matches!(
+            read_request(&mut [0x7A, 0x03, 0x04].as_slice()),
+            Err(ProtocolError::Checksum {
+                expected: 0x03,
+                received: 0x04
+            })
+        ));
+    }
+
+    #[test]
+    fn decodes_millis_golden_vector() {
+        let frame = [0x00, 0x0D, 0x59, 0x66, 0xCC];
+        assert_eq!(
+            NodeCommand::ReadMillis.decode(&frame).unwrap(),
+            NodeResponse::Millis(874854)
+        );
+
+        // The last byte is the checksum, not part of the value.
+        let frame = [0x00, 0x00, 0x0D, 0x59, 0x66];
+        assert_eq!(
+            NodeCommand::ReadMillis.decode(&frame).unwrap(),
+            NodeResponse::Millis(3417)
+        );
+    }
+
+    #[test]
+    fn decodes_peak_golden_vector() {
+        let frame = [0x07, 0x01, 0xF4, 0x5A, 0x56];
+        assert_eq!(
+            NodeCommand::ReadPeak.decode(&frame).unwrap(),
+            NodeResponse::Peak(Peak {
+                lap_id: 7,
+                ms_val: 500,
+                rssi: 90
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_version_golden_vector() {
+        let frame = [0x25, 0x03, 0x28];
+        assert_eq!(
+            NodeCommand::ReadVersion.decode(&frame).unwrap(),
+            NodeResponse::Version(0x2503)
+        );
+    }
+
+    #[test]
+    fn decodes_slot_count_golden_vector() {
+        assert_eq!(
+            NodeCommand::ReadSlotCount.decode(&[0x04, 0x04]).unwrap(),
+            NodeResponse::SlotCount(4)
+        );
+    }
+
+    #[test]
+    fn encodes_responses_as_decodable_frames() {
+        let responses = [
+            (
+                NodeCommand::ReadPeak,
+                NodeResponse::Peak(Peak {
+                    lap_id: 7,
+                    ms_val: 500,
+                    rssi: 90,
+                }),
+            ),
+            (NodeCommand::ReadMillis, NodeResponse::Millis(874854)),
+            (NodeCommand::ReadVersion, NodeResponse::Version(0x2503)),
+            (NodeCommand::ReadFrequency, NodeResponse::Frequency(5658)),
+            (NodeCommand::SelectSlot(1), NodeResponse::Ack),
+        ];
+        for (command, response) in responses {
+            assert_eq!(command.decode(&response.encode()).unwrap(), response);
+        }
+        assert_eq!(
+            NodeResponse::Millis(874854).encode(),
+            vec![0x00, 0x0D, 0x59, 0x66, 0xCC]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let frame = [0x00, 0x00, 0x0D, 0x59, 0x67];
+        assert!(matches!(
+            NodeCommand::ReadMillis.decode(&frame),
+            Err(ProtocolError::Checksum {
+                expected: 0x66,
+                received: 0x67
+            })
+        ));
+    }
+
+    #[test]
+    fn transact_reports_short_read() {
+        let mut port = FakePort::new(&[0x07, 0x01]);
+        assert!(matches!(
+            transact(&mut port, NodeCommand::ReadPeak),
+            Err(ProtocolError::ShortRead {
+                expected: 5,
+                received: 2
+            })
+        ));
+        assert_eq!(port.written, vec![0x0D]);
+    }
+
+    #[test]
+    fn transact_reports_timeout_without_data() {
+        let mut port = FakePort::new(&[]);
+        assert!(matches!(
+            read_millis(&mut port),
+            Err(ProtocolError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn transact_assembles_partial_reads() {
+        let mut port = FakePort::new(&[0x07, 0x01, 0xF4, 0x5A, 0x56]);
+        let peak = read_peak(&mut port).unwrap();
+        assert_eq!((peak.lap_id, peak.ms_val, peak.rssi), (7, 500, 90));
+    }
+}
@@ -0,0 +1,82 @@
+//! A node served over TCP, to try the network transport without an ESP32.
+//!
+//! Requests are read as the host writes them and handed to a handler playing
+//! the node; its responses go back on the wire exactly as a node would send
+//! them. Connections are served one after the other, like a node that only
+//! talks to one timer at a time.
+
+use crate::protocol::{self, NodeCommand, NodeResponse, ProtocolError};
+use std::io::{self, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Serve `handler` on every connection accepted by `listener`, until the
+/// listener fails. A handler error leaves the request unanswered, so the host
+/// sees a timeout as it would from a real node.
+pub fn serve<H>(listener: TcpListener, mut handler: H) -> io::Result<()>
+where
+    H: FnMut(NodeCommand) -> Result<NodeResponse, ProtocolError>,
+{
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        println!("Stand-in node: connection from {}", peer);
+        match handle(stream, &mut handler) {
+            Ok(()) => println!("Stand-in node: {} disconnected", peer),
+            Err(e) => eprintln!("Stand-in node: {} dropped: {}", peer, e),
+        }
+    }
+    Ok(())
+}
+
+fn handle<H>(mut stream: TcpStream, handler: &mut H) -> Result<(), ProtocolError>
+where
+    H: FnMut(NodeCommand) -> Result<NodeResponse, ProtocolError>,
+{
+    stream.set_nodelay(true)?;
+    while let Some(command) = protocol::read_request(&mut stream)? {
+        match handler(command) {
+            Ok(response) => stream.write_all(&response.encode())?,
+            Err(e) => eprintln!("Stand-in node: {:?} failed: {}", command, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Peak;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn answers_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut frequency = 5658;
+            serve(listener, move |command| match command {
+                NodeCommand::ReadPeak => Ok(NodeResponse::Peak(Peak {
+                    lap_id: 2,
+                    ms_val: 1500,
+                    rssi: 80,
+                })),
+                NodeCommand::ReadFrequency => Ok(NodeResponse::Frequency(frequency)),
+                NodeCommand::SetFrequency(f) => {
+                    frequency = f;
+                    Ok(NodeResponse::Ack)
+                }
+                _ => Err(ProtocolError::Timeout),
+            })
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert_eq!(protocol::read_peak(&mut stream).unwrap().ms_val, 1500);
+        assert_eq!(protocol::set_frequency(&mut stream, 5917).unwrap(), 5917);
+        assert!(protocol::read_millis(&mut stream).is_err());
+        assert_eq!(protocol::read_frequency(&mut stream).unwrap(), 5917);
+    }
+}
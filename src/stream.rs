@@ -0,0 +1,277 @@
+//! Samples pushed by the node instead of polled one request at a time.
+//!
+//! Once [`NodeCommand::SetStreaming`](crate::protocol::NodeCommand) turns it
+//! on, the node writes one frame per receiver slot every time it samples:
+//!
+//! | Bytes | Field                                         |
+//! |-------|-----------------------------------------------|
+//! | 2     | sync word `A5 5A`                             |
+//! | 1     | sequence number, one more for every frame     |
+//! | 1     | receiver slot                                 |
+//! | 4     | node `millis()` at the sample, big-endian     |
+//! | 4     | peak payload: lap id, `ms_val`, RSSI          |
+//! | 1     | checksum of the ten bytes after the sync word |
+//!
+//! The sync word may also turn up inside a frame, so a frame only counts once
+//! its checksum matches. After garbage or a corrupt frame the decoder moves on
+//! one byte at a time until frames line up again. Frames lost on the way show
+//! up as gaps in the sequence numbers.
+
+use crate::protocol::{self, Peak, ProtocolError};
+use std::io::{self, Read};
+
+pub const SYNC: [u8; 2] = [0xA5, 0x5A];
+/// Length of a frame on the wire, sync word and checksum included.
+pub const FRAME_LEN: usize = 13;
+/// Bytes [`read_frames`] takes without finding a frame before giving up.
+const MAX_GARBAGE: usize = 16 * FRAME_LEN;
+
+/// One pushed sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFrame {
+    pub seq: u8,
+    pub slot: u8,
+    /// Node uptime at the sample, in milliseconds.
+    pub millis: u32,
+    pub peak: Peak,
+}
+
+impl StreamFrame {
+    /// Bytes a node sends for this frame.
+    pub fn encode(&self) -> [u8; FRAME_LEN] {
+        let millis = self.millis.to_be_bytes();
+        let ms = self.peak.ms_val.to_be_bytes();
+        let mut frame = [0u8; FRAME_LEN];
+        frame[..2].copy_from_slice(&SYNC);
+        frame[2..12].copy_from_slice(&[
+            self.seq,
+            self.slot,
+            millis[0],
+            millis[1],
+            millis[2],
+            millis[3],
+            self.peak.lap_id,
+            ms[0],
+            ms[1],
+            self.peak.rssi,
+        ]);
+        frame[12] = protocol::checksum(&frame[2..12]);
+        frame
+    }
+
+    /// The frame at the start of `bytes`, if it is a whole and valid one.
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let frame = bytes.get(..FRAME_LEN)?;
+        if frame[..2] != SYNC || protocol::checksum(&frame[2..12]) != frame[12] {
+            return None;
+        }
+        Some(StreamFrame {
+            seq: frame[2],
+            slot: frame[3],
+            millis: u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]),
+            peak: Peak {
+                lap_id: frame[8],
+                ms_val: u16::from_be_bytes([frame[9], frame[10]]),
+                rssi: frame[11],
+            },
+        })
+    }
+}
+
+/// Counters of a stream since it was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    /// Valid frames decoded.
+    pub frames: u64,
+    /// Frames missing from the sequence, corrupt ones included. More than 255
+    /// frames lost in a row are undercounted.
+    pub dropped: u64,
+    /// Sync words whose frame failed its checksum.
+    pub corrupt: u64,
+    /// Bytes that were not part of a valid frame.
+    pub skipped_bytes: u64,
+}
+
+/// Cuts a byte stream into frames, whatever the reads happen to split it at.
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
+    buffer: Vec<u8>,
+    last_seq: Option<u8>,
+    stats: StreamStats,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        self.stats
+    }
+
+    /// Forget partial input and the sequence, e.g. after the stream was
+    /// paused and the input discarded. Counters are kept.
+    pub fn resync(&mut self) {
+        self.buffer.clear();
+        self.last_seq = None;
+    }
+
+    /// Feed bytes as read and take the frames they complete.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<StreamFrame> {
+        self.buffer.extend_from_slice(bytes);
+        let mut frames = Vec::new();
+        let mut start = 0;
+        loop {
+            let rest = &self.buffer[start..];
+            let Some(offset) = rest.windows(2).position(|window| window == SYNC) else {
+                // Keep a trailing first sync byte, its second may be next.
+                let keep = usize::from(rest.last() == Some(&SYNC[0]));
+                self.stats.skipped_bytes += (rest.len() - keep) as u64;
+                start = self.buffer.len() - keep;
+                break;
+            };
+            self.stats.skipped_bytes += offset as u64;
+            start += offset;
+            if self.buffer.len() - start < FRAME_LEN {
+                break;
+            }
+            match StreamFrame::decode(&self.buffer[start..]) {
+                Some(frame) => {
+                    self.count(frame.seq);
+                    frames.push(frame);
+                    start += FRAME_LEN;
+                }
+                None => {
+                    self.stats.corrupt += 1;
+                    self.stats.skipped_bytes += 1;
+                    start += 1;
+                }
+            }
+        }
+        self.buffer.drain(..start);
+        frames
+    }
+
+    fn count(&mut self, seq: u8) {
+        if let Some(last) = self.last_seq.replace(seq) {
+            self.stats.dropped += seq.wrapping_sub(last).wrapping_sub(1) as u64;
+        }
+        self.stats.frames += 1;
+    }
+}
+
+/// Read until at least one frame is complete. A stream that stays silent
+/// until the read timeout, or carries no frames at all, e.g. at the wrong
+/// baud rate, times out.
+pub fn read_frames<R: Read + ?Sized>(
+    reader: &mut R,
+    decoder: &mut StreamDecoder,
+) -> Result<Vec<StreamFrame>, ProtocolError> {
+    let mut buffer = [0u8; 256];
+    let mut received = 0;
+    while received <= MAX_GARBAGE {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        received += n;
+        let frames = decoder.push(&buffer[..n]);
+        if !frames.is_empty() {
+            return Ok(frames);
+        }
+    }
+    Err(ProtocolError::Timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(seq: u8, rssi: u8) -> StreamFrame {
+        StreamFrame {
+            seq,
+            slot: seq % 2,
+            millis: 1000 + seq as u32 * 10,
+            peak: Peak {
+                lap_id: 3,
+                ms_val: 0xA55A,
+                rssi,
+            },
+        }
+    }
+
+    fn bytes(frames: &[StreamFrame]) -> Vec<u8> {
+        frames.iter().flat_map(|frame| frame.encode()).collect()
+    }
+
+    #[test]
+    fn decodes_frames_split_across_reads() {
+        let sent = [frame(0, 80), frame(1, 90), frame(2, 100)];
+        let mut decoder = StreamDecoder::new();
+        let mut received = Vec::new();
+        for chunk in bytes(&sent).chunks(5) {
+            received.extend(decoder.push(chunk));
+        }
+        assert_eq!(received, sent);
+        assert_eq!(
+            decoder.stats(),
+            StreamStats {
+                frames: 3,
+                ..StreamStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn resynchronises_after_garbage() {
+        let mut input = vec![0x12, 0xA5, 0x5A, 0x00, 0xA5];
+        input.extend(bytes(&[frame(7, 90)]));
+        let mut corrupt = frame(8, 91).encode();
+        corrupt[11] ^= 0x01;
+        input.extend(corrupt);
+        input.extend(bytes(&[frame(9, 92)]));
+
+        let mut decoder = StreamDecoder::new();
+        let received = decoder.push(&input);
+        assert_eq!(received, vec![frame(7, 90), frame(9, 92)]);
+        let stats = decoder.stats();
+        assert_eq!(stats.frames, 2);
+        assert_eq!(stats.dropped, 1);
+        assert!(stats.corrupt >= 2, "{:?}", stats);
+        assert_eq!(stats.skipped_bytes, 5 + FRAME_LEN as u64);
+    }
+
+    #[test]
+    fn reads_until_a_frame_is_complete() {
+        let input = bytes(&[frame(1, 80), frame(2, 81)]);
+        let mut reader = &input[..20];
+        let mut decoder = StreamDecoder::new();
+        assert_eq!(
+            read_frames(&mut reader, &mut decoder).unwrap(),
+            vec![frame(1, 80)]
+        );
+        assert!(matches!(
+            read_frames(&mut reader, &mut decoder),
+            Err(ProtocolError::Timeout)
+        ));
+
+        let garbage = vec![0x55; 1024];
+        assert!(matches!(
+            read_frames(&mut garbage.as_slice(), &mut decoder),
+            Err(ProtocolError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn counts_gaps_across_the_sequence_wrap() {
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&bytes(&[frame(254, 80), frame(255, 80), frame(2, 80)]));
+        assert_eq!(decoder.stats().dropped, 2);
+
+        decoder.resync();
+        decoder.push(&bytes(&[frame(40, 80)]));
+        assert_eq!(decoder.stats().dropped, 2);
+    }
+}
@@ -10,6 +10,17 @@ struct Rssi {
     race_id: i32,
 }
 
+#[derive(Deserialize, Serialize)]
+struct Lap {
+    id: i32,
+    race_id: i32,
+    node_index: i32,
+    lap_number: i32,
+    time: f64,
+    host_time: Option<f64>,
+    uncertainty: Option<f64>,
+}
+
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
 async fn greet(name: &str) -> Result<String, String> {
@@ -50,12 +61,32 @@ async fn get_rssi() -> Result<Vec<Rssi>, String> {
     Ok(res)
 }
 
+/// Laps the server detected in race `race_id`.
+#[tauri::command]
+async fn get_laps(race_id: i32) -> Result<Vec<Lap>, String> {
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    client
+        .get("https://localhost:3000/laps")
+        .query(&[("race_id", race_id)])
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json::<Vec<Lap>>()
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_http::init())
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![greet, get_rssi])
+        .invoke_handler(tauri::generate_handler![greet, get_rssi, get_laps])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
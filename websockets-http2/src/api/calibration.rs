@@ -0,0 +1,167 @@
+use crate::calibration::{DEFAULT_NOISE_DURATION, MAX_NOISE_DURATION};
+use crate::db;
+use crate::enums::command::Command;
+use crate::structs::calibration::{CalibrationStatus, PassProfile, SetPilot, StartCalibration};
+use crate::structs::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Start measuring the node's noise floor; the pilot should keep the drone
+/// idle until the status moves on to flyovers.
+pub async fn start_calibration(
+    State(state): State<AppState>,
+    Path(node_index): Path<usize>,
+    Json(payload): Json<StartCalibration>,
+) -> Result<Json<CalibrationStatus>, StatusCode> {
+    let noise_duration = match payload.noise_s {
+        Some(seconds) if seconds > 0.0 && seconds <= MAX_NOISE_DURATION.as_secs_f64() => {
+            Duration::from_secs_f64(seconds)
+        }
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => DEFAULT_NOISE_DURATION,
+    };
+
+    let (response_sender, response_receiver) = oneshot::channel();
+    let command = Command::StartCalibration {
+        node_index,
+        pilot: payload.pilot.filter(|pilot| !pilot.is_empty()),
+        noise_duration,
+        respond_to: response_sender,
+    };
+    state.command_sender.send(command).await.unwrap();
+
+    response_receiver
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            tracing::error!("Failed to start calibrating node {}: {}", node_index, e);
+            StatusCode::CONFLICT
+        })?;
+    get_calibration(State(state), Path(node_index)).await
+}
+
+pub async fn get_calibration(
+    State(state): State<AppState>,
+    Path(node_index): Path<usize>,
+) -> Result<Json<CalibrationStatus>, StatusCode> {
+    let (response_sender, response_receiver) = oneshot::channel();
+    let command = Command::GetCalibration {
+        node_index,
+        respond_to: response_sender,
+    };
+    state.command_sender.send(command).await.unwrap();
+
+    response_receiver
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// End the calibration and save the thresholds it proposes.
+pub async fn finish_calibration(
+    State(state): State<AppState>,
+    Path(node_index): Path<usize>,
+) -> Result<Json<PassProfile>, StatusCode> {
+    let (response_sender, response_receiver) = oneshot::channel();
+    let command = Command::FinishCalibration {
+        node_index,
+        respond_to: response_sender,
+    };
+    state.command_sender.send(command).await.unwrap();
+
+    let profile = response_receiver
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            tracing::error!("Failed to finish calibrating node {}: {}", node_index, e);
+            StatusCode::CONFLICT
+        })?;
+
+    let saved = sqlx::query_as::<_, PassProfile>(
+        "INSERT INTO pass_profile (node_index, pilot, noise_floor, noise_sd, weakest_peak, enter_rssi, exit_rssi)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (node_index, pilot) DO UPDATE SET
+                noise_floor = excluded.noise_floor,
+                noise_sd = excluded.noise_sd,
+                weakest_peak = excluded.weakest_peak,
+                enter_rssi = excluded.enter_rssi,
+                exit_rssi = excluded.exit_rssi
+            RETURNING node_index, pilot, noise_floor, noise_sd, weakest_peak, enter_rssi, exit_rssi",
+    )
+    .bind(profile.node_index)
+    .bind(profile.pilot)
+    .bind(profile.noise_floor)
+    .bind(profile.noise_sd)
+    .bind(profile.weakest_peak)
+    .bind(profile.enter_rssi)
+    .bind(profile.exit_rssi)
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to save pass profile: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(saved))
+}
+
+/// Set the pilot flying a node, switching it to their profile. Returns the
+/// profile now in use; none means the configured thresholds.
+pub async fn set_pilot(
+    State(state): State<AppState>,
+    Path(node_index): Path<usize>,
+    Json(payload): Json<SetPilot>,
+) -> Result<Json<Option<PassProfile>>, StatusCode> {
+    let pilot = payload.pilot.filter(|pilot| !pilot.is_empty());
+    let database_error = |e: sqlx::Error| {
+        tracing::error!("Failed to set the pilot of node {}: {:?}", node_index, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let profile = db::pass_profile(&state.db, node_index as i32, pilot.as_deref())
+        .await
+        .map_err(database_error)?;
+
+    let (response_sender, response_receiver) = oneshot::channel();
+    let command = Command::SetPilot {
+        node_index,
+        pilot: pilot.clone(),
+        profile: profile.clone(),
+        respond_to: response_sender,
+    };
+    state.command_sender.send(command).await.unwrap();
+    response_receiver
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            tracing::error!("Failed to set the pilot of node {}: {}", node_index, e);
+            StatusCode::NOT_FOUND
+        })?;
+
+    let saved = match &pilot {
+        Some(pilot) => {
+            sqlx::query(
+                "INSERT INTO node_pilot (node_index, pilot) VALUES (?, ?)
+                    ON CONFLICT (node_index) DO UPDATE SET pilot = excluded.pilot",
+            )
+            .bind(node_index as i32)
+            .bind(pilot)
+            .execute(&state.db)
+            .await
+        }
+        None => {
+            sqlx::query("DELETE FROM node_pilot WHERE node_index = ?")
+                .bind(node_index as i32)
+                .execute(&state.db)
+                .await
+        }
+    };
+    saved.map_err(database_error)?;
+
+    Ok(Json(profile))
+}
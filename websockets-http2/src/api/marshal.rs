@@ -0,0 +1,492 @@
+use crate::db;
+use crate::enums::event::Event;
+use crate::leaderboard;
+use crate::structs::lap::{
+    AddLap, EditNote, Lap, LapCorrection, LapDiff, LapEdit, LapRecalculation, MoveLap,
+};
+use crate::structs::leaderboard::Standing;
+use crate::structs::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use sqlx::{Sqlite, Transaction};
+
+const LAP_COLUMNS: &str = "id, race_id, node_index, lap_number, time, host_time, uncertainty";
+
+fn database_error(e: sqlx::Error) -> StatusCode {
+    tracing::error!("Failed to correct laps: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+fn check(note: &EditNote, time: Option<f64>) -> Result<(), StatusCode> {
+    let valid_time = time.is_none_or(|time| time >= 0.0 && time.is_finite());
+    if note.by.trim().is_empty() || note.reason.trim().is_empty() || !valid_time {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(())
+}
+
+/// Add a lap the nodes missed, crossing at `time` seconds since the race
+/// start.
+pub async fn add_lap(
+    State(state): State<AppState>,
+    Path(race_id): Path<i32>,
+    Json(payload): Json<AddLap>,
+) -> Result<Json<LapCorrection>, StatusCode> {
+    check(&payload.note, Some(payload.time))?;
+    let mut transaction = state.db.begin().await.map_err(database_error)?;
+    sqlx::query("SELECT id FROM race WHERE id = ?")
+        .bind(race_id)
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let lap = sqlx::query_as::<_, Lap>(&format!(
+        "INSERT INTO lap (race_id, node_index, lap_number, time) VALUES (?, ?, 0, ?) RETURNING {}",
+        LAP_COLUMNS
+    ))
+    .bind(race_id)
+    .bind(payload.node_index)
+    .bind(payload.time)
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+
+    let edit = record(
+        &mut transaction,
+        &lap,
+        "add",
+        Some(lap.time),
+        None,
+        &payload.note,
+    )
+    .await?;
+    finish(&state, transaction, edit).await
+}
+
+/// Move a lap to another crossing. Its time is then the director's, so it
+/// loses the uncertainty of a detected one.
+pub async fn move_lap(
+    State(state): State<AppState>,
+    Path(lap_id): Path<i32>,
+    Json(payload): Json<MoveLap>,
+) -> Result<Json<LapCorrection>, StatusCode> {
+    check(&payload.note, Some(payload.time))?;
+    let mut transaction = state.db.begin().await.map_err(database_error)?;
+    let lap = find_lap(&mut transaction, lap_id).await?;
+
+    sqlx::query("UPDATE lap SET time = ?, uncertainty = NULL WHERE id = ?")
+        .bind(payload.time)
+        .bind(lap_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(database_error)?;
+
+    let edit = record(
+        &mut transaction,
+        &lap,
+        "move",
+        Some(payload.time),
+        Some(lap.time),
+        &payload.note,
+    )
+    .await?;
+    finish(&state, transaction, edit).await
+}
+
+/// Delete a phantom lap.
+pub async fn delete_lap(
+    State(state): State<AppState>,
+    Path(lap_id): Path<i32>,
+    Json(note): Json<EditNote>,
+) -> Result<Json<LapCorrection>, StatusCode> {
+    check(&note, None)?;
+    let mut transaction = state.db.begin().await.map_err(database_error)?;
+    let lap = find_lap(&mut transaction, lap_id).await?;
+
+    sqlx::query("DELETE FROM lap WHERE id = ?")
+        .bind(lap_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(database_error)?;
+
+    let edit = record(
+        &mut transaction,
+        &lap,
+        "delete",
+        None,
+        Some(lap.time),
+        &note,
+    )
+    .await?;
+    finish(&state, transaction, edit).await
+}
+
+/// Corrections made to a race's laps, oldest first.
+pub async fn get_lap_edits(
+    State(state): State<AppState>,
+    Path(race_id): Path<i32>,
+) -> Result<Json<Vec<LapEdit>>, StatusCode> {
+    sqlx::query_as::<_, LapEdit>("SELECT * FROM lap_edit WHERE race_id = ? ORDER BY id")
+        .bind(race_id)
+        .fetch_all(&state.db)
+        .await
+        .map(Json)
+        .map_err(database_error)
+}
+
+pub async fn get_leaderboard(
+    State(state): State<AppState>,
+    Path(race_id): Path<i32>,
+) -> Result<Json<Vec<Standing>>, StatusCode> {
+    let format = db::race_format(&state.db, race_id)
+        .await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let laps = db::race_laps(&state.db, race_id)
+        .await
+        .map_err(database_error)?;
+    Ok(Json(leaderboard::standings(&laps, format)))
+}
+
+async fn find_lap(
+    transaction: &mut Transaction<'_, Sqlite>,
+    lap_id: i32,
+) -> Result<Lap, StatusCode> {
+    sqlx::query_as::<_, Lap>(&format!("SELECT {} FROM lap WHERE id = ?", LAP_COLUMNS))
+        .bind(lap_id)
+        .fetch_optional(&mut **transaction)
+        .await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Log the correction and number the node's laps again.
+async fn record(
+    transaction: &mut Transaction<'_, Sqlite>,
+    lap: &Lap,
+    action: &str,
+    time: Option<f64>,
+    previous_time: Option<f64>,
+    note: &EditNote,
+) -> Result<LapEdit, StatusCode> {
+    let edit = sqlx::query_as::<_, LapEdit>(
+        "INSERT INTO lap_edit (race_id, lap_id, node_index, action, time, previous_time, by, reason)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            RETURNING id, race_id, lap_id, node_index, action, time, previous_time, by, reason, edited_at",
+    )
+    .bind(lap.race_id)
+    .bind(lap.id)
+    .bind(lap.node_index)
+    .bind(action)
+    .bind(time)
+    .bind(previous_time)
+    .bind(note.by.trim())
+    .bind(note.reason.trim())
+    .fetch_one(&mut **transaction)
+    .await
+    .map_err(database_error)?;
+
+    db::renumber_laps(transaction, lap.race_id, lap.node_index)
+        .await
+        .map_err(database_error)?;
+    Ok(edit)
+}
+
+/// Commit the correction and send the race's laps and leaderboard after it
+/// to every client.
+async fn finish(
+    state: &AppState,
+    transaction: Transaction<'_, Sqlite>,
+    edit: LapEdit,
+) -> Result<Json<LapCorrection>, StatusCode> {
+    transaction.commit().await.map_err(database_error)?;
+    tracing::info!("Lap correction: {:?}", edit);
+
+    let (laps, leaderboard) = standings(state, edit.race_id).await?;
+    let correction = LapCorrection {
+        edit,
+        laps,
+        leaderboard,
+    };
+    let _ = state
+        .tx
+        .send(serde_json::to_string(&Event::LapsCorrected(correction.clone())).unwrap());
+    Ok(Json(correction))
+}
+
+/// Apply a reviewed recalculation lap by lap, each change logged like a
+/// correction made by hand, and send the race's laps and leaderboard after
+/// it to every client.
+pub(super) async fn apply_recalculation(
+    state: &AppState,
+    diff: LapDiff,
+    note: EditNote,
+) -> Result<Json<LapRecalculation>, StatusCode> {
+    check(&note, None)?;
+    let race_id = diff.race_id;
+    let mut transaction = state.db.begin().await.map_err(database_error)?;
+    if db::hand_edits(&mut transaction, race_id)
+        .await
+        .map_err(database_error)?
+        > 0
+    {
+        tracing::error!(
+            "Race {} has laps corrected by hand; not replacing them",
+            race_id
+        );
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let mut edits = Vec::new();
+    for lap in diff.removed {
+        sqlx::query("DELETE FROM lap WHERE id = ?")
+            .bind(lap.id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(database_error)?;
+        let edit = record(
+            &mut transaction,
+            &lap,
+            "recalculate",
+            None,
+            Some(lap.time),
+            &note,
+        )
+        .await?;
+        edits.push(edit);
+    }
+    for change in diff.changed {
+        let new = change.recalculated;
+        sqlx::query("UPDATE lap SET time = ?, host_time = ?, uncertainty = ? WHERE id = ?")
+            .bind(new.time)
+            .bind(new.host_time)
+            .bind(new.uncertainty)
+            .bind(change.live.id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(database_error)?;
+        let edit = record(
+            &mut transaction,
+            &change.live,
+            "recalculate",
+            Some(new.time),
+            Some(change.live.time),
+            &note,
+        )
+        .await?;
+        edits.push(edit);
+    }
+    for new in diff.added {
+        let lap = sqlx::query_as::<_, Lap>(&format!(
+            "INSERT INTO lap (race_id, node_index, lap_number, time, host_time, uncertainty) VALUES (?, ?, 0, ?, ?, ?) RETURNING {}",
+            LAP_COLUMNS
+        ))
+        .bind(race_id)
+        .bind(new.node_index)
+        .bind(new.time)
+        .bind(new.host_time)
+        .bind(new.uncertainty)
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(database_error)?;
+        let edit = record(
+            &mut transaction,
+            &lap,
+            "recalculate",
+            Some(lap.time),
+            None,
+            &note,
+        )
+        .await?;
+        edits.push(edit);
+    }
+    transaction.commit().await.map_err(database_error)?;
+    tracing::info!(
+        "Race {}: recalculation accepted, {} laps changed",
+        race_id,
+        edits.len()
+    );
+
+    let (laps, leaderboard) = standings(state, race_id).await?;
+    let recalculation = LapRecalculation {
+        race_id,
+        edits,
+        laps,
+        leaderboard,
+    };
+    let _ = state
+        .tx
+        .send(serde_json::to_string(&Event::LapsRecalculated(recalculation.clone())).unwrap());
+    Ok(Json(recalculation))
+}
+
+/// The race's laps and the leaderboard they make in its format.
+async fn standings(
+    state: &AppState,
+    race_id: i32,
+) -> Result<(Vec<Lap>, Vec<Standing>), StatusCode> {
+    let laps = db::race_laps(&state.db, race_id)
+        .await
+        .map_err(database_error)?;
+    let format = db::race_format(&state.db, race_id)
+        .await
+        .map_err(database_error)?
+        .unwrap_or_default();
+    let leaderboard = leaderboard::standings(&laps, format);
+    Ok((laps, leaderboard))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::race_format::RaceFormat;
+    use crate::recalculation;
+    use crate::structs::lap::CreateLap;
+
+    fn note(by: &str) -> EditNote {
+        EditNote {
+            by: by.to_string(),
+            reason: " missed by the node ".to_string(),
+        }
+    }
+
+    /// Race with laps of node 0 stored at `times`, and their ids.
+    async fn race(state: &AppState, times: &[f64]) -> (i32, Vec<i32>) {
+        let race_id = db::create_race(&state.db, RaceFormat::Open)
+            .await
+            .unwrap()
+            .id;
+        let mut ids = Vec::new();
+        for &time in times {
+            let lap = CreateLap {
+                race_id,
+                node_index: 0,
+                time,
+                host_time: Some(time),
+                uncertainty: Some(0.01),
+            };
+            ids.push(db::insert_lap(&state.db, &lap).await.unwrap().id);
+        }
+        (race_id, ids)
+    }
+
+    fn laps(correction: &LapCorrection) -> Vec<(i32, f64)> {
+        correction
+            .laps
+            .iter()
+            .map(|lap| (lap.lap_number, lap.time))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn logs_every_correction() {
+        let state = AppState::in_memory().await;
+        let (race_id, ids) = race(&state, &[10.0, 20.0]).await;
+
+        let add = AddLap {
+            node_index: 0,
+            time: 15.0,
+            note: note("director"),
+        };
+        let added = add_lap(State(state.clone()), Path(race_id), Json(add))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(laps(&added), [(1, 10.0), (2, 15.0), (3, 20.0)]);
+
+        let to = MoveLap {
+            time: 12.0,
+            note: note("director"),
+        };
+        let moved = move_lap(State(state.clone()), Path(ids[1]), Json(to))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(laps(&moved), [(1, 10.0), (2, 12.0), (3, 15.0)]);
+        let lap = moved.laps.iter().find(|lap| lap.id == ids[1]).unwrap();
+        assert_eq!(lap.uncertainty, None);
+
+        let deleted = delete_lap(State(state.clone()), Path(ids[0]), Json(note(" marshal ")))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(laps(&deleted), [(1, 12.0), (2, 15.0)]);
+
+        let edits = get_lap_edits(State(state.clone()), Path(race_id))
+            .await
+            .unwrap()
+            .0;
+        let logged: Vec<(&str, Option<f64>, Option<f64>)> = edits
+            .iter()
+            .map(|edit| (edit.action.as_str(), edit.time, edit.previous_time))
+            .collect();
+        assert_eq!(
+            logged,
+            [
+                ("add", Some(15.0), None),
+                ("move", Some(12.0), Some(20.0)),
+                ("delete", None, Some(10.0)),
+            ]
+        );
+        assert_eq!(edits[1].lap_id, ids[1]);
+        assert_eq!(edits[2].by, "marshal");
+        assert_eq!(edits[2].reason, "missed by the node");
+    }
+
+    #[tokio::test]
+    async fn refuses_corrections_without_a_note_or_lap() {
+        let state = AppState::in_memory().await;
+        let (race_id, ids) = race(&state, &[10.0]).await;
+
+        let add = |time: f64, by: &str| AddLap {
+            node_index: 0,
+            time,
+            note: note(by),
+        };
+        for (payload, status) in [
+            (add(15.0, " "), StatusCode::BAD_REQUEST),
+            (add(-1.0, "director"), StatusCode::BAD_REQUEST),
+            (add(f64::NAN, "director"), StatusCode::BAD_REQUEST),
+        ] {
+            let refused = add_lap(State(state.clone()), Path(race_id), Json(payload)).await;
+            assert_eq!(refused.unwrap_err(), status);
+        }
+        let refused = add_lap(
+            State(state.clone()),
+            Path(race_id + 1),
+            Json(add(15.0, "director")),
+        )
+        .await;
+        assert_eq!(refused.unwrap_err(), StatusCode::NOT_FOUND);
+        let refused = delete_lap(
+            State(state.clone()),
+            Path(ids[0] + 1),
+            Json(note("director")),
+        )
+        .await;
+        assert_eq!(refused.unwrap_err(), StatusCode::NOT_FOUND);
+
+        let edits = get_lap_edits(State(state.clone()), Path(race_id))
+            .await
+            .unwrap();
+        assert!(edits.0.is_empty());
+        let stored = db::race_laps(&state.db, race_id).await.unwrap();
+        assert_eq!(stored.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_hand_corrections_from_a_recalculation() {
+        let state = AppState::in_memory().await;
+        let (race_id, ids) = race(&state, &[10.0]).await;
+        let deleted = delete_lap(State(state.clone()), Path(ids[0]), Json(note("director")));
+        assert!(deleted.await.is_ok());
+
+        let diff = recalculation::diff(race_id, &[], Vec::new(), 1);
+        let refused = apply_recalculation(&state, diff, note("director")).await;
+        assert_eq!(refused.unwrap_err(), StatusCode::CONFLICT);
+    }
+}
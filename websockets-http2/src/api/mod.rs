@@ -1,3 +1,6 @@
+pub mod calibration;
 pub mod count;
+pub mod marshal;
+pub mod nodes;
 pub mod post;
 pub mod race;
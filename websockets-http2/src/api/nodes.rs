@@ -0,0 +1,133 @@
+use crate::enums::command::Command;
+use crate::filter::{self, FilterConfig};
+use crate::structs::frequency::{NodeFrequency, SetFrequency};
+use crate::structs::node_status::NodeStatus;
+use crate::structs::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use rustimer::bands;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+
+pub async fn get_nodes(State(state): State<AppState>) -> (StatusCode, Json<Vec<NodeStatus>>) {
+    let (response_sender, response_receiver) = oneshot::channel();
+
+    let command = Command::GetNodes {
+        respond_to: response_sender,
+    };
+
+    state.command_sender.send(command).await.unwrap();
+
+    match response_receiver.await {
+        Ok(nodes) => (StatusCode::OK, Json(nodes)),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::new())),
+    }
+}
+
+pub async fn get_bands() -> Json<Value> {
+    Json(
+        bands::BANDS
+            .iter()
+            .map(|band| {
+                json!({
+                    "name": band.name,
+                    "code": band.code.to_string(),
+                    "channels": band.channels,
+                })
+            })
+            .collect(),
+    )
+}
+
+pub async fn set_frequency(
+    State(state): State<AppState>,
+    Path(node_index): Path<usize>,
+    Json(payload): Json<SetFrequency>,
+) -> Result<Json<NodeFrequency>, StatusCode> {
+    let frequency = match (payload.frequency, payload.band, payload.channel) {
+        (Some(frequency), None, None) => frequency,
+        (None, Some(band), Some(channel)) => {
+            bands::frequency(&band, channel).ok_or(StatusCode::BAD_REQUEST)?
+        }
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    if !(bands::MIN_FREQUENCY..=bands::MAX_FREQUENCY).contains(&frequency) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let (response_sender, response_receiver) = oneshot::channel();
+    let command = Command::SetFrequency {
+        node_index,
+        frequency,
+        respond_to: response_sender,
+    };
+    state.command_sender.send(command).await.unwrap();
+
+    let tuned = response_receiver
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            tracing::error!("Failed to tune node {}: {}", node_index, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let saved = sqlx::query_as::<_, NodeFrequency>(
+        "INSERT INTO node_frequency (node_index, frequency) VALUES (?, ?)
+            ON CONFLICT (node_index) DO UPDATE SET frequency = excluded.frequency
+            RETURNING node_index, frequency",
+    )
+    .bind(node_index as i32)
+    .bind(tuned)
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to save node frequency: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(saved))
+}
+
+/// Replace a node's RSSI filters, e.g. `[{"kind": "median", "window": 5}]`;
+/// an empty list leaves the RSSI unfiltered.
+pub async fn set_filters(
+    State(state): State<AppState>,
+    Path(node_index): Path<usize>,
+    Json(filters): Json<Vec<FilterConfig>>,
+) -> Result<Json<Vec<FilterConfig>>, StatusCode> {
+    filter::validate(&filters).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let (response_sender, response_receiver) = oneshot::channel();
+    let command = Command::SetFilters {
+        node_index,
+        filters: filters.clone(),
+        respond_to: response_sender,
+    };
+    state.command_sender.send(command).await.unwrap();
+
+    response_receiver
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            tracing::error!("Failed to set the filters of node {}: {}", node_index, e);
+            StatusCode::NOT_FOUND
+        })?;
+
+    sqlx::query(
+        "INSERT INTO node_filters (node_index, filters) VALUES (?, ?)
+            ON CONFLICT (node_index) DO UPDATE SET filters = excluded.filters",
+    )
+    .bind(node_index as i32)
+    .bind(serde_json::to_string(&filters).unwrap())
+    .execute(&state.db)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to save node filters: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(filters))
+}
@@ -1,34 +1,122 @@
+use crate::api::marshal;
+use crate::config::PassConfig;
+use crate::db;
 use crate::enums::command::Command;
+use crate::recalculation;
+use crate::structs::lap::{EditNote, Lap, LapDiff, LapRecalculation};
+use crate::structs::node::Node;
+use crate::structs::race::{FalseStart, RaceState, RaceStatus, RaceTransition, StageRace};
 use crate::structs::state::AppState;
-use axum::{extract::State, http::StatusCode, Json};
-
-pub async fn start_race(State(state): State<AppState>) -> StatusCode {
-    // create race in db
-    let bytes = std::time::Instant::now().elapsed().as_nanos().to_be_bytes();
-    let race = sqlx::query_as::<_, crate::structs::race::Race>(
-        "INSERT INTO race (start_time) VALUES (?) RETURNING id, start_time, end_time",
-    )
-    .bind(&bytes[..])
-    .fetch_one(&state.db)
-    .await;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::time::Instant;
+use tokio::sync::oneshot;
 
+/// Send a race command to the worker; a change the race's state does not
+/// allow is a conflict.
+async fn change_race(
+    state: &AppState,
+    command: impl FnOnce(oneshot::Sender<Result<RaceStatus, String>>) -> Command,
+) -> Result<Json<RaceStatus>, StatusCode> {
+    let (response_sender, response_receiver) = oneshot::channel();
     state
         .command_sender
-        .send(Command::StartRace {
-            time: std::time::Instant::now(),
-            race_id: race.unwrap().id,
-        })
+        .send(command(response_sender))
         .await
         .unwrap();
-    StatusCode::OK
-}
 
-pub async fn stop_race(State(state): State<AppState>) -> StatusCode {
-    let command = Command::StopRace;
+    response_receiver
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .map_err(|e| {
+            tracing::error!("Race command refused: {}", e);
+            StatusCode::CONFLICT
+        })
+}
 
+pub async fn get_race(State(state): State<AppState>) -> Result<Json<RaceStatus>, StatusCode> {
+    let (response_sender, response_receiver) = oneshot::channel();
+    let command = Command::GetRace {
+        respond_to: response_sender,
+    };
     state.command_sender.send(command).await.unwrap();
 
-    StatusCode::OK
+    response_receiver
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Create a race and stage it, once the last one is over, e.g. with
+/// `{"format": {"kind": "timed", "duration_s": 120, "grace_s": 30}}`.
+pub async fn stage_race(
+    State(state): State<AppState>,
+    payload: Option<Json<StageRace>>,
+) -> Result<Json<RaceStatus>, StatusCode> {
+    let format = payload.and_then(|Json(payload)| payload.format);
+    if let Some(format) = &format {
+        format.validate().map_err(|e| {
+            tracing::error!("Cannot stage a race: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+    }
+    change_race(&state, |respond_to| Command::StageRace {
+        format,
+        respond_to,
+    })
+    .await
+}
+
+/// Start the staged race's countdown. With no race staged, a new one is
+/// staged first.
+pub async fn start_race(State(state): State<AppState>) -> Result<Json<RaceStatus>, StatusCode> {
+    let Json(race) = get_race(State(state.clone())).await?;
+    if race.state != RaceState::Staging {
+        let Json(_) = stage_race(State(state.clone()), None).await?;
+    }
+    let time = Instant::now();
+    change_race(&state, |respond_to| Command::StartRace {
+        time,
+        countdown: None,
+        respond_to,
+    })
+    .await
+}
+
+/// Finish the running race now, in overtime or not.
+pub async fn stop_race(State(state): State<AppState>) -> Result<Json<RaceStatus>, StatusCode> {
+    let time = Instant::now();
+    change_race(&state, |respond_to| Command::StopRace { time, respond_to }).await
+}
+
+/// Call off the race, whatever stage it is at; its laps stay stored.
+pub async fn abort_race(State(state): State<AppState>) -> Result<Json<RaceStatus>, StatusCode> {
+    let time = Instant::now();
+    change_race(&state, |respond_to| Command::AbortRace { time, respond_to }).await
+}
+
+/// State changes of a race, in order.
+pub async fn get_race_transitions(
+    State(state): State<AppState>,
+    Path(race_id): Path<i32>,
+) -> Result<Json<Vec<RaceTransition>>, StatusCode> {
+    sqlx::query_as::<_, RaceTransition>(
+        "SELECT id, race_id, state, changed_at, time FROM race_transition WHERE race_id = ? ORDER BY changed_at, id",
+    )
+    .bind(race_id)
+    .fetch_all(&state.db)
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!("Failed to load the transitions of race {}: {}", race_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
 }
 
 pub async fn debug(State(state): State<AppState>) -> Json<Vec<crate::structs::node::NodeJson>> {
@@ -43,6 +131,7 @@ pub async fn debug(State(state): State<AppState>) -> Json<Vec<crate::structs::no
             .into_iter()
             .map(|node| crate::structs::node::NodeJson {
                 id: node.id,
+                node_index: node.node_index,
                 peak: node.peak,
                 time: node.time,
                 duration: node.duration,
@@ -51,3 +140,224 @@ pub async fn debug(State(state): State<AppState>) -> Json<Vec<crate::structs::no
             .collect(),
     )
 }
+
+#[derive(Debug, Deserialize)]
+pub struct AcceptRecalculation {
+    pub thresholds: PassConfig,
+    /// Token of the diff reviewed.
+    pub token: String,
+    #[serde(flatten)]
+    pub note: EditNote,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LapQuery {
+    /// Only laps of this race; all laps when unset.
+    pub race_id: Option<i32>,
+}
+
+/// Laps as stored by the worker, in race and crossing order.
+pub async fn get_laps(
+    State(state): State<AppState>,
+    Query(query): Query<LapQuery>,
+) -> Result<Json<Vec<Lap>>, StatusCode> {
+    sqlx::query_as::<_, Lap>(
+        "SELECT id, race_id, node_index, lap_number, time, host_time, uncertainty FROM lap WHERE ?1 IS NULL OR race_id = ?1 ORDER BY race_id, time",
+    )
+    .bind(query.race_id)
+    .fetch_all(&state.db)
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!("Failed to load laps: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Detect the race's laps again from its stored RSSI with other thresholds,
+/// e.g. `{"enter_rssi": 85, "exit_rssi": 70}`, and compare them with the
+/// stored laps. Nothing is changed until the result is accepted.
+pub async fn recalculate_laps(
+    State(state): State<AppState>,
+    Path(race_id): Path<i32>,
+    Json(thresholds): Json<PassConfig>,
+) -> Result<Json<LapDiff>, StatusCode> {
+    recalculate(&state.db, race_id, thresholds).await.map(Json)
+}
+
+/// Accept a recalculation as reviewed, e.g. `{"thresholds": {"enter_rssi":
+/// 85, "exit_rssi": 70}, "token": "…", "by": "…", "reason": "…"}`, with the
+/// token of its diff. A diff that no longer matches the stored laps, or a
+/// race with laps corrected by hand, is a conflict.
+pub async fn accept_recalculated_laps(
+    State(state): State<AppState>,
+    Path(race_id): Path<i32>,
+    Json(payload): Json<AcceptRecalculation>,
+) -> Result<Json<LapRecalculation>, StatusCode> {
+    let diff = recalculate(&state.db, race_id, payload.thresholds).await?;
+    if diff.token != payload.token {
+        tracing::error!(
+            "The laps of race {} changed since the recalculation was reviewed",
+            race_id
+        );
+        return Err(StatusCode::CONFLICT);
+    }
+    marshal::apply_recalculation(&state, diff, payload.note).await
+}
+
+async fn recalculate(
+    db: &SqlitePool,
+    race_id: i32,
+    thresholds: PassConfig,
+) -> Result<LapDiff, StatusCode> {
+    thresholds.validate().map_err(|e| {
+        tracing::error!(
+            "Invalid thresholds to recalculate race {} with: {}",
+            race_id,
+            e
+        );
+        StatusCode::BAD_REQUEST
+    })?;
+    let database_error = |e: sqlx::Error| {
+        tracing::error!("Failed to load race {}: {}", race_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    sqlx::query("SELECT id FROM race WHERE id = ?")
+        .bind(race_id)
+        .fetch_optional(db)
+        .await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    // The column has integer affinity, so whole durations come back as
+    // integers.
+    let rows = sqlx::query_as::<_, Node>(
+        "SELECT id, node_index, peak, time, CAST(duration AS REAL) AS duration, race_id FROM node WHERE race_id = ?",
+    )
+    .bind(race_id)
+    .fetch_all(db)
+    .await
+    .map_err(database_error)?;
+    let live = db::race_laps(db, race_id).await.map_err(database_error)?;
+    let mut connection = db.acquire().await.map_err(database_error)?;
+    let hand_edits = db::hand_edits(&mut connection, race_id)
+        .await
+        .map_err(database_error)?;
+
+    let laps = recalculation::recalculate(race_id, &rows, thresholds);
+    Ok(recalculation::diff(race_id, &live, laps, hand_edits))
+}
+
+/// Pilots flagged for crossing the gate before the start signal.
+pub async fn get_false_starts(
+    State(state): State<AppState>,
+    Path(race_id): Path<i32>,
+) -> Result<Json<Vec<FalseStart>>, StatusCode> {
+    sqlx::query_as::<_, FalseStart>(
+        "SELECT race_id, node_index, pilot, time, rssi FROM false_start WHERE race_id = ? ORDER BY time",
+    )
+    .bind(race_id)
+    .fetch_all(&state.db)
+    .await
+    .map(Json)
+    .map_err(|e| {
+        tracing::error!("Failed to load the false starts of race {}: {}", race_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::race_format::RaceFormat;
+    use crate::structs::lap::CreateLap;
+
+    /// Race with stored RSSI for passes of node 0 at 10 and 20 seconds and
+    /// the given laps stored.
+    async fn race(state: &AppState, laps: &[f64]) -> i32 {
+        let race_id = db::create_race(&state.db, RaceFormat::Open)
+            .await
+            .unwrap()
+            .id;
+        for pass in [10.0, 20.0] {
+            for (time, peak) in [(pass - 1.0, 50), (pass, 95), (pass + 0.5, 50)] {
+                sqlx::query(
+                    "INSERT INTO node (node_index, peak, time, duration, race_id) VALUES (0, ?, ?, 0.0, ?)",
+                )
+                .bind(peak)
+                .bind(time)
+                .bind(race_id)
+                .execute(&state.db)
+                .await
+                .unwrap();
+            }
+        }
+        for &time in laps {
+            store_lap(state, race_id, time).await;
+        }
+        race_id
+    }
+
+    async fn store_lap(state: &AppState, race_id: i32, time: f64) {
+        let lap = CreateLap {
+            race_id,
+            node_index: 0,
+            time,
+            host_time: Some(time),
+            uncertainty: None,
+        };
+        db::insert_lap(&state.db, &lap).await.unwrap();
+    }
+
+    async fn review(state: &AppState, race_id: i32) -> LapDiff {
+        let thresholds = Json(PassConfig::default());
+        recalculate_laps(State(state.clone()), Path(race_id), thresholds)
+            .await
+            .unwrap()
+            .0
+    }
+
+    async fn accept(
+        state: &AppState,
+        race_id: i32,
+        token: String,
+    ) -> Result<Json<LapRecalculation>, StatusCode> {
+        let payload = AcceptRecalculation {
+            thresholds: PassConfig::default(),
+            token,
+            note: EditNote {
+                by: "director".to_string(),
+                reason: "missed lap".to_string(),
+            },
+        };
+        accept_recalculated_laps(State(state.clone()), Path(race_id), Json(payload)).await
+    }
+
+    fn times(laps: &[Lap]) -> Vec<f64> {
+        laps.iter().map(|lap| lap.time).collect()
+    }
+
+    #[tokio::test]
+    async fn rejects_a_stale_recalculation() {
+        let state = AppState::in_memory().await;
+        let race_id = race(&state, &[10.0]).await;
+        let reviewed = review(&state, race_id).await;
+        assert_eq!(reviewed.unchanged, 1);
+        assert_eq!(reviewed.added.len(), 1);
+
+        // A lap stored after the review.
+        store_lap(&state, race_id, 30.0).await;
+        let stale = accept(&state, race_id, reviewed.token).await;
+        assert_eq!(stale.unwrap_err(), StatusCode::CONFLICT);
+        let laps = db::race_laps(&state.db, race_id).await.unwrap();
+        assert_eq!(times(&laps), [10.0, 30.0]);
+
+        let reviewed = review(&state, race_id).await;
+        assert_eq!(reviewed.removed.len(), 1);
+        let accepted = accept(&state, race_id, reviewed.token).await.unwrap().0;
+        assert_eq!(accepted.edits.len(), 2);
+        assert_eq!(times(&accepted.laps), [10.0, 20.0]);
+        let numbers: Vec<i32> = accepted.laps.iter().map(|lap| lap.lap_number).collect();
+        assert_eq!(numbers, [1, 2]);
+    }
+}
@@ -0,0 +1,287 @@
+use crate::filter::median;
+use crate::node_manager::Sample;
+use crate::structs::calibration::{CalibrationStatus, PassProfile};
+use std::time::{Duration, Instant};
+
+/// Idle time measured when the request does not say.
+pub const DEFAULT_NOISE_DURATION: Duration = Duration::from_secs(5);
+/// Longest idle time measured; requests asking for more are refused.
+pub const MAX_NOISE_DURATION: Duration = Duration::from_secs(10 * 60);
+/// Smallest rise over the noise floor a flyover is recorded at, however
+/// quiet the floor.
+const MIN_FLYOVER_RISE: f64 = 5.0;
+/// Smallest gap between the weakest flyover and the noise floor to place two
+/// thresholds in.
+const MIN_SPAN: f64 = 10.0;
+/// Where the thresholds go between the noise floor and the weakest flyover.
+const ENTER_FRACTION: f64 = 0.6;
+const EXIT_FRACTION: f64 = 0.3;
+
+/// RSSI with the pilot idle. Median and median absolute deviation, so a
+/// drone drifting by the gate while it is measured does not lift it.
+#[derive(Debug, Clone, Copy)]
+struct NoiseFloor {
+    level: f64,
+    sd: f64,
+}
+
+impl NoiseFloor {
+    fn measure(samples: &[u32]) -> Option<Self> {
+        let level = median(samples.iter().map(|&rssi| rssi as f64).collect())?;
+        let deviation = median(
+            samples
+                .iter()
+                .map(|&rssi| (rssi as f64 - level).abs())
+                .collect(),
+        )?;
+        Some(NoiseFloor {
+            level,
+            // Standard deviation of normal noise with that deviation.
+            sd: 1.4826 * deviation,
+        })
+    }
+
+    /// Highest RSSI noise alone reaches, in practice.
+    fn top(&self) -> u32 {
+        (self.level + 4.0 * self.sd).ceil() as u32
+    }
+
+    /// RSSI a sample has to exceed to count as part of a flyover.
+    fn flyover_level(&self) -> f64 {
+        self.top() as f64 + MIN_FLYOVER_RISE
+    }
+}
+
+enum Phase {
+    /// Pilot idle; samples go into the noise floor until `until`, set by
+    /// the first sample.
+    NoiseFloor {
+        until: Option<Instant>,
+        samples: Vec<u32>,
+    },
+    /// Pilot flying over the gate; `pass` is the peak of the flyover in
+    /// progress.
+    Flyovers {
+        floor: NoiseFloor,
+        pass: Option<u32>,
+    },
+}
+
+/// Calibration of a node's pass thresholds: first the noise floor with the
+/// pilot idle, then as many flyovers as the pilot makes. The weakest flyover
+/// sets how high the thresholds can go, the noise floor how low.
+pub struct Calibration {
+    node_index: usize,
+    pilot: Option<String>,
+    noise_duration: Duration,
+    phase: Phase,
+    flyovers: Vec<u32>,
+}
+
+impl Calibration {
+    pub fn new(node_index: usize, pilot: Option<String>, noise_duration: Duration) -> Self {
+        Calibration {
+            node_index,
+            pilot,
+            noise_duration: noise_duration.min(MAX_NOISE_DURATION),
+            phase: Phase::NoiseFloor {
+                until: None,
+                samples: Vec::new(),
+            },
+            flyovers: Vec::new(),
+        }
+    }
+
+    pub fn pilot(&self) -> Option<&str> {
+        self.pilot.as_deref()
+    }
+
+    pub fn update(&mut self, sample: Sample) {
+        match &mut self.phase {
+            Phase::NoiseFloor { until, samples } => {
+                let until = *until.get_or_insert(sample.at + self.noise_duration);
+                if sample.at < until {
+                    samples.push(sample.rssi);
+                    return;
+                }
+                let Some(floor) = NoiseFloor::measure(samples) else {
+                    return;
+                };
+                println!(
+                    "Node {}: noise floor {:.1} ± {:.1}, recording flyovers",
+                    self.node_index, floor.level, floor.sd
+                );
+                self.phase = Phase::Flyovers { floor, pass: None };
+            }
+            Phase::Flyovers { floor, pass } => {
+                if (sample.rssi as f64) > floor.flyover_level() {
+                    let peak = pass.get_or_insert(sample.rssi);
+                    *peak = (*peak).max(sample.rssi);
+                } else if (sample.rssi as f64) <= floor.level + 3.0 * floor.sd {
+                    // Back to the floor: the flyover is over.
+                    if let Some(peak) = pass.take() {
+                        println!("Node {}: flyover peaking at {}", self.node_index, peak);
+                        self.flyovers.push(peak);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Thresholds from what was recorded so far.
+    pub fn propose(&self) -> Result<PassProfile, String> {
+        let Phase::Flyovers { floor, .. } = self.phase else {
+            return Err("still measuring the noise floor".to_string());
+        };
+        let Some(&weakest_peak) = self.flyovers.iter().min() else {
+            return Err("no flyover recorded yet".to_string());
+        };
+        let span = weakest_peak as f64 - floor.level;
+        if span < MIN_SPAN {
+            return Err(format!(
+                "the weakest flyover peaked only {:.0} above the noise floor",
+                span
+            ));
+        }
+        let enter_rssi = (floor.level + ENTER_FRACTION * span).round() as u32;
+        let exit_rssi = ((floor.level + EXIT_FRACTION * span).round() as u32).max(floor.top() + 1);
+        if exit_rssi >= enter_rssi {
+            return Err(format!(
+                "noise up to {} leaves no room for thresholds below the flyovers",
+                floor.top()
+            ));
+        }
+        Ok(PassProfile {
+            node_index: self.node_index as i32,
+            pilot: self.pilot.clone().unwrap_or_default(),
+            noise_floor: floor.level,
+            noise_sd: floor.sd,
+            weakest_peak,
+            enter_rssi,
+            exit_rssi,
+        })
+    }
+
+    pub fn status(&self) -> CalibrationStatus {
+        let (phase, floor) = match &self.phase {
+            Phase::NoiseFloor { .. } => ("noise_floor", None),
+            Phase::Flyovers { floor, .. } => ("flyovers", Some(floor)),
+        };
+        let proposal = self.propose();
+        CalibrationStatus {
+            node_index: self.node_index,
+            pilot: self.pilot.clone(),
+            phase,
+            noise_floor: floor.map(|floor| floor.level),
+            noise_sd: floor.map(|floor| floor.sd),
+            flyovers: self.flyovers.clone(),
+            issue: proposal.as_ref().err().cloned(),
+            proposal: proposal.ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Calibration fed `rssi` at one sample every 10 ms, measuring the noise
+    /// floor for the first second.
+    fn calibrate(rssi: &[u32]) -> Calibration {
+        let start = Instant::now();
+        let mut calibration = Calibration::new(2, Some("ada".to_string()), Duration::from_secs(1));
+        for (i, &rssi) in rssi.iter().enumerate() {
+            calibration.update(Sample {
+                rssi,
+                at: start + Duration::from_millis(i as u64 * 10),
+                lap_id: 0,
+                ms_since_lap: 0,
+                latency: None,
+            });
+        }
+        calibration
+    }
+
+    /// A second of noise at `floor`, then a flyover to each of `peaks`.
+    fn session(floor: u32, peaks: &[u32]) -> Vec<u32> {
+        let mut rssi = vec![floor; 101];
+        for &peak in peaks {
+            rssi.extend([floor, peak - 10, peak, peak - 10, floor]);
+        }
+        rssi
+    }
+
+    #[test]
+    fn measures_noise_floor_robustly() {
+        // A drone drifting by while the floor is measured.
+        let floor = NoiseFloor::measure(&[40, 41, 39, 40, 90, 40, 41, 39, 40]).unwrap();
+        assert_eq!(floor.level, 40.0);
+        assert!((floor.sd - 1.4826).abs() < 1e-9, "{}", floor.sd);
+        assert_eq!(floor.top(), 46);
+        assert!(NoiseFloor::measure(&[]).is_none());
+    }
+
+    #[test]
+    fn proposes_thresholds_from_weakest_flyover() {
+        let calibration = calibrate(&session(40, &[100, 80, 120]));
+        assert_eq!(calibration.status().flyovers, [100, 80, 120]);
+
+        let profile = calibration.propose().unwrap();
+        assert_eq!(profile.pilot, "ada");
+        assert_eq!(profile.noise_floor, 40.0);
+        assert_eq!(profile.weakest_peak, 80);
+        // 60% and 30% of the way from the floor to 80.
+        assert_eq!(profile.enter_rssi, 64);
+        assert_eq!(profile.exit_rssi, 52);
+    }
+
+    #[test]
+    fn explains_missing_proposal() {
+        let calibration = calibrate(&[40; 50]);
+        assert_eq!(calibration.status().phase, "noise_floor");
+        assert!(calibration.propose().is_err());
+
+        let calibration = calibrate(&session(40, &[]));
+        let status = calibration.status();
+        assert_eq!(status.phase, "flyovers");
+        assert_eq!(status.issue.as_deref(), Some("no flyover recorded yet"));
+
+        // Too close to the floor for two thresholds.
+        let calibration = calibrate(&session(40, &[48]));
+        assert_eq!(calibration.status().flyovers, [48]);
+        assert!(calibration.propose().is_err());
+    }
+
+    #[test]
+    fn keeps_exit_above_noise() {
+        // Floor 40 with a deviation of 6, so noise reaches 76: over 30% of
+        // the way to the flyover.
+        let mut rssi: Vec<u32> = (0..101)
+            .map(|i| match i % 10 {
+                0..=2 => 34,
+                3..=6 => 40,
+                _ => 46,
+            })
+            .collect();
+        rssi.extend([40, 120, 40]);
+        let profile = calibrate(&rssi).propose().unwrap();
+        assert_eq!(profile.enter_rssi, 88);
+        assert_eq!(profile.exit_rssi, 77);
+    }
+
+    #[test]
+    fn caps_noise_duration() {
+        let start = Instant::now();
+        let mut calibration = Calibration::new(0, None, Duration::MAX);
+        let sample = |at: Instant| Sample {
+            rssi: 40,
+            at,
+            lap_id: 0,
+            ms_since_lap: 0,
+            latency: None,
+        };
+        calibration.update(sample(start));
+        calibration.update(sample(start + MAX_NOISE_DURATION));
+        assert_eq!(calibration.status().phase, "flyovers");
+    }
+}
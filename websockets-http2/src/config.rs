@@ -0,0 +1,373 @@
+use crate::filter::{self, FilterConfig};
+use crate::race_format::RaceFormat;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+const DEFAULT_CONFIG_PATH: &str = "rustimer.json";
+
+/// Longest span any timing setting may have, a day, so the deadlines the
+/// worker computes from settings stay representable.
+pub const MAX_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Config {
+    /// Backend used by nodes that do not pick one themselves.
+    #[serde(default)]
+    pub backend: BackendKind,
+    /// Receivers to time with. When empty, serial nodes are discovered on USB.
+    #[serde(default)]
+    pub nodes: Vec<NodeConfig>,
+    #[serde(default)]
+    pub simulator: SimulatorConfig,
+    /// Record every node exchange and race start/stop to this session file.
+    #[serde(default)]
+    pub record: Option<PathBuf>,
+    /// Play a recorded session back instead of talking to the nodes.
+    #[serde(default)]
+    pub replay: Option<ReplayConfig>,
+    /// Serve a stand-in network node, for trying the network backends
+    /// without an ESP32.
+    #[serde(default)]
+    pub standin: Option<StandinConfig>,
+    /// Where laps come from.
+    #[serde(default)]
+    pub lap_source: LapSource,
+    /// RSSI thresholds of host-side lap detection.
+    #[serde(default)]
+    pub pass: PassConfig,
+    /// RSSI filters of nodes that do not set their own.
+    #[serde(default)]
+    pub filters: Vec<FilterConfig>,
+    /// How samples are taken from the nodes.
+    #[serde(default)]
+    pub sampling: Sampling,
+    /// Timing of the race lifecycle.
+    #[serde(default)]
+    pub race: RaceConfig,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sampling {
+    /// One request per sample.
+    #[default]
+    Poll,
+    /// Nodes whose firmware and backend can push samples stream them; the
+    /// others are polled.
+    Stream,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LapSource {
+    /// Laps are detected on the host, from the RSSI of each gate pass.
+    #[default]
+    Host,
+    /// The node's own lap counter and pass times are recorded as laps, each
+    /// matched against the RSSI peak the host saw around it.
+    Node,
+}
+
+/// A pass starts once the RSSI rises above `exit_rssi`, counts once it also
+/// reaches `enter_rssi`, and ends when it falls back to `exit_rssi`. The gap
+/// between the two keeps noise around a threshold from splitting a pass.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct PassConfig {
+    pub enter_rssi: u32,
+    pub exit_rssi: u32,
+    /// Passes closer than this to the last lap are not counted.
+    pub min_lap_s: f64,
+}
+
+impl Default for PassConfig {
+    fn default() -> Self {
+        PassConfig {
+            enter_rssi: 90,
+            exit_rssi: 80,
+            min_lap_s: 5.0,
+        }
+    }
+}
+
+impl PassConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enter_rssi <= self.exit_rssi {
+            return Err(format!(
+                "enter_rssi ({}) must be above exit_rssi ({})",
+                self.enter_rssi, self.exit_rssi
+            ));
+        }
+        if !(0.0..=MAX_SECONDS).contains(&self.min_lap_s) {
+            return Err(format!(
+                "min_lap_s must be from 0 up to {} seconds",
+                MAX_SECONDS
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How races start, and the format of those staged without one.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct RaceConfig {
+    /// From the start command to the race clock starting, ticking every
+    /// second.
+    pub countdown_s: f64,
+    /// Silent wait after the countdown, drawn anew for every race, so pilots
+    /// cannot anticipate the start signal.
+    pub random_delay: Option<DelayRange>,
+    pub format: RaceFormat,
+}
+
+impl RaceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let seconds = |value: f64| (0.0..=MAX_SECONDS).contains(&value);
+        if !seconds(self.countdown_s) {
+            return Err(format!(
+                "countdown_s must be from 0 up to {} seconds",
+                MAX_SECONDS
+            ));
+        }
+        if let Some(delay) = self.random_delay {
+            if !(seconds(delay.min_s) && seconds(delay.max_s) && delay.min_s <= delay.max_s) {
+                return Err(format!(
+                    "random_delay must go from min_s up to max_s, at most {} seconds",
+                    MAX_SECONDS
+                ));
+            }
+        }
+        self.format.validate()
+    }
+}
+
+/// E.g. `{"min_s": 1, "max_s": 5}`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct DelayRange {
+    pub min_s: f64,
+    pub max_s: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    #[default]
+    Serial,
+    Mock,
+    Simulator,
+    /// Network node on TCP; `port` is its `host:port`.
+    Tcp,
+    /// Network node on UDP, pushing its peaks; `port` is its `host:port`.
+    Udp,
+    /// Node on an I2C bus; `port` is the bus, e.g. `/dev/i2c-1`, and
+    /// `address` the node's address on it.
+    I2c,
+}
+
+/// One receiver: a device, optionally narrowed to one slot of a
+/// multi-receiver Arduino. Nodes naming the same device share it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeConfig {
+    #[serde(default)]
+    pub port: String,
+    /// USB serial number of the device. Takes precedence over `port`, so the
+    /// node is found again when plugged into another port.
+    #[serde(default)]
+    pub serial_number: Option<String>,
+    #[serde(default)]
+    pub backend: Option<BackendKind>,
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    #[serde(default)]
+    pub slot: Option<u8>,
+    /// Bus address of an I2C node, 7 bits.
+    #[serde(default)]
+    pub address: Option<u8>,
+    /// Connect and response timeout of network nodes.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// RSSI filters, instead of the configured default ones.
+    #[serde(default)]
+    pub filters: Option<Vec<FilterConfig>>,
+}
+
+/// A recorded session to play back through the worker at `speed`.
+/// Nodes are matched to recorded devices by the order in which their devices
+/// first appear in `nodes`, so replay with the configuration that recorded.
+/// With no nodes configured, each recorded device is replayed as one node.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplayConfig {
+    pub file: PathBuf,
+    /// Playback speed; recorded times are kept as they were.
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+}
+
+/// A simulated device served over TCP on `listen`, e.g. `127.0.0.1:5005`,
+/// for a `tcp` node pointed at the same address.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StandinConfig {
+    pub listen: String,
+    #[serde(default = "default_standin_backend")]
+    pub backend: BackendKind,
+}
+
+fn default_standin_backend() -> BackendKind {
+    BackendKind::Simulator
+}
+
+/// Settings for the RSSI simulator. Each receiver slot of a simulated device
+/// flies the pilot with the same index in `pilots`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SimulatorConfig {
+    /// RSSI with no drone near the gate.
+    pub noise_floor: f64,
+    /// Standard deviation of the RSSI noise.
+    pub noise: f64,
+    /// Time one sample takes, standing in for the serial round trip.
+    pub sample_interval_ms: u64,
+    /// Seed for reproducible runs; random when unset.
+    pub seed: Option<u64>,
+    pub pilots: Vec<PilotProfile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PilotProfile {
+    pub lap_mean_s: f64,
+    pub lap_stddev_s: f64,
+    /// RSSI at the top of a gate pass.
+    pub peak: f64,
+    /// Standard deviation of the bell-shaped pass, in seconds.
+    pub pass_width_s: f64,
+    /// Chance per lap of crashing and losing `crash_delay_s` seconds.
+    pub crash_probability: f64,
+    pub crash_delay_s: (f64, f64),
+    /// Chance per lap of not finishing the race at all.
+    pub dnf_probability: f64,
+    /// Chance per pass of a multipath notch splitting the peak.
+    pub multipath_probability: f64,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        SimulatorConfig {
+            noise_floor: 45.0,
+            noise: 2.0,
+            sample_interval_ms: 20,
+            seed: None,
+            pilots: Vec::new(),
+        }
+    }
+}
+
+impl Default for PilotProfile {
+    fn default() -> Self {
+        PilotProfile {
+            lap_mean_s: 20.0,
+            lap_stddev_s: 1.5,
+            peak: 120.0,
+            pass_width_s: 0.25,
+            crash_probability: 0.03,
+            crash_delay_s: (5.0, 20.0),
+            dnf_probability: 0.01,
+            multipath_probability: 0.2,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn new(port: &str) -> Self {
+        NodeConfig {
+            port: port.to_string(),
+            serial_number: None,
+            backend: None,
+            baud_rate: default_baud_rate(),
+            slot: None,
+            address: None,
+            timeout_ms: default_timeout_ms(),
+            filters: None,
+        }
+    }
+
+    /// Identifies the device, for sharing it between slots. Devices on one
+    /// I2C bus are told apart by their address.
+    pub fn device(&self) -> String {
+        match (&self.serial_number, self.address) {
+            (Some(serial_number), _) => serial_number.clone(),
+            (None, Some(address)) => format!("{}@0x{:02X}", self.port, address),
+            (None, None) => self.port.clone(),
+        }
+    }
+}
+
+pub fn default_baud_rate() -> u32 {
+    115200
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+impl Config {
+    /// Load the configuration from `$RUSTIMER_CONFIG`, or `rustimer.json` in the
+    /// working directory. A missing file yields the defaults.
+    pub fn load() -> Result<Config, Box<dyn std::error::Error>> {
+        let path = std::env::var("RUSTIMER_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        let config: Config = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e.into()),
+        };
+        config.pass.validate().map_err(|e| format!("pass.{}", e))?;
+        config.race.validate().map_err(|e| format!("race.{}", e))?;
+        filter::validate(&config.filters)?;
+        for node in &config.nodes {
+            filter::validate(node.filters.as_deref().unwrap_or_default())?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_min_lap() {
+        let pass = |min_lap_s: f64| PassConfig {
+            min_lap_s,
+            ..PassConfig::default()
+        };
+        assert!(pass(0.0).validate().is_ok());
+        assert!(pass(MAX_SECONDS).validate().is_ok());
+        assert!(pass(-1.0).validate().is_err());
+        assert!(pass(f64::NAN).validate().is_err());
+        assert!(pass(1e20).validate().is_err());
+    }
+
+    #[test]
+    fn bounds_race_timings() {
+        let race = |countdown_s: f64, random_delay: Option<(f64, f64)>| RaceConfig {
+            countdown_s,
+            random_delay: random_delay.map(|(min_s, max_s)| DelayRange { min_s, max_s }),
+            ..RaceConfig::default()
+        };
+        assert!(race(0.0, None).validate().is_ok());
+        assert!(race(MAX_SECONDS, Some((1.0, 5.0))).validate().is_ok());
+
+        assert!(race(-1.0, None).validate().is_err());
+        assert!(race(1e20, None).validate().is_err());
+        assert!(race(3.0, Some((5.0, 1.0))).validate().is_err());
+        assert!(race(3.0, Some((1.0, 1e20))).validate().is_err());
+    }
+}
@@ -1,6 +1,37 @@
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::ConnectOptions;
-use sqlx::SqlitePool;
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::race_format::RaceFormat;
+use crate::structs::calibration::PassProfile;
+use crate::structs::lap::{CreateLap, Lap};
+use crate::structs::race::Race;
+
+/// `CREATE TABLE IF NOT EXISTS` leaves older databases untouched, so columns
+/// added later are patched in here.
+async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let (count,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_one(pool)
+            .await?;
+
+    if count == 0 {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
 
 pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
     let db_options: SqliteConnectOptions = SqliteConnectOptions::new()
@@ -10,7 +41,12 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         .to_owned();
 
     let pool = SqlitePoolOptions::new().connect_with(db_options).await?;
+    create_tables(&pool).await?;
+    Ok(pool)
+}
 
+/// Create the tables, patching those of older databases.
+async fn create_tables(pool: &SqlitePool) -> Result<(), sqlx::Error> {
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS posts (
                 id INTEGER PRIMARY KEY,
@@ -18,17 +54,46 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
                 content NOT NULL
             );",
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS race (
                 id INTEGER PRIMARY KEY,
                 start_time TEXT NOT NULL,
-                end_time TEXT NULL
+                end_time TEXT NULL,
+                format TEXT NULL
             );",
     )
-    .execute(&pool)
+    .execute(pool)
+    .await?;
+    add_column_if_missing(pool, "race", "format", "TEXT NULL").await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS race_transition (
+                id INTEGER PRIMARY KEY,
+                race_id INTEGER NOT NULL,
+                state TEXT NOT NULL,
+                changed_at TEXT NOT NULL,
+                time REAL NULL,
+                FOREIGN KEY (race_id) REFERENCES race (id)
+            );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS false_start (
+                id INTEGER PRIMARY KEY,
+                race_id INTEGER NOT NULL,
+                node_index INTEGER NOT NULL,
+                pilot TEXT NULL,
+                time REAL NOT NULL,
+                rssi INTEGER NOT NULL,
+                FOREIGN KEY (race_id) REFERENCES race (id)
+            );",
+    )
+    .execute(pool)
     .await?;
 
     sqlx::query(
@@ -38,11 +103,243 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
                 time BLOB NOT NULL,
                 duration INTEGER NOT NULL,
                 race_id INTEGER NOT NULL,
+                node_index INTEGER NOT NULL DEFAULT 0,
                 FOREIGN KEY (race_id) REFERENCES race (id)
             );",
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
+    add_column_if_missing(pool, "node", "node_index", "INTEGER NOT NULL DEFAULT 0").await?;
 
-    Ok(pool)
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS node_frequency (
+                node_index INTEGER PRIMARY KEY,
+                frequency INTEGER NOT NULL
+            );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS node_filters (
+                node_index INTEGER PRIMARY KEY,
+                filters TEXT NOT NULL
+            );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS node_event (
+                id INTEGER PRIMARY KEY,
+                race_id INTEGER NOT NULL,
+                node_index INTEGER NOT NULL,
+                event TEXT NOT NULL,
+                time REAL NOT NULL,
+                detail TEXT NULL,
+                FOREIGN KEY (race_id) REFERENCES race (id)
+            );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS lap (
+                id INTEGER PRIMARY KEY,
+                race_id INTEGER NOT NULL,
+                node_index INTEGER NOT NULL,
+                lap_number INTEGER NOT NULL,
+                time REAL NOT NULL,
+                host_time REAL NULL,
+                uncertainty REAL NULL,
+                FOREIGN KEY (race_id) REFERENCES race (id)
+            );",
+    )
+    .execute(pool)
+    .await?;
+    add_column_if_missing(pool, "lap", "uncertainty", "REAL NULL").await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS lap_edit (
+                id INTEGER PRIMARY KEY,
+                race_id INTEGER NOT NULL,
+                lap_id INTEGER NOT NULL,
+                node_index INTEGER NOT NULL,
+                action TEXT NOT NULL,
+                time REAL NULL,
+                previous_time REAL NULL,
+                by TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                edited_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                FOREIGN KEY (race_id) REFERENCES race (id)
+            );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS pass_profile (
+                node_index INTEGER NOT NULL,
+                pilot TEXT NOT NULL DEFAULT '',
+                noise_floor REAL NOT NULL,
+                noise_sd REAL NOT NULL,
+                weakest_peak INTEGER NOT NULL,
+                enter_rssi INTEGER NOT NULL,
+                exit_rssi INTEGER NOT NULL,
+                PRIMARY KEY (node_index, pilot)
+            );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS node_pilot (
+                node_index INTEGER PRIMARY KEY,
+                pilot TEXT NOT NULL
+            );",
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Empty database in memory, for tests. One connection, since each would
+/// get a database of its own.
+#[cfg(test)]
+pub async fn memory_db() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    create_tables(&pool).await.unwrap();
+    pool
+}
+
+/// Insert a new race row, as the worker stages it.
+pub async fn create_race(pool: &SqlitePool, format: RaceFormat) -> Result<Race, sqlx::Error> {
+    sqlx::query_as::<_, Race>(
+        "INSERT INTO race (start_time, format) VALUES (?, ?) RETURNING id, start_time, end_time, format",
+    )
+    .bind(chrono::Utc::now().to_rfc3339())
+    .bind(serde_json::to_string(&format).unwrap())
+    .fetch_one(pool)
+    .await
+}
+
+/// Format a stored race ran with; `None` when there is no such race.
+pub async fn race_format(
+    pool: &SqlitePool,
+    race_id: i32,
+) -> Result<Option<RaceFormat>, sqlx::Error> {
+    let format: Option<Option<String>> = sqlx::query_scalar("SELECT format FROM race WHERE id = ?")
+        .bind(race_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(format.map(|format| {
+        format
+            .and_then(|format| serde_json::from_str(&format).ok())
+            .unwrap_or_default()
+    }))
+}
+
+/// Profile to detect `pilot`'s passes on a node with: their own, else the
+/// node's.
+pub async fn pass_profile(
+    pool: &SqlitePool,
+    node_index: i32,
+    pilot: Option<&str>,
+) -> Result<Option<PassProfile>, sqlx::Error> {
+    sqlx::query_as::<_, PassProfile>(
+        "SELECT node_index, pilot, noise_floor, noise_sd, weakest_peak, enter_rssi, exit_rssi
+            FROM pass_profile WHERE node_index = ? AND pilot IN (?, '')
+            ORDER BY pilot = '' LIMIT 1",
+    )
+    .bind(node_index)
+    .bind(pilot.unwrap_or_default())
+    .fetch_optional(pool)
+    .await
+}
+
+/// Pilot last set on a node, if any.
+pub async fn node_pilot(pool: &SqlitePool, node_index: i32) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT pilot FROM node_pilot WHERE node_index = ?")
+        .bind(node_index)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Store a detected lap and number the node's laps again, in one
+/// transaction, so laps stored at once or out of crossing order are numbered
+/// like any correction.
+pub async fn insert_lap(pool: &SqlitePool, lap: &CreateLap) -> Result<Lap, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let id: i32 = sqlx::query_scalar(
+        "INSERT INTO lap (race_id, node_index, lap_number, time, host_time, uncertainty)
+            VALUES (?, ?, 0, ?, ?, ?) RETURNING id",
+    )
+    .bind(lap.race_id)
+    .bind(lap.node_index)
+    .bind(lap.time)
+    .bind(lap.host_time)
+    .bind(lap.uncertainty)
+    .fetch_one(&mut *transaction)
+    .await?;
+    renumber_laps(&mut transaction, lap.race_id, lap.node_index).await?;
+    let lap = sqlx::query_as::<_, Lap>(
+        "SELECT id, race_id, node_index, lap_number, time, host_time, uncertainty FROM lap WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_one(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(lap)
+}
+
+/// Number a node's laps of a race in crossing order again, after laps were
+/// added, removed or moved.
+pub async fn renumber_laps(
+    connection: &mut SqliteConnection,
+    race_id: i32,
+    node_index: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE lap SET lap_number = (
+                SELECT COUNT(*) FROM lap AS earlier
+                    WHERE earlier.race_id = lap.race_id
+                        AND earlier.node_index = lap.node_index
+                        AND (earlier.time < lap.time OR (earlier.time = lap.time AND earlier.id <= lap.id))
+            )
+            WHERE race_id = ? AND node_index = ?",
+    )
+    .bind(race_id)
+    .bind(node_index)
+    .execute(connection)
+    .await?;
+    Ok(())
+}
+
+/// Corrections made by hand to a race's laps, not counting accepted
+/// recalculations.
+pub async fn hand_edits(
+    connection: &mut SqliteConnection,
+    race_id: i32,
+) -> Result<usize, sqlx::Error> {
+    let count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM lap_edit WHERE race_id = ? AND action != 'recalculate'",
+    )
+    .bind(race_id)
+    .fetch_one(connection)
+    .await?;
+    Ok(count as usize)
+}
+
+pub async fn race_laps(pool: &SqlitePool, race_id: i32) -> Result<Vec<Lap>, sqlx::Error> {
+    sqlx::query_as::<_, Lap>(
+        "SELECT id, race_id, node_index, lap_number, time, host_time, uncertainty FROM lap WHERE race_id = ? ORDER BY time",
+    )
+    .bind(race_id)
+    .fetch_all(pool)
+    .await
 }
@@ -1,10 +1,72 @@
-use std::time::Instant;
+use crate::filter::FilterConfig;
+use crate::race_format::RaceFormat;
+use crate::structs::calibration::{CalibrationStatus, PassProfile};
+use crate::structs::node_status::NodeStatus;
+use crate::structs::race::RaceStatus;
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
 
 #[derive(Debug)]
 pub enum Command {
     Increment,
-    GetCount { respond_to: oneshot::Sender<u32> },
-    StartRace { time: Instant, race_id: i32 },
-    StopRace,
+    GetCount {
+        respond_to: oneshot::Sender<u32>,
+    },
+    /// Create a race and stage it, with the configured format unless
+    /// `format` is set.
+    StageRace {
+        format: Option<RaceFormat>,
+        respond_to: oneshot::Sender<Result<RaceStatus, String>>,
+    },
+    /// Start the staged race's countdown, the configured one unless
+    /// `countdown` is set.
+    StartRace {
+        time: Instant,
+        countdown: Option<Duration>,
+        respond_to: oneshot::Sender<Result<RaceStatus, String>>,
+    },
+    StopRace {
+        time: Instant,
+        respond_to: oneshot::Sender<Result<RaceStatus, String>>,
+    },
+    AbortRace {
+        time: Instant,
+        respond_to: oneshot::Sender<Result<RaceStatus, String>>,
+    },
+    GetRace {
+        respond_to: oneshot::Sender<RaceStatus>,
+    },
+    GetNodes {
+        respond_to: oneshot::Sender<Vec<NodeStatus>>,
+    },
+    SetFrequency {
+        node_index: usize,
+        frequency: u16,
+        respond_to: oneshot::Sender<Result<u16, String>>,
+    },
+    StartCalibration {
+        node_index: usize,
+        pilot: Option<String>,
+        noise_duration: Duration,
+        respond_to: oneshot::Sender<Result<(), String>>,
+    },
+    GetCalibration {
+        node_index: usize,
+        respond_to: oneshot::Sender<Option<CalibrationStatus>>,
+    },
+    FinishCalibration {
+        node_index: usize,
+        respond_to: oneshot::Sender<Result<PassProfile, String>>,
+    },
+    SetFilters {
+        node_index: usize,
+        filters: Vec<FilterConfig>,
+        respond_to: oneshot::Sender<Result<(), String>>,
+    },
+    SetPilot {
+        node_index: usize,
+        pilot: Option<String>,
+        profile: Option<PassProfile>,
+        respond_to: oneshot::Sender<Result<(), String>>,
+    },
 }
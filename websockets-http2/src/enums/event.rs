@@ -0,0 +1,42 @@
+use crate::structs::lap::{Lap, LapCorrection, LapRecalculation};
+use crate::structs::race::{CountdownSignal, FalseStart, RaceStatus};
+use crate::structs::rssi::RssiSample;
+use serde::Serialize;
+
+/// Messages the worker broadcasts to websocket clients, as JSON tagged with
+/// `type`. `time` is in seconds since the race start, when a race is running.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    NodeDisconnected {
+        node_index: usize,
+        race_id: Option<i32>,
+        time: Option<f64>,
+        error: String,
+    },
+    NodeReconnected {
+        node_index: usize,
+        race_id: Option<i32>,
+        time: Option<f64>,
+    },
+    /// The current race changed state.
+    Race(RaceStatus),
+    /// Cue of the race's start sequence.
+    Countdown {
+        race_id: i32,
+        signal: CountdownSignal,
+        remaining: Option<u32>,
+    },
+    FalseStart(FalseStart),
+    Lap(Lap),
+    /// The race director corrected a lap; `laps` are all of the race's laps
+    /// after it, renumbered.
+    LapsCorrected(LapCorrection),
+    /// The race director accepted a recalculation of a race's laps; `laps`
+    /// are all of them after it, renumbered.
+    LapsRecalculated(LapRecalculation),
+    /// Samples of the nodes read together, sent while anyone listens.
+    Rssi {
+        samples: Vec<RssiSample>,
+    },
+}
@@ -0,0 +1,311 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::time::Instant;
+
+/// Longest median window accepted.
+const MAX_MEDIAN_WINDOW: usize = 64;
+
+/// One stage of a node's RSSI filter chain, as configured or set through the
+/// API, e.g. `{"kind": "median", "window": 5}`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FilterConfig {
+    /// Median of the last `window` samples; removes spikes without smearing
+    /// the edges of a pass.
+    Median { window: usize },
+    /// Exponential moving average, `alpha` of each new sample going in.
+    Ema { alpha: f64 },
+    /// First-order low-pass on the sample times, so irregular sampling does
+    /// not change its response.
+    LowPass { cutoff_hz: f64 },
+    /// Kalman filter of a level drifting by `process_noise` RSSI² a second,
+    /// measured with `measurement_noise` RSSI² of noise.
+    Kalman {
+        process_noise: f64,
+        measurement_noise: f64,
+    },
+}
+
+impl FilterConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = match *self {
+            FilterConfig::Median { window } => (1..=MAX_MEDIAN_WINDOW).contains(&window),
+            FilterConfig::Ema { alpha } => alpha > 0.0 && alpha <= 1.0,
+            FilterConfig::LowPass { cutoff_hz } => cutoff_hz > 0.0 && cutoff_hz.is_finite(),
+            FilterConfig::Kalman {
+                process_noise,
+                measurement_noise,
+            } => {
+                process_noise > 0.0
+                    && measurement_noise > 0.0
+                    && (process_noise + measurement_noise).is_finite()
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("invalid filter {:?}", self))
+        }
+    }
+
+    fn build(&self) -> Box<dyn Filter> {
+        match *self {
+            FilterConfig::Median { window } => Box::new(Median {
+                window,
+                recent: VecDeque::with_capacity(window),
+            }),
+            FilterConfig::Ema { alpha } => Box::new(Ema { alpha, value: None }),
+            FilterConfig::LowPass { cutoff_hz } => Box::new(LowPass {
+                time_constant: 1.0 / (2.0 * PI * cutoff_hz),
+                last: None,
+            }),
+            FilterConfig::Kalman {
+                process_noise,
+                measurement_noise,
+            } => Box::new(Kalman {
+                process_noise,
+                measurement_noise,
+                estimate: None,
+            }),
+        }
+    }
+}
+
+/// Check a whole chain before using it.
+pub fn validate(filters: &[FilterConfig]) -> Result<(), String> {
+    filters.iter().try_for_each(FilterConfig::validate)
+}
+
+/// A stage fed one sample at a time. The first sample passes unchanged.
+trait Filter: Send {
+    fn apply(&mut self, value: f64, at: Instant) -> f64;
+}
+
+struct Median {
+    window: usize,
+    recent: VecDeque<f64>,
+}
+
+impl Filter for Median {
+    fn apply(&mut self, value: f64, _at: Instant) -> f64 {
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(value);
+        median(self.recent.iter().copied().collect()).unwrap_or(value)
+    }
+}
+
+/// Middle value, or the mean of the two middle ones; `None` for no values.
+pub fn median(mut values: Vec<f64>) -> Option<f64> {
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    match values.len() {
+        0 => None,
+        n if n.is_multiple_of(2) => Some((values[middle - 1] + values[middle]) / 2.0),
+        _ => Some(values[middle]),
+    }
+}
+
+struct Ema {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Filter for Ema {
+    fn apply(&mut self, value: f64, _at: Instant) -> f64 {
+        let output = match self.value {
+            Some(last) => last + self.alpha * (value - last),
+            None => value,
+        };
+        self.value = Some(output);
+        output
+    }
+}
+
+struct LowPass {
+    time_constant: f64,
+    last: Option<(f64, Instant)>,
+}
+
+impl Filter for LowPass {
+    fn apply(&mut self, value: f64, at: Instant) -> f64 {
+        let output = match self.last {
+            Some((last, last_at)) => {
+                let dt = at.saturating_duration_since(last_at).as_secs_f64();
+                last + dt / (self.time_constant + dt) * (value - last)
+            }
+            None => value,
+        };
+        self.last = Some((output, at));
+        output
+    }
+}
+
+struct Kalman {
+    process_noise: f64,
+    measurement_noise: f64,
+    /// Level, its variance and when it was estimated.
+    estimate: Option<(f64, f64, Instant)>,
+}
+
+impl Filter for Kalman {
+    fn apply(&mut self, value: f64, at: Instant) -> f64 {
+        let (level, variance) = match self.estimate {
+            Some((level, variance, last_at)) => {
+                let dt = at.saturating_duration_since(last_at).as_secs_f64();
+                let predicted = variance + self.process_noise * dt;
+                let gain = predicted / (predicted + self.measurement_noise);
+                (level + gain * (value - level), (1.0 - gain) * predicted)
+            }
+            None => (value, self.measurement_noise),
+        };
+        self.estimate = Some((level, variance, at));
+        level
+    }
+}
+
+/// A node's filters, applied in order to every sample before detection.
+pub struct FilterChain {
+    config: Vec<FilterConfig>,
+    filters: Vec<Box<dyn Filter>>,
+}
+
+impl FilterChain {
+    pub fn new(config: Vec<FilterConfig>) -> Self {
+        let filters = config.iter().map(FilterConfig::build).collect();
+        FilterChain { config, filters }
+    }
+
+    pub fn config(&self) -> &[FilterConfig] {
+        &self.config
+    }
+
+    /// Start over, e.g. when samples resume after a pause.
+    pub fn reset(&mut self) {
+        *self = FilterChain::new(std::mem::take(&mut self.config));
+    }
+
+    pub fn apply(&mut self, rssi: u32, at: Instant) -> f64 {
+        self.filters
+            .iter_mut()
+            .fold(rssi as f64, |value, filter| filter.apply(value, at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Outputs of `config` fed `values` at one sample every `step_ms`.
+    fn run(config: Vec<FilterConfig>, values: &[u32], step_ms: u64) -> Vec<f64> {
+        let start = Instant::now();
+        let mut chain = FilterChain::new(config);
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| {
+                chain.apply(value, start + Duration::from_millis(i as u64 * step_ms))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn median_of_values() {
+        assert_eq!(median(vec![]), None);
+        assert_eq!(median(vec![3.0]), Some(3.0));
+        assert_eq!(median(vec![5.0, 1.0, 3.0]), Some(3.0));
+        assert_eq!(median(vec![4.0, 1.0, 3.0, 2.0]), Some(2.5));
+    }
+
+    #[test]
+    fn median_removes_spikes() {
+        let output = run(
+            vec![FilterConfig::Median { window: 3 }],
+            &[50, 50, 120, 50, 50],
+            10,
+        );
+        assert_eq!(output, [50.0, 50.0, 50.0, 50.0, 50.0]);
+    }
+
+    #[test]
+    fn ema_moves_by_alpha() {
+        let output = run(vec![FilterConfig::Ema { alpha: 0.5 }], &[40, 80, 80], 10);
+        assert_eq!(output, [40.0, 60.0, 70.0]);
+    }
+
+    #[test]
+    fn low_pass_follows_sample_times() {
+        // At dt equal to the time constant, half of each step goes through.
+        let cutoff_hz = 1.0 / (2.0 * PI * 0.1);
+        let output = run(vec![FilterConfig::LowPass { cutoff_hz }], &[0, 100], 100);
+        assert!((output[1] - 50.0).abs() < 1e-9, "{:?}", output);
+
+        // Twice the time step, twice the gain over the constant.
+        let output = run(vec![FilterConfig::LowPass { cutoff_hz }], &[0, 100], 200);
+        assert!((output[1] - 200.0 / 3.0).abs() < 1e-9, "{:?}", output);
+    }
+
+    #[test]
+    fn kalman_converges_on_a_level() {
+        let config = FilterConfig::Kalman {
+            process_noise: 1.0,
+            measurement_noise: 100.0,
+        };
+        let mut values = vec![100; 201];
+        values[0] = 0;
+        let output = run(vec![config], &values, 10);
+        assert_eq!(output[0], 0.0);
+        assert!(output[1] > 0.0 && output[1] < 100.0, "{:?}", output[1]);
+        assert!((output[200] - 100.0).abs() < 1.0, "{:?}", output[200]);
+    }
+
+    #[test]
+    fn applies_stages_in_order() {
+        let chain = vec![
+            FilterConfig::Median { window: 3 },
+            FilterConfig::Ema { alpha: 0.5 },
+        ];
+        // The spike never reaches the average.
+        let output = run(chain, &[60, 60, 200, 60], 10);
+        assert_eq!(output, [60.0, 60.0, 60.0, 60.0]);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let start = Instant::now();
+        let mut chain = FilterChain::new(vec![FilterConfig::Ema { alpha: 0.5 }]);
+        chain.apply(0, start);
+        chain.reset();
+        assert_eq!(chain.apply(100, start), 100.0);
+        assert_eq!(chain.config(), [FilterConfig::Ema { alpha: 0.5 }]);
+    }
+
+    #[test]
+    fn validates_parameters() {
+        assert!(validate(&[
+            FilterConfig::Median { window: 5 },
+            FilterConfig::Ema { alpha: 1.0 },
+            FilterConfig::LowPass { cutoff_hz: 20.0 },
+        ])
+        .is_ok());
+        for invalid in [
+            FilterConfig::Median { window: 0 },
+            FilterConfig::Median {
+                window: MAX_MEDIAN_WINDOW + 1,
+            },
+            FilterConfig::Ema { alpha: 0.0 },
+            FilterConfig::LowPass {
+                cutoff_hz: f64::INFINITY,
+            },
+            FilterConfig::Kalman {
+                process_noise: 1.0,
+                measurement_noise: 0.0,
+            },
+        ] {
+            assert!(validate(&[invalid]).is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::node_manager::Sample;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How far from a node lap the host looks for the RSSI peak of the same pass.
+/// A lap is reported once the samples have moved this far past it.
+const RECONCILE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Lap reported by a node, with the pass as seen by host-side detection.
+#[derive(Debug, Clone, Copy)]
+pub struct NodeLap {
+    /// Laps since the race start, counting from 1.
+    pub number: u32,
+    /// Time of the pass on the node clock.
+    pub at: Instant,
+    /// Time of the highest RSSI the host saw around the pass, if it saw any.
+    pub host_at: Option<Instant>,
+}
+
+/// Follows a node's own lap counter. The counter is a `u8` that keeps running
+/// across races, so the first sample only sets the baseline and every later
+/// change is counted modulo 256. The pass time is the sample time minus
+/// `ms_val`, the node's time since the lap; `ms_val` is 16 bits and only read
+/// from the first sample showing the new lap, long before it can overflow.
+pub struct LapTracker {
+    last_lap_id: Option<u8>,
+    laps: u32,
+    recent: VecDeque<Sample>,
+    pending: VecDeque<NodeLap>,
+}
+
+impl LapTracker {
+    pub fn new() -> Self {
+        LapTracker {
+            last_lap_id: None,
+            laps: 0,
+            recent: VecDeque::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Take the next sample's counter as the baseline again, e.g. after the
+    /// device reconnected and may have restarted it. Laps counted so far are
+    /// kept.
+    pub fn rebase(&mut self) {
+        self.last_lap_id = None;
+    }
+
+    /// Feeds one sample and returns the laps whose reconciliation window has
+    /// closed.
+    pub fn update(&mut self, sample: Sample) -> Vec<NodeLap> {
+        if let Some(last) = self.last_lap_id.replace(sample.lap_id) {
+            let new_laps = sample.lap_id.wrapping_sub(last);
+            if new_laps > 0 {
+                if new_laps > 1 {
+                    println!(
+                        "Lap counter skipped {} laps; only the last one has a time",
+                        new_laps - 1
+                    );
+                }
+                self.laps += new_laps as u32;
+                let at = sample
+                    .at
+                    .checked_sub(Duration::from_millis(sample.ms_since_lap as u64))
+                    .unwrap_or(sample.at);
+                self.pending.push_back(NodeLap {
+                    number: self.laps,
+                    at,
+                    host_at: None,
+                });
+            }
+        }
+
+        self.recent.push_back(sample);
+        let keep_from = self
+            .pending
+            .front()
+            .map_or(sample.at, |lap| lap.at.min(sample.at))
+            .checked_sub(RECONCILE_WINDOW);
+        while let (Some(oldest), Some(keep_from)) = (self.recent.front(), keep_from) {
+            if oldest.at >= keep_from {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        let mut done = Vec::new();
+        while let Some(lap) = self.pending.front() {
+            if sample.at < lap.at + RECONCILE_WINDOW {
+                break;
+            }
+            let mut lap = self.pending.pop_front().unwrap();
+            lap.host_at = self.host_peak(lap.at);
+            done.push(lap);
+        }
+        done
+    }
+
+    /// Report the laps still waiting for their reconciliation window, with
+    /// the samples seen so far, e.g. once the race stopped and no more
+    /// samples are coming.
+    pub fn flush(&mut self) -> Vec<NodeLap> {
+        let mut laps: Vec<NodeLap> = self.pending.drain(..).collect();
+        for lap in &mut laps {
+            lap.host_at = self.host_peak(lap.at);
+        }
+        laps
+    }
+
+    /// Time of the strongest sample within the window around `at`.
+    fn host_peak(&self, at: Instant) -> Option<Instant> {
+        self.recent
+            .iter()
+            .filter(|sample| {
+                let distance = sample
+                    .at
+                    .saturating_duration_since(at)
+                    .max(at.saturating_duration_since(sample.at));
+                distance <= RECONCILE_WINDOW
+            })
+            .max_by_key(|sample| sample.rssi)
+            .map(|sample| sample.at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(start: Instant, at_ms: u64, rssi: u32, lap_id: u8, ms_since_lap: u16) -> Sample {
+        Sample {
+            rssi,
+            at: start + Duration::from_millis(at_ms),
+            lap_id,
+            ms_since_lap,
+            latency: None,
+        }
+    }
+
+    #[test]
+    fn counts_from_the_first_counter_seen() {
+        let start = Instant::now();
+        let mut tracker = LapTracker::new();
+        assert!(tracker.update(sample(start, 0, 50, 254, 0)).is_empty());
+        assert!(tracker.update(sample(start, 100, 50, 255, 40)).is_empty());
+        // The counter wraps; the lap is reported a window after its pass.
+        assert!(tracker.update(sample(start, 1000, 50, 0, 900)).is_empty());
+        let laps = tracker.update(sample(start, 1080, 50, 0, 980));
+        assert_eq!(laps.len(), 1);
+        assert_eq!(laps[0].number, 1);
+        assert_eq!(laps[0].at, start + Duration::from_millis(60));
+
+        let laps = tracker.update(sample(start, 2100, 50, 0, 2000));
+        assert_eq!(laps.len(), 1);
+        assert_eq!(laps[0].number, 2);
+        assert_eq!(laps[0].at, start + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn matches_host_peak_around_the_lap() {
+        let start = Instant::now();
+        let mut tracker = LapTracker::new();
+        tracker.update(sample(start, 0, 50, 7, 0));
+        tracker.update(sample(start, 400, 120, 7, 0));
+        tracker.update(sample(start, 500, 90, 8, 50));
+        let laps = tracker.update(sample(start, 1500, 50, 8, 1050));
+        assert_eq!(laps[0].host_at, Some(start + Duration::from_millis(400)));
+    }
+
+    #[test]
+    fn flushes_laps_when_samples_stop() {
+        let start = Instant::now();
+        let mut tracker = LapTracker::new();
+        tracker.update(sample(start, 0, 50, 3, 0));
+        tracker.update(sample(start, 100, 110, 3, 0));
+        // The deciding lap, in the last second before the race stops.
+        assert!(tracker.update(sample(start, 200, 60, 4, 80)).is_empty());
+
+        let laps = tracker.flush();
+        assert_eq!(laps.len(), 1);
+        assert_eq!(laps[0].at, start + Duration::from_millis(120));
+        assert_eq!(laps[0].host_at, Some(start + Duration::from_millis(100)));
+        assert!(tracker.flush().is_empty());
+    }
+
+    #[test]
+    fn rebase_keeps_the_count() {
+        let start = Instant::now();
+        let mut tracker = LapTracker::new();
+        tracker.update(sample(start, 0, 50, 10, 0));
+        tracker.update(sample(start, 100, 50, 11, 0));
+        tracker.rebase();
+        // A restarted node counting from 0 again adds no lap.
+        assert!(tracker.update(sample(start, 200, 50, 0, 0)).is_empty());
+        tracker.update(sample(start, 300, 50, 1, 0));
+        let laps = tracker.flush();
+        assert_eq!(
+            laps.iter().map(|lap| lap.number).collect::<Vec<_>>(),
+            [1, 2]
+        );
+    }
+}
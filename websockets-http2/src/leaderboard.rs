@@ -0,0 +1,201 @@
+use crate::race_format::RaceFormat;
+use crate::structs::lap::Lap;
+use crate::structs::leaderboard::Standing;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// Rank the nodes of a race by its laps, as its format says. A lap lasts
+/// from the previous crossing, or from the race start for the first one.
+pub fn standings(laps: &[Lap], format: RaceFormat) -> Vec<Standing> {
+    let mut by_node: BTreeMap<i32, Vec<f64>> = BTreeMap::new();
+    for lap in laps {
+        by_node.entry(lap.node_index).or_default().push(lap.time);
+    }
+    for times in by_node.values_mut() {
+        times.sort_by(f64::total_cmp);
+    }
+
+    let decided = decided_at(&by_node, format);
+    let grace = format.grace().as_secs_f64();
+    let mut standings: Vec<Standing> = by_node
+        .into_iter()
+        .map(|(node_index, mut times)| {
+            // Laps up to the one the pilot was on when the race was decided,
+            // if they finished it in time.
+            if let Some(decided) = decided {
+                let finishing = times.iter().position(|&time| time >= decided);
+                if let Some(finishing) = finishing {
+                    let in_time = times[finishing] <= decided + grace;
+                    times.truncate(finishing + usize::from(in_time));
+                }
+            }
+            let durations: Vec<f64> = times
+                .iter()
+                .scan(0.0, |previous, &time| {
+                    let duration = time - *previous;
+                    *previous = time;
+                    Some(duration)
+                })
+                .collect();
+            let consecutive = match format {
+                RaceFormat::BestConsecutive { laps, .. } => durations
+                    .windows(laps as usize)
+                    .map(|window| window.iter().sum::<f64>())
+                    .reduce(f64::min),
+                _ => None,
+            };
+            Standing {
+                position: 0,
+                node_index,
+                laps: times.len(),
+                time: times.last().copied().unwrap_or_default(),
+                best_lap: durations.iter().copied().reduce(f64::min),
+                last_lap: durations.last().copied(),
+                consecutive,
+            }
+        })
+        .collect();
+
+    let most_laps =
+        |a: &Standing, b: &Standing| b.laps.cmp(&a.laps).then(a.time.total_cmp(&b.time));
+    // Pilots with a time ahead of those without.
+    let fastest = |a: Option<f64>, b: Option<f64>| match (a, b) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    };
+    standings.sort_by(|a, b| match format {
+        RaceFormat::BestLap { .. } => fastest(a.best_lap, b.best_lap).then(most_laps(a, b)),
+        RaceFormat::BestConsecutive { .. } => {
+            fastest(a.consecutive, b.consecutive).then(most_laps(a, b))
+        }
+        _ => most_laps(a, b),
+    });
+    for (position, standing) in standings.iter_mut().enumerate() {
+        standing.position = position + 1;
+    }
+    standings
+}
+
+/// Race time the race was decided at: the end of its duration, or the first
+/// pilot completing the target laps.
+fn decided_at(by_node: &BTreeMap<i32, Vec<f64>>, format: RaceFormat) -> Option<f64> {
+    if let Some(duration) = format.duration() {
+        return Some(duration.as_secs_f64());
+    }
+    let target = format.target_laps()? as usize;
+    by_node
+        .values()
+        .filter_map(|times| times.get(target - 1).copied())
+        .reduce(f64::min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Laps of each node, crossed at the given race times.
+    fn laps(nodes: &[(i32, &[f64])]) -> Vec<Lap> {
+        nodes
+            .iter()
+            .flat_map(|&(node_index, times)| {
+                times.iter().enumerate().map(move |(i, &time)| Lap {
+                    id: 0,
+                    race_id: 1,
+                    node_index,
+                    lap_number: i as i32 + 1,
+                    time,
+                    host_time: None,
+                    uncertainty: None,
+                })
+            })
+            .collect()
+    }
+
+    /// Nodes in order, with their laps counted.
+    fn ranking(standings: &[Standing]) -> Vec<(i32, usize)> {
+        standings
+            .iter()
+            .map(|standing| (standing.node_index, standing.laps))
+            .collect()
+    }
+
+    #[test]
+    fn open_ranks_most_laps_then_earliest() {
+        let laps = laps(&[
+            (1, &[10.0, 20.0]),
+            (2, &[9.0, 19.0, 29.0]),
+            (3, &[11.0, 18.0]),
+        ]);
+        let standings = standings(&laps, RaceFormat::Open);
+        assert_eq!(ranking(&standings), [(2, 3), (3, 2), (1, 2)]);
+        assert_eq!(
+            standings.iter().map(|s| s.position).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+
+        let third = &standings[2];
+        assert_eq!(third.time, 20.0);
+        assert_eq!(third.best_lap, Some(10.0));
+        assert_eq!(third.last_lap, Some(10.0));
+        assert_eq!(third.consecutive, None);
+    }
+
+    #[test]
+    fn first_to_laps_counts_the_lap_finished_in_grace() {
+        let laps = laps(&[
+            // Decides the race at 30; its lap after does not count.
+            (1, &[10.0, 20.0, 30.0, 40.0]),
+            // On its last lap, finished within the grace period.
+            (2, &[10.0, 21.0, 34.0]),
+            // Finishing its lap too late.
+            (3, &[12.0, 25.0, 40.0]),
+        ]);
+        let format = RaceFormat::FirstToLaps {
+            laps: 3,
+            grace_s: 5.0,
+        };
+        let standings = standings(&laps, format);
+        assert_eq!(ranking(&standings), [(1, 3), (2, 3), (3, 2)]);
+        assert_eq!(standings[0].time, 30.0);
+        assert_eq!(standings[2].time, 25.0);
+    }
+
+    #[test]
+    fn timed_stops_counting_after_grace() {
+        let laps = laps(&[(1, &[20.0, 40.0, 61.0, 70.0]), (2, &[25.0, 50.0, 66.0])]);
+        let format = RaceFormat::Timed {
+            duration_s: 60.0,
+            grace_s: 5.0,
+        };
+        assert_eq!(ranking(&standings(&laps, format)), [(1, 3), (2, 2)]);
+    }
+
+    #[test]
+    fn best_lap_ranks_fastest_lap() {
+        let laps = laps(&[(1, &[10.0, 30.0, 50.0]), (2, &[8.0, 40.0])]);
+        let format = RaceFormat::BestLap {
+            duration_s: 100.0,
+            grace_s: 0.0,
+        };
+        let standings = standings(&laps, format);
+        assert_eq!(ranking(&standings), [(2, 2), (1, 3)]);
+        assert_eq!(standings[0].best_lap, Some(8.0));
+    }
+
+    #[test]
+    fn best_consecutive_ranks_pilots_without_a_run_last() {
+        let laps = laps(&[(1, &[3.0]), (2, &[5.0, 25.0]), (3, &[10.0, 20.0, 30.0])]);
+        let format = RaceFormat::BestConsecutive {
+            laps: 2,
+            duration_s: 100.0,
+            grace_s: 0.0,
+        };
+        let standings = standings(&laps, format);
+        assert_eq!(ranking(&standings), [(3, 3), (2, 2), (1, 1)]);
+        assert_eq!(standings[0].consecutive, Some(20.0));
+        assert_eq!(standings[1].consecutive, Some(25.0));
+        assert_eq!(standings[2].consecutive, None);
+    }
+}
@@ -0,0 +1,599 @@
+use crate::config::RaceConfig;
+use crate::node_manager::RaceWindow;
+use crate::race_format::RaceFormat;
+use crate::structs::lap::CreateLap;
+use crate::structs::race::{FalseStart, RaceState, RaceStatus};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Whether a race may go from `from` to `to`. A new race can be staged once
+/// the last one is over; any race not over yet can be aborted.
+fn allowed(from: RaceState, to: RaceState) -> bool {
+    use RaceState::*;
+    matches!(
+        (from, to),
+        (Idle | Finished | Aborted, Staging)
+            | (Staging, Countdown)
+            | (Countdown, Running)
+            | (Running, Overtime)
+            | (Running | Overtime, Finished)
+            | (Staging | Countdown | Running | Overtime, Aborted)
+    )
+}
+
+/// Wall-clock time of an instant, for storing and showing. Instants map
+/// through one reading of both clocks, so their order and spacing are kept.
+pub fn wall_clock(at: Instant) -> DateTime<Utc> {
+    static EPOCH: OnceLock<(Instant, DateTime<Utc>)> = OnceLock::new();
+    let &(epoch, wall) = EPOCH.get_or_init(|| (Instant::now(), Utc::now()));
+    let offset = |duration: Duration| chrono::Duration::from_std(duration).unwrap_or_default();
+    match at.checked_duration_since(epoch) {
+        Some(since) => wall + offset(since),
+        None => wall - offset(epoch - at),
+    }
+}
+
+/// `by` after `at`, unless that is past what an instant can hold.
+fn later(at: Instant, by: Duration) -> Result<Instant, String> {
+    at.checked_add(by)
+        .ok_or_else(|| format!("{:?} from now is out of range", by))
+}
+
+/// Something the race has scheduled, once it is due.
+pub enum Due {
+    /// A second of the countdown, with the seconds left.
+    Tick(u32),
+    Change(Instant, RaceState),
+}
+
+/// The current race and where it is in its lifecycle. The countdown ends by
+/// itself once due, and the race format decides when the race goes into
+/// overtime and finishes; every other change is commanded, and refused unless
+/// the lifecycle allows it.
+pub struct RaceLifecycle {
+    config: RaceConfig,
+    state: RaceState,
+    race_id: Option<i32>,
+    format: RaceFormat,
+    /// Laps of each node seen during the race.
+    laps: HashMap<i32, u32>,
+    /// Nodes yet to finish the lap they were on when the race was decided.
+    finishing: BTreeSet<i32>,
+    changed_at: Option<Instant>,
+    /// When the race moves on by itself, and to what.
+    next: Option<(Instant, RaceState)>,
+    /// Seconds of the countdown still to tick, with the seconds left at each.
+    ticks: VecDeque<(Instant, u32)>,
+    /// Whether the countdown ends after a random delay, kept from clients.
+    random_start: bool,
+    false_starts: Vec<FalseStart>,
+    /// Timed part of the last race that ran, kept after it ends for samples
+    /// still queued from it.
+    window: Option<RaceWindow>,
+}
+
+impl RaceLifecycle {
+    pub fn new(config: RaceConfig) -> Self {
+        RaceLifecycle {
+            config,
+            state: RaceState::Idle,
+            race_id: None,
+            format: RaceFormat::Open,
+            laps: HashMap::new(),
+            finishing: BTreeSet::new(),
+            changed_at: None,
+            next: None,
+            ticks: VecDeque::new(),
+            random_start: false,
+            false_starts: Vec::new(),
+            window: None,
+        }
+    }
+
+    pub fn state(&self) -> RaceState {
+        self.state
+    }
+
+    pub fn race_id(&self) -> Option<i32> {
+        self.race_id
+    }
+
+    pub fn format(&self) -> RaceFormat {
+        self.format
+    }
+
+    pub fn window(&self) -> Option<RaceWindow> {
+        self.window
+    }
+
+    /// Window of the race while its clock runs, overtime included.
+    pub fn running(&self) -> Option<RaceWindow> {
+        self.window.filter(RaceWindow::is_running)
+    }
+
+    /// The change the race will make by itself, and when.
+    pub fn next_change(&self) -> Option<(Instant, RaceState)> {
+        self.next
+    }
+
+    /// When something scheduled is next due.
+    pub fn next(&self) -> Option<Instant> {
+        let tick = self.ticks.front().map(|&(at, _)| at);
+        let change = self.next.map(|(at, _)| at);
+        tick.into_iter().chain(change).min()
+    }
+
+    /// The earliest scheduled thing due by `now`, ticks first.
+    pub fn take_due(&mut self, now: Instant) -> Option<Due> {
+        if let Some(&(at, remaining)) = self.ticks.front() {
+            if at <= now {
+                self.ticks.pop_front();
+                return Some(Due::Tick(remaining));
+            }
+        }
+        let (at, to) = self.next.filter(|&(at, _)| at <= now)?;
+        self.next = None;
+        Some(Due::Change(at, to))
+    }
+
+    /// Count a lap of the current race, returning the state the race moves
+    /// on to if it decided or finished it.
+    pub fn record_lap(&mut self, lap: &CreateLap) -> Option<RaceState> {
+        if Some(lap.race_id) != self.race_id {
+            return None;
+        }
+        let laps = self.laps.entry(lap.node_index).or_default();
+        *laps += 1;
+        match self.state {
+            RaceState::Running => self
+                .format
+                .target_laps()
+                .filter(|&target| *laps >= target)
+                .map(|_| RaceState::Overtime),
+            RaceState::Overtime => {
+                let finished = self.finishing.remove(&lap.node_index);
+                (finished && self.finishing.is_empty()).then_some(RaceState::Finished)
+            }
+            _ => None,
+        }
+    }
+
+    /// Flag a false start against the current race.
+    pub fn flag_false_start(&mut self, false_start: FalseStart) {
+        if Some(false_start.race_id) == self.race_id {
+            self.false_starts.push(false_start);
+        }
+    }
+
+    pub fn check(&self, to: RaceState) -> Result<(), String> {
+        if allowed(self.state, to) {
+            Ok(())
+        } else {
+            Err(format!(
+                "a race cannot go from {} to {}",
+                self.state.as_str(),
+                to.as_str()
+            ))
+        }
+    }
+
+    /// Stage a new race, already stored as `race_id`.
+    pub fn stage(&mut self, race_id: i32, format: RaceFormat, at: Instant) -> Result<(), String> {
+        self.check(RaceState::Staging)?;
+        self.race_id = Some(race_id);
+        self.format = format;
+        self.laps.clear();
+        self.false_starts.clear();
+        self.enter(RaceState::Staging, at, None);
+        Ok(())
+    }
+
+    /// Start the countdown, ticking every second, then wait the random delay
+    /// if any. `countdown` overrides both.
+    pub fn start(&mut self, at: Instant, countdown: Option<Duration>) -> Result<(), String> {
+        self.check(RaceState::Countdown)?;
+        let seconds = |value: f64| Duration::try_from_secs_f64(value).map_err(|e| e.to_string());
+        let fixed = match countdown {
+            Some(countdown) => countdown,
+            None => seconds(self.config.countdown_s)?,
+        };
+        let delay = match (countdown, self.config.random_delay) {
+            (None, Some(range)) => {
+                seconds(rand::thread_rng().gen_range(range.min_s..=range.max_s))?
+            }
+            _ => Duration::ZERO,
+        };
+        let end = later(at, fixed)?;
+        let running_at = later(end, delay)?;
+        let seconds = fixed.as_secs_f64().ceil() as u32;
+        self.enter(
+            RaceState::Countdown,
+            at,
+            Some((running_at, RaceState::Running)),
+        );
+        self.ticks = (1..=seconds)
+            .rev()
+            .map(|remaining| {
+                let tick = end.checked_sub(Duration::from_secs(remaining.into()));
+                (tick.unwrap_or(at).max(at), remaining)
+            })
+            .collect();
+        self.random_start = countdown.is_none() && self.config.random_delay.is_some();
+        Ok(())
+    }
+
+    /// Move on to any state but staging and countdown, which need
+    /// [`stage`](Self::stage) and [`start`](Self::start).
+    pub fn transition(&mut self, to: RaceState, at: Instant) -> Result<(), String> {
+        self.check(to)?;
+        let next = match to {
+            RaceState::Idle | RaceState::Staging | RaceState::Countdown => {
+                return Err(format!("{} is entered by staging or starting", to.as_str()))
+            }
+            RaceState::Running => {
+                let next = match self.format.duration() {
+                    Some(duration) => Some((later(at, duration)?, RaceState::Overtime)),
+                    None => None,
+                };
+                self.window = self.race_id.map(|id| RaceWindow {
+                    id,
+                    start: at,
+                    stop: None,
+                });
+                next
+            }
+            RaceState::Overtime => {
+                // Pilots who have lapped and not reached the target yet are
+                // on a lap; the race finishes once they all completed it, or
+                // when the grace period is over.
+                let grace_end = later(at, self.format.grace())?;
+                let target = self.format.target_laps().unwrap_or(u32::MAX);
+                self.finishing = self
+                    .laps
+                    .iter()
+                    .filter(|&(_, &laps)| laps < target)
+                    .map(|(&node_index, _)| node_index)
+                    .collect();
+                let finished = self.finishing.is_empty() && !self.laps.is_empty();
+                Some((if finished { at } else { grace_end }, RaceState::Finished))
+            }
+            RaceState::Finished | RaceState::Aborted => {
+                if let Some(window) = self.window.as_mut().filter(|window| window.is_running()) {
+                    window.stop = Some(at);
+                }
+                None
+            }
+        };
+        self.enter(to, at, next);
+        Ok(())
+    }
+
+    fn enter(&mut self, state: RaceState, at: Instant, next: Option<(Instant, RaceState)>) {
+        println!(
+            "Race {}: {}",
+            self.race_id.unwrap_or_default(),
+            state.as_str()
+        );
+        self.state = state;
+        self.changed_at = Some(at);
+        self.next = next;
+        self.ticks.clear();
+    }
+
+    pub fn status(&self) -> RaceStatus {
+        let time = match (self.window, self.changed_at) {
+            (Some(window), Some(at)) if Some(window.id) == self.race_id && at >= window.start => {
+                Some(at.duration_since(window.start).as_secs_f64())
+            }
+            _ => None,
+        };
+        RaceStatus {
+            race_id: self.race_id,
+            state: self.state,
+            changed_at: self.changed_at.map(|at| wall_clock(at).to_rfc3339()),
+            time,
+            next_in: self
+                .next
+                .filter(|_| !(self.state == RaceState::Countdown && self.random_start))
+                .map(|(at, _)| at.saturating_duration_since(Instant::now()).as_secs_f64()),
+            false_starts: self.false_starts.clone(),
+            format: self.format,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lifecycle(countdown_s: f64) -> RaceLifecycle {
+        RaceLifecycle::new(RaceConfig {
+            countdown_s,
+            ..RaceConfig::default()
+        })
+    }
+
+    fn seconds(start: Instant, seconds: f64) -> Instant {
+        start + Duration::from_secs_f64(seconds)
+    }
+
+    /// Every scheduled thing due by `now`, ticks as the seconds left.
+    fn take_all(race: &mut RaceLifecycle, now: Instant) -> (Vec<u32>, Vec<RaceState>) {
+        let (mut ticks, mut changes) = (Vec::new(), Vec::new());
+        while let Some(due) = race.take_due(now) {
+            match due {
+                Due::Tick(remaining) => ticks.push(remaining),
+                Due::Change(at, to) => {
+                    race.transition(to, at).unwrap();
+                    changes.push(to);
+                }
+            }
+        }
+        (ticks, changes)
+    }
+
+    fn false_start(race_id: i32) -> FalseStart {
+        FalseStart {
+            race_id,
+            node_index: 0,
+            pilot: None,
+            time: 1.0,
+            rssi: 100,
+        }
+    }
+
+    #[test]
+    fn runs_through_its_stages() {
+        let start = Instant::now();
+        let mut race = lifecycle(3.0);
+        assert_eq!(race.state(), RaceState::Idle);
+
+        race.stage(7, RaceFormat::Open, start).unwrap();
+        assert_eq!(race.state(), RaceState::Staging);
+        assert_eq!(race.race_id(), Some(7));
+        race.start(start, None).unwrap();
+        assert_eq!(race.state(), RaceState::Countdown);
+        assert_eq!(race.next(), Some(start));
+
+        assert_eq!(
+            take_all(&mut race, seconds(start, 1.5)),
+            (vec![3, 2], vec![])
+        );
+        assert_eq!(
+            take_all(&mut race, seconds(start, 3.0)),
+            (vec![1], vec![RaceState::Running])
+        );
+        let window = race.running().unwrap();
+        assert_eq!((window.id, window.start), (7, seconds(start, 3.0)));
+        assert_eq!(race.next(), None);
+
+        race.transition(RaceState::Finished, seconds(start, 10.0))
+            .unwrap();
+        assert!(race.running().is_none());
+        assert_eq!(race.window().unwrap().stop, Some(seconds(start, 10.0)));
+        assert_eq!(race.status().time, Some(7.0));
+
+        // The next race can be staged.
+        race.stage(8, RaceFormat::Open, seconds(start, 11.0))
+            .unwrap();
+        assert_eq!(race.race_id(), Some(8));
+    }
+
+    #[test]
+    fn refuses_changes_out_of_order() {
+        let start = Instant::now();
+        let mut race = lifecycle(0.0);
+        assert!(race.start(start, None).is_err());
+        assert!(race.transition(RaceState::Running, start).is_err());
+        assert!(race.transition(RaceState::Aborted, start).is_err());
+
+        race.stage(1, RaceFormat::Open, start).unwrap();
+        assert!(race.stage(2, RaceFormat::Open, start).is_err());
+        assert!(race.transition(RaceState::Finished, start).is_err());
+        // Staging and countdown only through their own commands.
+        assert!(race.transition(RaceState::Countdown, start).is_err());
+
+        race.start(start, None).unwrap();
+        take_all(&mut race, start);
+        assert_eq!(race.state(), RaceState::Running);
+        assert!(race.start(start, None).is_err());
+        assert!(race.transition(RaceState::Idle, start).is_err());
+        assert_eq!(race.race_id(), Some(1));
+    }
+
+    #[test]
+    fn aborts_until_finished() {
+        let start = Instant::now();
+        let mut race = lifecycle(5.0);
+        race.stage(1, RaceFormat::Open, start).unwrap();
+        race.start(start, None).unwrap();
+        race.transition(RaceState::Aborted, seconds(start, 1.0))
+            .unwrap();
+        // Nothing of the countdown is left to run.
+        assert_eq!(race.next(), None);
+        assert!(race.window().is_none());
+
+        race.stage(2, RaceFormat::Open, start).unwrap();
+        race.start(start, Some(Duration::ZERO)).unwrap();
+        take_all(&mut race, start);
+        race.transition(RaceState::Finished, seconds(start, 2.0))
+            .unwrap();
+        assert!(race.check(RaceState::Aborted).is_err());
+    }
+
+    #[test]
+    fn countdown_override_skips_random_delay() {
+        let start = Instant::now();
+        let mut race = RaceLifecycle::new(RaceConfig {
+            countdown_s: 3.0,
+            random_delay: Some(crate::config::DelayRange {
+                min_s: 10.0,
+                max_s: 20.0,
+            }),
+            ..RaceConfig::default()
+        });
+        race.stage(1, RaceFormat::Open, start).unwrap();
+        race.start(start, Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(
+            race.next_change(),
+            Some((seconds(start, 2.0), RaceState::Running))
+        );
+        assert!(race.status().next_in.is_some());
+
+        race.transition(RaceState::Aborted, start).unwrap();
+        race.stage(2, RaceFormat::Open, start).unwrap();
+        race.start(start, None).unwrap();
+        let (at, _) = race.next_change().unwrap();
+        assert!(at >= seconds(start, 13.0) && at <= seconds(start, 23.0));
+        // Clients are not told when the race starts.
+        assert_eq!(race.status().next_in, None);
+    }
+
+    #[test]
+    fn keeps_false_starts_of_the_current_race() {
+        let start = Instant::now();
+        let mut race = lifecycle(3.0);
+        race.stage(1, RaceFormat::Open, start).unwrap();
+        race.start(start, None).unwrap();
+        race.flag_false_start(false_start(1));
+        race.flag_false_start(false_start(2));
+        assert_eq!(race.status().false_starts.len(), 1);
+
+        race.transition(RaceState::Aborted, start).unwrap();
+        assert_eq!(race.status().false_starts.len(), 1);
+        race.stage(2, RaceFormat::Open, start).unwrap();
+        assert!(race.status().false_starts.is_empty());
+    }
+
+    fn lap(node_index: i32) -> CreateLap {
+        CreateLap {
+            race_id: 1,
+            node_index,
+            time: 0.0,
+            host_time: None,
+            uncertainty: None,
+        }
+    }
+
+    /// A race in `format` running since `start`.
+    fn running(format: RaceFormat, start: Instant) -> RaceLifecycle {
+        let mut race = lifecycle(0.0);
+        race.stage(1, format, start).unwrap();
+        race.start(start, None).unwrap();
+        take_all(&mut race, start);
+        assert_eq!(race.state(), RaceState::Running);
+        race
+    }
+
+    #[test]
+    fn first_to_laps_finishes_once_lapping_pilots_are_in() {
+        let start = Instant::now();
+        let format = RaceFormat::FirstToLaps {
+            laps: 2,
+            grace_s: 10.0,
+        };
+        let mut race = running(format, start);
+        assert_eq!(race.next_change(), None);
+
+        assert_eq!(race.record_lap(&lap(1)), None);
+        assert_eq!(race.record_lap(&lap(2)), None);
+        // Laps of another race do not count.
+        assert_eq!(
+            race.record_lap(&CreateLap {
+                race_id: 2,
+                ..lap(1)
+            }),
+            None
+        );
+        assert_eq!(race.record_lap(&lap(1)), Some(RaceState::Overtime));
+
+        race.transition(RaceState::Overtime, seconds(start, 30.0))
+            .unwrap();
+        assert_eq!(
+            race.next_change(),
+            Some((seconds(start, 40.0), RaceState::Finished))
+        );
+        // Node 1 already finished; node 2 completing its lap ends the race.
+        assert_eq!(race.record_lap(&lap(1)), None);
+        assert_eq!(race.record_lap(&lap(2)), Some(RaceState::Finished));
+    }
+
+    #[test]
+    fn first_to_laps_finishes_at_once_when_nobody_else_lapped() {
+        let start = Instant::now();
+        let format = RaceFormat::FirstToLaps {
+            laps: 1,
+            grace_s: 10.0,
+        };
+        let mut race = running(format, start);
+        assert_eq!(race.record_lap(&lap(1)), Some(RaceState::Overtime));
+        race.transition(RaceState::Overtime, seconds(start, 5.0))
+            .unwrap();
+        assert_eq!(
+            race.next_change(),
+            Some((seconds(start, 5.0), RaceState::Finished))
+        );
+    }
+
+    #[test]
+    fn timed_race_finishes_after_grace() {
+        let start = Instant::now();
+        let format = RaceFormat::Timed {
+            duration_s: 60.0,
+            grace_s: 5.0,
+        };
+        let mut race = running(format, start);
+        race.record_lap(&lap(1));
+
+        let (_, changes) = take_all(&mut race, seconds(start, 60.0));
+        assert_eq!(changes, [RaceState::Overtime]);
+        assert_eq!(race.record_lap(&lap(1)), Some(RaceState::Finished));
+
+        let mut race = running(format, start);
+        let (_, changes) = take_all(&mut race, seconds(start, 65.0));
+        assert_eq!(changes, [RaceState::Overtime, RaceState::Finished]);
+        assert_eq!(race.window().unwrap().stop, Some(seconds(start, 65.0)));
+    }
+
+    #[test]
+    fn open_race_runs_until_stopped() {
+        let start = Instant::now();
+        let mut race = running(RaceFormat::Open, start);
+        for _ in 0..100 {
+            assert_eq!(race.record_lap(&lap(1)), None);
+        }
+        assert_eq!(race.next(), None);
+    }
+
+    #[test]
+    fn refuses_deadlines_out_of_range() {
+        let start = Instant::now();
+        // Past validation, a format this long would overflow the race clock.
+        let format = RaceFormat::Timed {
+            duration_s: 1e20,
+            grace_s: 0.0,
+        };
+        let mut race = lifecycle(0.0);
+        race.stage(1, format, start).unwrap();
+        race.start(start, None).unwrap();
+        assert!(race.transition(RaceState::Running, start).is_err());
+        assert_eq!(race.state(), RaceState::Countdown);
+        assert!(race.window().is_none());
+    }
+
+    #[test]
+    fn refuses_countdowns_out_of_range() {
+        let start = Instant::now();
+        let mut race = RaceLifecycle::new(RaceConfig {
+            countdown_s: 1e20,
+            ..RaceConfig::default()
+        });
+        race.stage(1, RaceFormat::Open, start).unwrap();
+        assert!(race.start(start, None).is_err());
+        assert!(race.start(start, Some(Duration::MAX)).is_err());
+        assert_eq!(race.state(), RaceState::Staging);
+    }
+}
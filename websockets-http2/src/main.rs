@@ -1,6 +1,6 @@
-use axum::{routing::any, routing::get, Router};
+use axum::{routing::any, routing::get, routing::post, routing::put, Router};
 use axum_server::tls_rustls::RustlsConfig;
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tower_http::services::ServeDir;
@@ -8,23 +8,40 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 mod worker;
 use crate::worker::worker_task;
 mod api;
+mod calibration;
+mod config;
 mod enums;
+mod filter;
+mod lap_tracker;
+mod leaderboard;
+mod lifecycle;
 mod node;
+mod node_manager;
+mod pass_detector;
+mod race_format;
+mod recalculation;
+mod sampler;
 mod structs;
+use crate::api::calibration as calibration_api;
 use crate::api::count;
+use crate::api::marshal;
+use crate::api::nodes;
 use crate::api::post;
 use crate::api::race;
 mod websocket;
 use crate::structs::state::AppState;
 use crate::websocket::ws_handler;
 mod db;
+use crate::config::Config;
 use crate::db::init_db;
+use crate::node::replay::{self, ReplaySession};
 
 #[tokio::main]
 async fn main() {
     let (command_sender, command_receiver) = mpsc::channel(32);
     let (tx, _) = broadcast::channel(100);
     let db = init_db().await.unwrap();
+    let config = Config::load().unwrap();
 
     let app_state = AppState {
         command_sender,
@@ -32,8 +49,29 @@ async fn main() {
         db,
     };
 
+    let replay_session = config
+        .replay
+        .as_ref()
+        .map(|replay| ReplaySession::load(replay).unwrap());
+    if let Some(session) = &replay_session {
+        tokio::spawn(replay::drive(
+            Arc::clone(session),
+            app_state.command_sender.clone(),
+        ));
+    }
+
+    if let Some(standin) = &config.standin {
+        node::standin::spawn(&config, standin).unwrap();
+    }
+
     let db_pool = app_state.db.clone();
-    tokio::spawn(worker_task(command_receiver, db_pool));
+    tokio::spawn(worker_task(
+        command_receiver,
+        db_pool,
+        app_state.tx.clone(),
+        config,
+        replay_session,
+    ));
 
     tracing_subscriber::registry()
         .with(
@@ -64,7 +102,40 @@ async fn main() {
         .route("/get_count", get(count::get_count_handler))
         .route("/start_race", get(race::start_race))
         .route("/stop_race", get(race::stop_race))
+        .route("/race", get(race::get_race))
+        .route("/race/stage", post(race::stage_race))
+        .route("/race/start", post(race::start_race))
+        .route("/race/stop", post(race::stop_race))
+        .route("/race/abort", post(race::abort_race))
+        .route("/races/{id}/transitions", get(race::get_race_transitions))
+        .route("/races/{id}/false_starts", get(race::get_false_starts))
         .route("/debug", get(race::debug))
+        .route("/laps", get(race::get_laps))
+        .route("/races/{id}/laps", post(marshal::add_lap))
+        .route(
+            "/laps/{id}",
+            put(marshal::move_lap).delete(marshal::delete_lap),
+        )
+        .route("/races/{id}/lap_edits", get(marshal::get_lap_edits))
+        .route("/races/{id}/leaderboard", get(marshal::get_leaderboard))
+        .route("/races/{id}/recalculate", post(race::recalculate_laps))
+        .route(
+            "/races/{id}/recalculate/accept",
+            post(race::accept_recalculated_laps),
+        )
+        .route("/nodes", get(nodes::get_nodes))
+        .route("/nodes/{index}/frequency", post(nodes::set_frequency))
+        .route("/nodes/{index}/filters", put(nodes::set_filters))
+        .route(
+            "/nodes/{index}/calibration",
+            get(calibration_api::get_calibration).post(calibration_api::start_calibration),
+        )
+        .route(
+            "/nodes/{index}/calibration/finish",
+            post(calibration_api::finish_calibration),
+        )
+        .route("/nodes/{index}/pilot", put(calibration_api::set_pilot))
+        .route("/bands", get(nodes::get_bands))
         .route("/posts", get(post::get_posts).post(post::create_post))
         .with_state(app_state);
 
@@ -0,0 +1,78 @@
+use super::NodeBackend;
+use crate::config::NodeConfig;
+use rustimer::i2c::{I2cNode, LinuxBus};
+use rustimer::protocol::{self, Peak, ProtocolError};
+use std::io;
+
+/// Arduino node on an I2C bus, as on Raspberry Pi boards carrying several.
+///
+/// Every node opens the bus on its own: the kernel keeps the target address
+/// per open file and runs each transfer whole, so nodes on one bus can be
+/// driven from separate threads.
+pub struct I2cBackend {
+    bus: String,
+    address: Option<u8>,
+    node: Option<I2cNode<LinuxBus>>,
+}
+
+impl I2cBackend {
+    pub fn new(node_config: &NodeConfig) -> Self {
+        I2cBackend {
+            bus: node_config.port.clone(),
+            address: node_config.address,
+            node: None,
+        }
+    }
+
+    fn node(&mut self) -> Result<&mut I2cNode<LinuxBus>, ProtocolError> {
+        self.node.as_mut().ok_or_else(|| {
+            ProtocolError::Io(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "I2C bus is not open",
+            ))
+        })
+    }
+}
+
+impl NodeBackend for I2cBackend {
+    fn open(&mut self) -> Result<(), ProtocolError> {
+        self.node = None;
+        let address = self.address.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("I2C node on {} has no address", self.bus),
+            )
+        })?;
+        println!("Using I2C node 0x{:02X} on {}", address, self.bus);
+        self.node = Some(I2cNode::new(LinuxBus::open(&self.bus)?, address));
+        Ok(())
+    }
+
+    fn read_peak(&mut self) -> Result<Peak, ProtocolError> {
+        protocol::read_peak(self.node()?)
+    }
+
+    fn read_time(&mut self) -> Result<u32, ProtocolError> {
+        protocol::read_millis(self.node()?)
+    }
+
+    fn read_version(&mut self) -> Result<u16, ProtocolError> {
+        protocol::read_version(self.node()?)
+    }
+
+    fn read_slot_count(&mut self) -> Result<u8, ProtocolError> {
+        protocol::read_slot_count(self.node()?)
+    }
+
+    fn select_slot(&mut self, slot: u8) -> Result<(), ProtocolError> {
+        protocol::select_slot(self.node()?, slot)
+    }
+
+    fn read_frequency(&mut self) -> Result<u16, ProtocolError> {
+        protocol::read_frequency(self.node()?)
+    }
+
+    fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError> {
+        protocol::set_frequency(self.node()?, frequency)
+    }
+}
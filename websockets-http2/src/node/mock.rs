@@ -0,0 +1,77 @@
+use super::NodeBackend;
+use rand::Rng;
+use rustimer::firmware;
+use rustimer::protocol::{Peak, ProtocolError};
+use std::time::Instant;
+
+const SLOT_COUNT: u8 = 8;
+
+/// Stand-in node that answers every command without hardware. RSSI values are
+/// random and arrive after a random 1-5 second delay.
+pub struct MockBackend {
+    opened_at: Instant,
+    slot: usize,
+    frequencies: [u16; SLOT_COUNT as usize],
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        MockBackend {
+            opened_at: Instant::now(),
+            slot: 0,
+            frequencies: [5658; SLOT_COUNT as usize],
+        }
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NodeBackend for MockBackend {
+    fn open(&mut self) -> Result<(), ProtocolError> {
+        println!("Using mock Arduino port.");
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+
+    fn read_peak(&mut self) -> Result<Peak, ProtocolError> {
+        let mut rng = rand::thread_rng();
+        let rssi = rng.gen_range(50..100);
+        let random_sleep = rng.gen_range(1..5);
+        std::thread::sleep(std::time::Duration::from_secs(random_sleep));
+        Ok(Peak {
+            lap_id: 0,
+            ms_val: 0,
+            rssi,
+        })
+    }
+
+    fn read_time(&mut self) -> Result<u32, ProtocolError> {
+        Ok(self.opened_at.elapsed().as_millis() as u32)
+    }
+
+    fn read_version(&mut self) -> Result<u16, ProtocolError> {
+        Ok(firmware::LATEST_VERSION)
+    }
+
+    fn read_slot_count(&mut self) -> Result<u8, ProtocolError> {
+        Ok(SLOT_COUNT)
+    }
+
+    fn select_slot(&mut self, slot: u8) -> Result<(), ProtocolError> {
+        self.slot = usize::from(slot.min(SLOT_COUNT - 1));
+        Ok(())
+    }
+
+    fn read_frequency(&mut self) -> Result<u16, ProtocolError> {
+        Ok(self.frequencies[self.slot])
+    }
+
+    fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError> {
+        self.frequencies[self.slot] = frequency;
+        Ok(frequency)
+    }
+}
@@ -0,0 +1,156 @@
+mod i2c;
+mod mock;
+mod network;
+pub mod recording;
+pub mod replay;
+mod serial;
+mod session;
+mod simulator;
+pub mod standin;
+mod tcp;
+mod udp;
+
+use crate::config::{default_baud_rate, BackendKind, Config, NodeConfig};
+use rustimer::clock::ClockReading;
+use rustimer::discovery;
+use rustimer::protocol::{Peak, ProtocolError};
+use rustimer::stream::{StreamFrame, StreamStats};
+use std::io;
+use std::time::{Duration, Instant};
+
+pub use self::i2c::I2cBackend;
+pub use self::mock::MockBackend;
+pub use self::serial::SerialBackend;
+pub use self::simulator::SimulatorBackend;
+pub use self::tcp::TcpBackend;
+pub use self::udp::UdpBackend;
+
+/// A timing node the worker can talk to. Implementations are blocking and are
+/// driven from the blocking thread pool.
+///
+/// One backend is one device; devices driving several receivers route the
+/// per-receiver commands to the slot picked with [`NodeBackend::select_slot`].
+pub trait NodeBackend: Send {
+    /// Connect to the device. Called once before any other method.
+    fn open(&mut self) -> Result<(), ProtocolError>;
+
+    /// Time the last response was received. Replays report the recorded time
+    /// so detection sees the same timing as the original session.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Smoothed round trip to the device, for backends that measure it.
+    fn latency(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Lap counter, time since the last lap and current RSSI.
+    fn read_peak(&mut self) -> Result<Peak, ProtocolError>;
+
+    /// Device uptime in milliseconds.
+    fn read_time(&mut self) -> Result<u32, ProtocolError>;
+
+    /// Device uptime along with the host times around the request, for clock
+    /// sync.
+    fn read_clock(&mut self) -> Result<ClockReading, ProtocolError> {
+        let sent = Instant::now();
+        let millis = self.read_time()?;
+        Ok(ClockReading {
+            sent,
+            millis,
+            received: self.now(),
+        })
+    }
+
+    /// Firmware version word, see [`rustimer::firmware`].
+    fn read_version(&mut self) -> Result<u16, ProtocolError>;
+
+    fn read_slot_count(&mut self) -> Result<u8, ProtocolError>;
+
+    fn select_slot(&mut self, slot: u8) -> Result<(), ProtocolError>;
+
+    fn read_frequency(&mut self) -> Result<u16, ProtocolError>;
+
+    /// Tune the receiver and return the frequency it reports afterwards.
+    fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError>;
+
+    /// Start or stop pushing samples, see [`rustimer::stream`]. Requests made
+    /// while streaming still work; backends pause the stream around them.
+    fn set_streaming(&mut self, _on: bool) -> Result<(), ProtocolError> {
+        Err(ProtocolError::Io(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "backend does not stream",
+        )))
+    }
+
+    /// Frames pushed since the last call, for every slot. Waits for at least
+    /// one.
+    fn read_stream(&mut self) -> Result<Vec<StreamFrame>, ProtocolError> {
+        Err(not_streaming())
+    }
+
+    /// Counters of the stream, while streaming.
+    fn stream_stats(&self) -> Option<StreamStats> {
+        None
+    }
+}
+
+fn not_streaming() -> ProtocolError {
+    ProtocolError::Io(io::Error::new(
+        io::ErrorKind::NotConnected,
+        "device is not streaming",
+    ))
+}
+
+/// Build the (not yet opened) backend selected for a node.
+pub fn create_backend(config: &Config, node_config: &NodeConfig) -> Box<dyn NodeBackend> {
+    match node_config.backend.unwrap_or(config.backend) {
+        BackendKind::Serial => Box::new(SerialBackend::new(node_config)),
+        BackendKind::Mock => Box::new(MockBackend::new()),
+        BackendKind::Simulator => Box::new(SimulatorBackend::new(&config.simulator)),
+        BackendKind::Tcp => Box::new(TcpBackend::new(node_config)),
+        BackendKind::Udp => Box::new(UdpBackend::new(node_config)),
+        BackendKind::I2c => Box::new(I2cBackend::new(node_config)),
+    }
+}
+
+/// Nodes to use when none are configured: every USB device found running node
+/// firmware, bound by serial number when it has one. Other backends
+/// get a single device.
+pub fn discover_nodes(config: &Config) -> Vec<NodeConfig> {
+    match config.backend {
+        BackendKind::Serial => match discovery::discover(default_baud_rate()) {
+            Ok(found) => found
+                .into_iter()
+                .map(|node| {
+                    println!(
+                        "Discovered node on {} ({}, serial {:?}, firmware {})",
+                        node.candidate.port_name,
+                        node.candidate.chip,
+                        node.candidate.serial_number,
+                        node.firmware
+                    );
+                    NodeConfig {
+                        serial_number: node.candidate.serial_number,
+                        ..NodeConfig::new(&node.candidate.port_name)
+                    }
+                })
+                .collect(),
+            Err(e) => {
+                eprintln!("Failed to list serial ports: {}", e);
+                Vec::new()
+            }
+        },
+        BackendKind::Mock => vec![NodeConfig::new("mock")],
+        BackendKind::Simulator => vec![NodeConfig::new("simulator")],
+        BackendKind::Tcp | BackendKind::Udp => {
+            eprintln!("Network nodes are not discovered; configure their addresses");
+            Vec::new()
+        }
+        BackendKind::I2c => {
+            eprintln!("I2C nodes are not discovered; configure their bus addresses");
+            Vec::new()
+        }
+    }
+}
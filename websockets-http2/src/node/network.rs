@@ -0,0 +1,47 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Smoothed round trip of the exchanges with a network node, weighted like
+/// TCP's smoothed RTT so one slow packet does not swing it.
+#[derive(Debug, Default)]
+pub struct Latency {
+    smoothed: Option<Duration>,
+}
+
+impl Latency {
+    /// Time `exchange` and fold its duration in when it succeeds.
+    pub fn measure<T, E>(&mut self, exchange: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        let started = Instant::now();
+        let result = exchange()?;
+        self.record(started.elapsed());
+        Ok(result)
+    }
+
+    pub fn record(&mut self, sample: Duration) {
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => smoothed.mul_f64(0.875) + sample.mul_f64(0.125),
+            None => sample,
+        });
+    }
+
+    pub fn get(&self) -> Option<Duration> {
+        self.smoothed
+    }
+}
+
+/// Every address `address` (`host:port`) resolves to.
+pub fn resolve(address: &str) -> io::Result<Vec<SocketAddr>> {
+    let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+    if addresses.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} does not resolve to any address", address),
+        ));
+    }
+    Ok(addresses)
+}
+
+pub fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "node is not connected")
+}
@@ -0,0 +1,192 @@
+use super::session::{Event, Exchange, Record, RecordedError, SessionWriter};
+use super::NodeBackend;
+use crate::race_format::RaceFormat;
+use rustimer::protocol::{NodeCommand, NodeResponse, Peak, ProtocolError};
+use rustimer::stream::{StreamFrame, StreamStats};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Session file shared by every recording backend and the worker.
+pub struct Recorder {
+    epoch: Instant,
+    writer: Mutex<SessionWriter>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Arc<Recorder>> {
+        println!("Recording node session to {}", path.display());
+        Ok(Arc::new(Recorder {
+            epoch: Instant::now(),
+            writer: Mutex::new(SessionWriter::create(path)?),
+        }))
+    }
+
+    /// Offset of `instant` in the recording, truncated to the microsecond
+    /// resolution of the file so replays see exactly the same times.
+    fn offset(&self, instant: Instant) -> Duration {
+        let offset = instant.saturating_duration_since(self.epoch);
+        Duration::from_micros(offset.as_micros() as u64)
+    }
+
+    /// Write an event stamped with the current time and return that time.
+    fn record(&self, event: Event) -> Instant {
+        self.write(event, None)
+    }
+
+    /// Record the start of a race with its format and return its start time
+    /// as seen by the recording, which is what a replay will use.
+    pub fn race_start(&self, race_id: i32, format: RaceFormat, time: Instant) -> Instant {
+        self.write(Event::RaceFormat { race_id, format }, Some(time));
+        self.write(Event::RaceStart { race_id }, Some(time))
+    }
+
+    pub fn race_stop(&self, race_id: i32, time: Instant) -> Instant {
+        self.write(Event::RaceStop { race_id }, Some(time))
+    }
+
+    fn write(&self, event: Event, time: Option<Instant>) -> Instant {
+        // Take the time under the lock so records land in the file in order.
+        let mut writer = self.writer.lock().unwrap();
+        let at = self.offset(time.unwrap_or_else(Instant::now));
+        if let Err(e) = writer.write(&Record { at, event }) {
+            eprintln!("Failed to record node session: {}", e);
+        }
+        self.epoch + at
+    }
+}
+
+/// Wraps another backend and records every command it is asked to run along
+/// with the response, as protocol wire bytes, and every frame it streams.
+pub struct RecordingBackend {
+    inner: Box<dyn NodeBackend>,
+    device: u8,
+    recorder: Arc<Recorder>,
+    now: Instant,
+}
+
+impl RecordingBackend {
+    pub fn new(inner: Box<dyn NodeBackend>, device: u8, recorder: Arc<Recorder>) -> Self {
+        RecordingBackend {
+            inner,
+            device,
+            recorder,
+            now: Instant::now(),
+        }
+    }
+
+    fn record_exchange(&mut self, command: NodeCommand, response: Result<Vec<u8>, RecordedError>) {
+        let exchange = Exchange {
+            device: self.device,
+            request: command.encode(),
+            response,
+        };
+        self.now = self.recorder.record(Event::Exchange(exchange));
+    }
+
+    fn record<T>(
+        &mut self,
+        command: NodeCommand,
+        result: Result<T, ProtocolError>,
+        response: impl Fn(&T) -> NodeResponse,
+    ) -> Result<T, ProtocolError> {
+        let recorded = match &result {
+            Ok(value) => Ok(response(value).encode()),
+            Err(e) => Err(RecordedError::from_protocol(e)),
+        };
+        self.record_exchange(command, recorded);
+        result
+    }
+}
+
+impl NodeBackend for RecordingBackend {
+    fn open(&mut self) -> Result<(), ProtocolError> {
+        self.inner.open()
+    }
+
+    fn now(&self) -> Instant {
+        self.now
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.inner.latency()
+    }
+
+    fn read_peak(&mut self) -> Result<Peak, ProtocolError> {
+        let result = self.inner.read_peak();
+        self.record(NodeCommand::ReadPeak, result, |peak| {
+            NodeResponse::Peak(*peak)
+        })
+    }
+
+    fn read_time(&mut self) -> Result<u32, ProtocolError> {
+        let result = self.inner.read_time();
+        self.record(NodeCommand::ReadMillis, result, |millis| {
+            NodeResponse::Millis(*millis)
+        })
+    }
+
+    fn read_version(&mut self) -> Result<u16, ProtocolError> {
+        let result = self.inner.read_version();
+        self.record(NodeCommand::ReadVersion, result, |version| {
+            NodeResponse::Version(*version)
+        })
+    }
+
+    fn read_slot_count(&mut self) -> Result<u8, ProtocolError> {
+        let result = self.inner.read_slot_count();
+        self.record(NodeCommand::ReadSlotCount, result, |count| {
+            NodeResponse::SlotCount(*count)
+        })
+    }
+
+    fn select_slot(&mut self, slot: u8) -> Result<(), ProtocolError> {
+        let result = self.inner.select_slot(slot);
+        self.record(NodeCommand::SelectSlot(slot), result, |_| NodeResponse::Ack)
+    }
+
+    fn read_frequency(&mut self) -> Result<u16, ProtocolError> {
+        let result = self.inner.read_frequency();
+        self.record(NodeCommand::ReadFrequency, result, |frequency| {
+            NodeResponse::Frequency(*frequency)
+        })
+    }
+
+    /// Recorded as the write followed by the read-back, as on the wire. A
+    /// failure is recorded against the write.
+    fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError> {
+        let command = NodeCommand::SetFrequency(frequency);
+        match self.inner.set_frequency(frequency) {
+            Ok(tuned) => {
+                self.record_exchange(command, Ok(NodeResponse::Ack.encode()));
+                self.record(NodeCommand::ReadFrequency, Ok(tuned), |frequency| {
+                    NodeResponse::Frequency(*frequency)
+                })
+            }
+            Err(e) => {
+                self.record_exchange(command, Err(RecordedError::from_protocol(&e)));
+                Err(e)
+            }
+        }
+    }
+
+    fn set_streaming(&mut self, on: bool) -> Result<(), ProtocolError> {
+        let result = self.inner.set_streaming(on);
+        self.record(NodeCommand::SetStreaming(on), result, |_| NodeResponse::Ack)
+    }
+
+    /// Failed reads are not recorded; a replay just reads on.
+    fn read_stream(&mut self) -> Result<Vec<StreamFrame>, ProtocolError> {
+        let frames = self.inner.read_stream()?;
+        self.now = self.recorder.record(Event::Stream {
+            device: self.device,
+            frames: frames.clone(),
+        });
+        Ok(frames)
+    }
+
+    fn stream_stats(&self) -> Option<StreamStats> {
+        self.inner.stream_stats()
+    }
+}
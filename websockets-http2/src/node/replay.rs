@@ -0,0 +1,389 @@
+use super::session::{self, Event, Exchange};
+use super::NodeBackend;
+use crate::config::ReplayConfig;
+use crate::enums::command::Command;
+use crate::structs::race::{RaceState, RaceStatus};
+use rustimer::clock::ClockReading;
+use rustimer::protocol::{NodeCommand, NodeResponse, Peak, ProtocolError};
+use rustimer::stream::StreamFrame;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+/// How long a replay backend waits before reporting a timeout once its device
+/// has no recorded exchanges left.
+const EXHAUSTED_DELAY: Duration = Duration::from_millis(500);
+/// How long a race stop waits for the worker to replay the exchanges recorded
+/// before it. Exchanges the worker never asks for would otherwise stall it.
+const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a device did in the recording.
+enum DeviceRecord {
+    Exchange(Exchange),
+    Stream(Vec<StreamFrame>),
+}
+
+impl DeviceRecord {
+    fn is_exchange(&self, request: &[u8]) -> bool {
+        matches!(self, DeviceRecord::Exchange(exchange) if exchange.request == request)
+    }
+}
+
+/// A recorded session being played back. Exchanges and streamed frames are
+/// handed to the replay backend of their device in order; race starts and
+/// stops are fed to the worker by [`drive`].
+pub struct ReplaySession {
+    epoch: Instant,
+    speed: f64,
+    devices: Mutex<HashMap<u8, VecDeque<(Duration, DeviceRecord)>>>,
+    marks: Mutex<VecDeque<(Duration, Event)>>,
+}
+
+impl ReplaySession {
+    pub fn load(config: &ReplayConfig) -> io::Result<Arc<ReplaySession>> {
+        let records = session::read_session(&config.file)?;
+        println!(
+            "Replaying {} records from {} at {}x",
+            records.len(),
+            config.file.display(),
+            config.speed
+        );
+
+        let mut devices: HashMap<u8, VecDeque<(Duration, DeviceRecord)>> = HashMap::new();
+        let mut marks = VecDeque::new();
+        for record in records {
+            match record.event {
+                Event::Exchange(exchange) => devices
+                    .entry(exchange.device)
+                    .or_default()
+                    .push_back((record.at, DeviceRecord::Exchange(exchange))),
+                Event::Stream { device, frames } => devices
+                    .entry(device)
+                    .or_default()
+                    .push_back((record.at, DeviceRecord::Stream(frames))),
+                event => marks.push_back((record.at, event)),
+            }
+        }
+
+        Ok(Arc::new(ReplaySession {
+            epoch: Instant::now(),
+            speed: config.speed.max(f64::MIN_POSITIVE),
+            devices: Mutex::new(devices),
+            marks: Mutex::new(marks),
+        }))
+    }
+
+    /// Number of devices with recorded exchanges or frames.
+    pub fn device_count(&self) -> u8 {
+        self.devices.lock().unwrap().len() as u8
+    }
+
+    /// Instant at which something recorded at `at` happens in this replay.
+    /// Recorded offsets are kept as they are, so every time derived from two
+    /// of these instants matches the recording regardless of the speed.
+    fn virtual_time(&self, at: Duration) -> Instant {
+        self.epoch + at
+    }
+
+    /// Sleep until the wall-clock moment `at` is due at the replay speed.
+    fn wait_until(&self, at: Duration) -> Duration {
+        let due = self.epoch + at.div_f64(self.speed);
+        due.saturating_duration_since(Instant::now())
+    }
+
+    /// Take the next recorded exchange of `device` for `command`. Recorded
+    /// exchanges the replay does not ask for are skipped; a command that was
+    /// never recorded fails without consuming anything.
+    fn next_exchange(&self, device: u8, command: &NodeCommand) -> Option<(Duration, Exchange)> {
+        let request = command.encode();
+        match self.take(device, |record| record.is_exchange(&request))? {
+            (at, DeviceRecord::Exchange(exchange)) => Some((at, exchange)),
+            _ => None,
+        }
+    }
+
+    /// Take the next frames `device` streamed, skipping like
+    /// [`next_exchange`](Self::next_exchange).
+    fn next_stream(&self, device: u8) -> Option<(Duration, Vec<StreamFrame>)> {
+        match self.take(device, |record| matches!(record, DeviceRecord::Stream(_)))? {
+            (at, DeviceRecord::Stream(frames)) => Some((at, frames)),
+            _ => None,
+        }
+    }
+
+    fn take(
+        &self,
+        device: u8,
+        wanted: impl Fn(&DeviceRecord) -> bool,
+    ) -> Option<(Duration, DeviceRecord)> {
+        let mut devices = self.devices.lock().unwrap();
+        let queue = devices.get_mut(&device)?;
+        let position = queue.iter().position(|(_, record)| wanted(record))?;
+        // Clock reads are taken on a timer, so a session holds more of them
+        // than a replay asks for; those are skipped without a word.
+        let millis = NodeCommand::ReadMillis.encode();
+        let skipped = queue
+            .iter()
+            .take(position)
+            .filter(|(_, record)| !record.is_exchange(&millis))
+            .count();
+        if skipped > 0 {
+            println!(
+                "Replay: skipped {} recorded records on device {}",
+                skipped, device
+            );
+        }
+        queue.drain(..position);
+        queue.pop_front()
+    }
+
+    /// Whether the next recorded exchange of `device` is for `command`.
+    fn next_is(&self, device: u8, command: &NodeCommand) -> bool {
+        let request = command.encode();
+        self.devices
+            .lock()
+            .unwrap()
+            .get(&device)
+            .and_then(|queue| queue.front())
+            .is_some_and(|(_, record)| record.is_exchange(&request))
+    }
+
+    /// Whether everything devices did before `at` has been replayed.
+    fn caught_up(&self, at: Duration) -> bool {
+        self.devices
+            .lock()
+            .unwrap()
+            .values()
+            .all(|queue| queue.front().is_none_or(|(recorded, _)| *recorded >= at))
+    }
+
+    /// Drop what devices did before `at` that was never replayed, such as a
+    /// read still in flight when the previous race stopped.
+    fn discard_before(&self, at: Duration) {
+        for queue in self.devices.lock().unwrap().values_mut() {
+            while queue.front().is_some_and(|(recorded, _)| *recorded < at) {
+                queue.pop_front();
+            }
+        }
+    }
+}
+
+/// Plays back one recorded device.
+pub struct ReplayBackend {
+    session: Arc<ReplaySession>,
+    device: u8,
+    now: Instant,
+}
+
+impl ReplayBackend {
+    pub fn new(session: Arc<ReplaySession>, device: u8) -> Self {
+        ReplayBackend {
+            now: session.epoch,
+            session,
+            device,
+        }
+    }
+
+    fn exchange(&mut self, command: NodeCommand) -> Result<NodeResponse, ProtocolError> {
+        let Some((at, exchange)) = self.session.next_exchange(self.device, &command) else {
+            std::thread::sleep(EXHAUSTED_DELAY);
+            return Err(ProtocolError::Timeout);
+        };
+
+        std::thread::sleep(self.session.wait_until(at));
+        self.now = self.session.virtual_time(at);
+        match exchange.response {
+            Ok(frame) => command.decode(&frame),
+            Err(e) => Err(e.to_protocol()),
+        }
+    }
+}
+
+fn unexpected(command: NodeCommand, response: NodeResponse) -> ProtocolError {
+    ProtocolError::UnexpectedResponse { command, response }
+}
+
+impl NodeBackend for ReplayBackend {
+    fn open(&mut self) -> Result<(), ProtocolError> {
+        println!("Using replayed device {}.", self.device);
+        Ok(())
+    }
+
+    fn now(&self) -> Instant {
+        self.now
+    }
+
+    fn read_peak(&mut self) -> Result<Peak, ProtocolError> {
+        match self.exchange(NodeCommand::ReadPeak)? {
+            NodeResponse::Peak(peak) => Ok(peak),
+            response => Err(unexpected(NodeCommand::ReadPeak, response)),
+        }
+    }
+
+    fn read_time(&mut self) -> Result<u32, ProtocolError> {
+        match self.exchange(NodeCommand::ReadMillis)? {
+            NodeResponse::Millis(millis) => Ok(millis),
+            response => Err(unexpected(NodeCommand::ReadMillis, response)),
+        }
+    }
+
+    /// The request time is not recorded, so replayed reads have no round
+    /// trip. Sessions recorded without clock reads fall back to host times
+    /// instead of skipping ahead to some later clock read.
+    fn read_clock(&mut self) -> Result<ClockReading, ProtocolError> {
+        if !self.session.next_is(self.device, &NodeCommand::ReadMillis) {
+            return Err(ProtocolError::Timeout);
+        }
+        let millis = self.read_time()?;
+        Ok(ClockReading {
+            sent: self.now,
+            millis,
+            received: self.now,
+        })
+    }
+
+    fn read_version(&mut self) -> Result<u16, ProtocolError> {
+        match self.exchange(NodeCommand::ReadVersion)? {
+            NodeResponse::Version(version) => Ok(version),
+            response => Err(unexpected(NodeCommand::ReadVersion, response)),
+        }
+    }
+
+    fn read_slot_count(&mut self) -> Result<u8, ProtocolError> {
+        match self.exchange(NodeCommand::ReadSlotCount)? {
+            NodeResponse::SlotCount(count) => Ok(count),
+            response => Err(unexpected(NodeCommand::ReadSlotCount, response)),
+        }
+    }
+
+    fn select_slot(&mut self, slot: u8) -> Result<(), ProtocolError> {
+        self.exchange(NodeCommand::SelectSlot(slot))?;
+        Ok(())
+    }
+
+    fn read_frequency(&mut self) -> Result<u16, ProtocolError> {
+        match self.exchange(NodeCommand::ReadFrequency)? {
+            NodeResponse::Frequency(frequency) => Ok(frequency),
+            response => Err(unexpected(NodeCommand::ReadFrequency, response)),
+        }
+    }
+
+    fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError> {
+        self.exchange(NodeCommand::SetFrequency(frequency))?;
+        self.read_frequency()
+    }
+
+    /// Streams only if the recording did; otherwise the worker polls, as it
+    /// did while recording.
+    fn set_streaming(&mut self, on: bool) -> Result<(), ProtocolError> {
+        self.exchange(NodeCommand::SetStreaming(on))?;
+        Ok(())
+    }
+
+    fn read_stream(&mut self) -> Result<Vec<StreamFrame>, ProtocolError> {
+        let Some((at, frames)) = self.session.next_stream(self.device) else {
+            std::thread::sleep(EXHAUSTED_DELAY);
+            return Err(ProtocolError::Timeout);
+        };
+
+        std::thread::sleep(self.session.wait_until(at));
+        self.now = self.session.virtual_time(at);
+        Ok(frames)
+    }
+}
+
+/// Feeds the recorded race starts and stops to the worker once they are due.
+/// A start stages a race in its recorded format, or the configured one for
+/// recordings without it, and starts it without a countdown, the recording
+/// having marked when the race clock started. A stop also waits for the
+/// exchanges recorded before it to be replayed, so the race gets the same
+/// samples as the recording; a race its format already finished is left as
+/// it is.
+pub async fn drive(session: Arc<ReplaySession>, command_sender: mpsc::Sender<Command>) {
+    let mut formats = HashMap::new();
+    loop {
+        let Some((at, event)) = session.marks.lock().unwrap().pop_front() else {
+            println!("Replay: no more race events");
+            return;
+        };
+
+        tokio::time::sleep(session.wait_until(at)).await;
+
+        let result = match event {
+            Event::RaceStart { race_id } => {
+                let format = formats.remove(&race_id);
+                let staged = send(&command_sender, |respond_to| Command::StageRace {
+                    format,
+                    respond_to,
+                })
+                .await;
+                match staged {
+                    Some(Ok(status)) => {
+                        session.discard_before(at);
+                        println!(
+                            "Replay: race {} starts as race {}",
+                            race_id,
+                            status.race_id.unwrap_or_default()
+                        );
+                        send(&command_sender, |respond_to| Command::StartRace {
+                            time: session.virtual_time(at),
+                            countdown: Some(Duration::ZERO),
+                            respond_to,
+                        })
+                        .await
+                    }
+                    other => other,
+                }
+            }
+            Event::RaceStop { race_id } => {
+                let waiting_since = Instant::now();
+                while !session.caught_up(at) && waiting_since.elapsed() < CATCH_UP_TIMEOUT {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                let (response_sender, response_receiver) = oneshot::channel();
+                let command = Command::GetRace {
+                    respond_to: response_sender,
+                };
+                if command_sender.send(command).await.is_err() {
+                    return;
+                }
+                let Ok(race) = response_receiver.await else {
+                    return;
+                };
+                if matches!(race.state, RaceState::Finished | RaceState::Aborted) {
+                    println!("Replay: race {} already finished", race_id);
+                    continue;
+                }
+                println!("Replay: race {} stops", race_id);
+                send(&command_sender, |respond_to| Command::StopRace {
+                    time: session.virtual_time(at),
+                    respond_to,
+                })
+                .await
+            }
+            Event::RaceFormat { race_id, format } => {
+                formats.insert(race_id, format);
+                continue;
+            }
+            Event::Exchange(_) | Event::Stream { .. } => continue,
+        };
+        match result {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => eprintln!("Replay: {}", e),
+            None => return,
+        }
+    }
+}
+
+/// Send a race command and wait for its outcome; `None` once the worker is
+/// gone.
+async fn send(
+    command_sender: &mpsc::Sender<Command>,
+    command: impl FnOnce(oneshot::Sender<Result<RaceStatus, String>>) -> Command,
+) -> Option<Result<RaceStatus, String>> {
+    let (response_sender, response_receiver) = oneshot::channel();
+    command_sender.send(command(response_sender)).await.ok()?;
+    response_receiver.await.ok()
+}
@@ -0,0 +1,153 @@
+use super::NodeBackend;
+use crate::config::NodeConfig;
+use rustimer::discovery;
+use rustimer::protocol::{self, Peak, ProtocolError};
+use rustimer::stream::{self, StreamDecoder, StreamFrame, StreamStats};
+use serialport::{ClearBuffer, SerialPort};
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+/// Time for frames already sent to arrive after the stream is stopped, before
+/// the input is discarded.
+const STREAM_SETTLE: Duration = Duration::from_millis(20);
+
+/// Arduino node on a USB serial port.
+pub struct SerialBackend {
+    port_name: String,
+    serial_number: Option<String>,
+    baud_rate: u32,
+    port: Option<Box<dyn SerialPort>>,
+    /// Set while the node streams.
+    stream: Option<StreamDecoder>,
+}
+
+impl SerialBackend {
+    pub fn new(node_config: &NodeConfig) -> Self {
+        SerialBackend {
+            port_name: node_config.port.clone(),
+            serial_number: node_config.serial_number.clone(),
+            baud_rate: node_config.baud_rate,
+            port: None,
+            stream: None,
+        }
+    }
+
+    /// Find the port the device is plugged into now, when it is bound by USB
+    /// serial number.
+    fn resolve_port(&mut self) -> Result<(), ProtocolError> {
+        let Some(serial_number) = &self.serial_number else {
+            return Ok(());
+        };
+        let candidate = discovery::find_by_serial_number(serial_number)
+            .map_err(io::Error::from)?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no USB device with serial number {}", serial_number),
+                )
+            })?;
+        if candidate.port_name != self.port_name {
+            println!("Device {} found on {}", serial_number, candidate.port_name);
+            self.port_name = candidate.port_name;
+        }
+        Ok(())
+    }
+
+    fn port(&mut self) -> Result<&mut Box<dyn SerialPort>, ProtocolError> {
+        self.port.as_mut().ok_or_else(not_open)
+    }
+
+    /// Run a request on the port. Answers cannot be told apart from stream
+    /// frames, so a running stream is stopped around it and picks up afresh.
+    fn request<T>(
+        &mut self,
+        request: impl FnOnce(&mut Box<dyn SerialPort>) -> Result<T, ProtocolError>,
+    ) -> Result<T, ProtocolError> {
+        let Some(mut decoder) = self.stream.take() else {
+            return request(self.port()?);
+        };
+        let port = self.port()?;
+        stop_stream(port)?;
+        let result = request(port);
+        protocol::set_streaming(port, true)?;
+        decoder.resync();
+        self.stream = Some(decoder);
+        result
+    }
+}
+
+fn not_open() -> ProtocolError {
+    ProtocolError::Io(io::Error::new(
+        io::ErrorKind::NotConnected,
+        "serial port is not open",
+    ))
+}
+
+fn stop_stream(port: &mut Box<dyn SerialPort>) -> Result<(), ProtocolError> {
+    protocol::set_streaming(port, false)?;
+    thread::sleep(STREAM_SETTLE);
+    port.clear(ClearBuffer::Input).map_err(io::Error::from)?;
+    Ok(())
+}
+
+impl NodeBackend for SerialBackend {
+    fn open(&mut self) -> Result<(), ProtocolError> {
+        self.port = None;
+        self.stream = None;
+        self.resolve_port()?;
+        println!("Opening real Arduino port: {}", self.port_name);
+        self.port = Some(discovery::open_port(&self.port_name, self.baud_rate)?);
+        Ok(())
+    }
+
+    fn read_peak(&mut self) -> Result<Peak, ProtocolError> {
+        self.request(protocol::read_peak)
+    }
+
+    fn read_time(&mut self) -> Result<u32, ProtocolError> {
+        self.request(protocol::read_millis)
+    }
+
+    fn read_version(&mut self) -> Result<u16, ProtocolError> {
+        self.request(protocol::read_version)
+    }
+
+    fn read_slot_count(&mut self) -> Result<u8, ProtocolError> {
+        self.request(protocol::read_slot_count)
+    }
+
+    fn select_slot(&mut self, slot: u8) -> Result<(), ProtocolError> {
+        self.request(|port| protocol::select_slot(port, slot))
+    }
+
+    fn read_frequency(&mut self) -> Result<u16, ProtocolError> {
+        self.request(protocol::read_frequency)
+    }
+
+    fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError> {
+        self.request(|port| protocol::set_frequency(port, frequency))
+    }
+
+    fn set_streaming(&mut self, on: bool) -> Result<(), ProtocolError> {
+        let port = self.port()?;
+        if on {
+            protocol::set_streaming(port, true)?;
+            self.stream.get_or_insert_with(StreamDecoder::new);
+        } else {
+            stop_stream(port)?;
+            self.stream = None;
+        }
+        Ok(())
+    }
+
+    fn read_stream(&mut self) -> Result<Vec<StreamFrame>, ProtocolError> {
+        let port = self.port.as_mut().ok_or_else(not_open)?;
+        let decoder = self.stream.as_mut().ok_or_else(super::not_streaming)?;
+        stream::read_frames(port, decoder)
+    }
+
+    fn stream_stats(&self) -> Option<StreamStats> {
+        self.stream.as_ref().map(StreamDecoder::stats)
+    }
+}
@@ -0,0 +1,507 @@
+//! Compact binary format for recorded node sessions.
+//!
+//! A file starts with `RTRS` and a version byte, followed by records. Every
+//! record starts with a tag byte and the microseconds elapsed since the
+//! previous record as a LEB128 varint:
+//!
+//! - `0` exchange: device, request length and bytes, status, response length
+//!   and bytes. Requests and responses are the protocol wire bytes.
+//! - `1` race start / `2` race stop: race id as a varint.
+//! - `3` stream: device, frame count as a varint and the frames as pushed
+//!   on the wire, see [`rustimer::stream`].
+//! - `4` race format: race id as a varint, length and bytes of the format as
+//!   JSON; recorded along with every race start.
+
+use crate::race_format::RaceFormat;
+use rustimer::protocol::ProtocolError;
+use rustimer::stream::{StreamDecoder, StreamFrame, FRAME_LEN};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const MAGIC: &[u8; 4] = b"RTRS";
+/// Version written; files of version 1 have no stream or race format
+/// records and read the same.
+const VERSION: u8 = 2;
+
+const TAG_EXCHANGE: u8 = 0;
+const TAG_RACE_START: u8 = 1;
+const TAG_RACE_STOP: u8 = 2;
+const TAG_STREAM: u8 = 3;
+const TAG_RACE_FORMAT: u8 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_TIMEOUT: u8 = 1;
+const STATUS_SHORT_READ: u8 = 2;
+const STATUS_CHECKSUM: u8 = 3;
+const STATUS_IO: u8 = 4;
+
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub device: u8,
+    pub request: Vec<u8>,
+    /// Response frame, or the error the exchange ended with.
+    pub response: Result<Vec<u8>, RecordedError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordedError {
+    Timeout,
+    ShortRead { expected: u8, received: u8 },
+    Checksum { expected: u8, received: u8 },
+    Io,
+}
+
+impl RecordedError {
+    pub fn from_protocol(error: &ProtocolError) -> Self {
+        match error {
+            ProtocolError::Timeout => RecordedError::Timeout,
+            ProtocolError::ShortRead { expected, received } => RecordedError::ShortRead {
+                expected: *expected as u8,
+                received: *received as u8,
+            },
+            ProtocolError::Checksum { expected, received } => RecordedError::Checksum {
+                expected: *expected,
+                received: *received,
+            },
+            _ => RecordedError::Io,
+        }
+    }
+
+    pub fn to_protocol(self) -> ProtocolError {
+        match self {
+            RecordedError::Timeout => ProtocolError::Timeout,
+            RecordedError::ShortRead { expected, received } => ProtocolError::ShortRead {
+                expected: expected.into(),
+                received: received.into(),
+            },
+            RecordedError::Checksum { expected, received } => {
+                ProtocolError::Checksum { expected, received }
+            }
+            RecordedError::Io => ProtocolError::Io(io::Error::other("recorded I/O error")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Exchange(Exchange),
+    RaceStart {
+        race_id: i32,
+    },
+    RaceStop {
+        race_id: i32,
+    },
+    /// Frames a streaming device pushed, as one read returned them.
+    Stream {
+        device: u8,
+        frames: Vec<StreamFrame>,
+    },
+    RaceFormat {
+        race_id: i32,
+        format: RaceFormat,
+    },
+}
+
+/// An event with its offset from the start of the recording.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub at: Duration,
+    pub event: Event,
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(reader)?;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint too long",
+    ))
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Write `bytes` behind a one-byte length; longer fields cannot be recorded.
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let len = u8::try_from(bytes.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("record field of {} bytes, at most 255 fit", bytes.len()),
+        )
+    })?;
+    writer.write_all(&[len])?;
+    writer.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; read_u8(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub struct SessionWriter {
+    writer: BufWriter<File>,
+    last: Duration,
+}
+
+impl SessionWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.flush()?;
+        Ok(SessionWriter {
+            writer,
+            last: Duration::ZERO,
+        })
+    }
+
+    /// Append a record. Records must be written in time order; every record is
+    /// flushed so a session survives the server being killed. A record that
+    /// cannot be encoded is refused whole, leaving the file readable.
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let delta = record.at.saturating_sub(self.last);
+
+        let mut w = Vec::new();
+        match &record.event {
+            Event::Exchange(exchange) => {
+                w.push(TAG_EXCHANGE);
+                write_varint(&mut w, delta.as_micros() as u64)?;
+                w.push(exchange.device);
+                write_bytes(&mut w, &exchange.request)?;
+                let (status, response) = match &exchange.response {
+                    Ok(frame) => (STATUS_OK, frame.clone()),
+                    Err(RecordedError::Timeout) => (STATUS_TIMEOUT, Vec::new()),
+                    Err(RecordedError::ShortRead { expected, received }) => {
+                        (STATUS_SHORT_READ, vec![*expected, *received])
+                    }
+                    Err(RecordedError::Checksum { expected, received }) => {
+                        (STATUS_CHECKSUM, vec![*expected, *received])
+                    }
+                    Err(RecordedError::Io) => (STATUS_IO, Vec::new()),
+                };
+                w.push(status);
+                write_bytes(&mut w, &response)?;
+            }
+            Event::RaceStart { race_id } | Event::RaceStop { race_id } => {
+                let tag = match record.event {
+                    Event::RaceStart { .. } => TAG_RACE_START,
+                    _ => TAG_RACE_STOP,
+                };
+                w.push(tag);
+                write_varint(&mut w, delta.as_micros() as u64)?;
+                write_varint(&mut w, *race_id as u64)?;
+            }
+            Event::Stream { device, frames } => {
+                w.push(TAG_STREAM);
+                write_varint(&mut w, delta.as_micros() as u64)?;
+                w.push(*device);
+                write_varint(&mut w, frames.len() as u64)?;
+                for frame in frames {
+                    w.extend_from_slice(&frame.encode());
+                }
+            }
+            Event::RaceFormat { race_id, format } => {
+                let json = serde_json::to_vec(format).map_err(io::Error::other)?;
+                w.push(TAG_RACE_FORMAT);
+                write_varint(&mut w, delta.as_micros() as u64)?;
+                write_varint(&mut w, *race_id as u64)?;
+                write_bytes(&mut w, &json)?;
+            }
+        }
+        self.writer.write_all(&w)?;
+        self.last = self.last.max(record.at);
+        self.writer.flush()
+    }
+}
+
+/// Read a whole session. A truncated last record (e.g. from a crash while
+/// writing) is ignored.
+pub fn read_session(path: &Path) -> io::Result<Vec<Record>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || !(1..=VERSION).contains(&read_u8(&mut reader)?) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a rustimer session file",
+        ));
+    }
+
+    let mut records = Vec::new();
+    let mut at = Duration::ZERO;
+    loop {
+        let tag = match read_u8(&mut reader) {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        match read_record(&mut reader, tag, &mut at) {
+            Ok(record) => records.push(record),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(records)
+}
+
+fn read_record<R: Read>(reader: &mut R, tag: u8, at: &mut Duration) -> io::Result<Record> {
+    *at += Duration::from_micros(read_varint(reader)?);
+    let event = match tag {
+        TAG_EXCHANGE => {
+            let device = read_u8(reader)?;
+            let request = read_bytes(reader)?;
+            let status = read_u8(reader)?;
+            let response = read_bytes(reader)?;
+            let response = match status {
+                STATUS_OK => Ok(response),
+                STATUS_TIMEOUT => Err(RecordedError::Timeout),
+                STATUS_SHORT_READ if response.len() == 2 => Err(RecordedError::ShortRead {
+                    expected: response[0],
+                    received: response[1],
+                }),
+                STATUS_CHECKSUM if response.len() == 2 => Err(RecordedError::Checksum {
+                    expected: response[0],
+                    received: response[1],
+                }),
+                _ => Err(RecordedError::Io),
+            };
+            Event::Exchange(Exchange {
+                device,
+                request,
+                response,
+            })
+        }
+        TAG_RACE_START => Event::RaceStart {
+            race_id: read_varint(reader)? as i32,
+        },
+        TAG_RACE_STOP => Event::RaceStop {
+            race_id: read_varint(reader)? as i32,
+        },
+        TAG_STREAM => {
+            let device = read_u8(reader)?;
+            let count = read_varint(reader)? as usize;
+            let mut bytes = vec![0u8; count * FRAME_LEN];
+            reader.read_exact(&mut bytes)?;
+            let frames = StreamDecoder::new().push(&bytes);
+            if frames.len() != count {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupt stream record",
+                ));
+            }
+            Event::Stream { device, frames }
+        }
+        TAG_RACE_FORMAT => {
+            let race_id = read_varint(reader)? as i32;
+            let format = serde_json::from_slice(&read_bytes(reader)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Event::RaceFormat { race_id, format }
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown record tag {}", tag),
+            ))
+        }
+    };
+    Ok(Record { at: *at, event })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustimer::protocol::Peak;
+    use std::path::PathBuf;
+
+    /// Session file of its own for each test.
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "rustimer-session-{}-{}.bin",
+            std::process::id(),
+            name
+        ))
+    }
+
+    fn record(at_ms: u64, event: Event) -> Record {
+        Record {
+            at: Duration::from_millis(at_ms),
+            event,
+        }
+    }
+
+    fn exchange(request: Vec<u8>, response: Result<Vec<u8>, RecordedError>) -> Event {
+        Event::Exchange(Exchange {
+            device: 1,
+            request,
+            response,
+        })
+    }
+
+    fn write(path: &Path, records: &[Record]) {
+        let mut writer = SessionWriter::create(path).unwrap();
+        for record in records {
+            writer.write(record).unwrap();
+        }
+    }
+
+    /// Records as text, since they do not compare.
+    fn debug(records: &[Record]) -> Vec<String> {
+        records
+            .iter()
+            .map(|record| format!("{:?}", record))
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_every_record() {
+        let frame = |seq: u8, millis: u32| StreamFrame {
+            seq,
+            slot: 1,
+            millis,
+            peak: Peak {
+                lap_id: 2,
+                ms_val: 1500,
+                rssi: 90,
+            },
+        };
+        let records = [
+            record(0, exchange(vec![0x10], Ok(vec![1, 2, 3]))),
+            record(5, exchange(vec![0x11], Err(RecordedError::Timeout))),
+            record(
+                6,
+                exchange(
+                    vec![0x12],
+                    Err(RecordedError::ShortRead {
+                        expected: 4,
+                        received: 1,
+                    }),
+                ),
+            ),
+            record(
+                7,
+                exchange(
+                    vec![0x13],
+                    Err(RecordedError::Checksum {
+                        expected: 9,
+                        received: 8,
+                    }),
+                ),
+            ),
+            record(8, exchange(vec![0x14], Err(RecordedError::Io))),
+            record(
+                1000,
+                Event::RaceFormat {
+                    race_id: 3,
+                    format: RaceFormat::Timed {
+                        duration_s: 120.0,
+                        grace_s: 30.0,
+                    },
+                },
+            ),
+            record(1000, Event::RaceStart { race_id: 3 }),
+            record(
+                1200,
+                Event::Stream {
+                    device: 0,
+                    frames: vec![frame(0, 40_000), frame(1, 40_004)],
+                },
+            ),
+            record(90_000, Event::RaceStop { race_id: 3 }),
+        ];
+        let path = path("round-trip");
+        write(&path, &records);
+        let read = read_session(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(debug(&read), debug(&records));
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let path = path("version-1");
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(1);
+        // Exchange 2 ms in, then a race start.
+        bytes.extend([TAG_EXCHANGE, 0xD0, 0x0F, 0, 1, 0x10, STATUS_OK, 2, 7, 8]);
+        bytes.extend([TAG_RACE_START, 0, 4]);
+        std::fs::write(&path, bytes).unwrap();
+        let read = read_session(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let expected = [
+            Record {
+                at: Duration::from_millis(2),
+                event: Event::Exchange(Exchange {
+                    device: 0,
+                    request: vec![0x10],
+                    response: Ok(vec![7, 8]),
+                }),
+            },
+            record(2, Event::RaceStart { race_id: 4 }),
+        ];
+        assert_eq!(debug(&read), debug(&expected));
+    }
+
+    #[test]
+    fn ignores_a_truncated_last_record() {
+        let records = [
+            record(0, Event::RaceStart { race_id: 1 }),
+            record(10, exchange(vec![0x10], Ok(vec![1, 2, 3]))),
+        ];
+        let path = path("truncated");
+        write(&path, &records);
+        let full = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &full[..full.len() - 2]).unwrap();
+        let read = read_session(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(debug(&read), debug(&records[..1]));
+    }
+
+    #[test]
+    fn refuses_fields_too_long_to_record() {
+        let path = path("too-long");
+        let mut writer = SessionWriter::create(&path).unwrap();
+        let refused = writer.write(&record(0, exchange(vec![0; 256], Ok(Vec::new()))));
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let refused = writer.write(&record(0, exchange(vec![0x10], Ok(vec![0; 300]))));
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let longest = record(5, exchange(vec![0; 255], Ok(vec![1; 255])));
+        writer.write(&longest).unwrap();
+        drop(writer);
+
+        let read = read_session(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(debug(&read), debug(&[longest]));
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        let path = path("other");
+        std::fs::write(&path, b"RTRS\x09").unwrap();
+        let refused = read_session(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
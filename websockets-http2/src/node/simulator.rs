@@ -0,0 +1,397 @@
+use super::NodeBackend;
+use crate::config::{PilotProfile, SimulatorConfig};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rustimer::firmware;
+use rustimer::protocol::{Peak, ProtocolError};
+use rustimer::stream::{StreamDecoder, StreamFrame, StreamStats};
+use std::time::{Duration, Instant};
+
+const SLOT_COUNT: u8 = 8;
+/// Passes further than this many widths from `t` no longer affect the RSSI.
+const PASS_REACH: f64 = 5.0;
+/// Stream samples a read catches up on at most; older ones are skipped, as a
+/// full serial buffer would lose them.
+const MAX_STREAM_BACKLOG: f64 = 1.0;
+
+/// One gate pass: a bell curve centred on `time`, optionally notched by a
+/// multipath dip.
+struct Pass {
+    time: f64,
+    amplitude: f64,
+    width: f64,
+    dip: Option<Dip>,
+}
+
+struct Dip {
+    offset: f64,
+    depth: f64,
+    width: f64,
+}
+
+impl Pass {
+    fn rssi(&self, t: f64) -> f64 {
+        let bell = (-(t - self.time).powi(2) / (2.0 * self.width.powi(2))).exp();
+        let notch = match &self.dip {
+            Some(dip) => {
+                let d = t - (self.time + dip.offset);
+                1.0 - dip.depth * (-d.powi(2) / (2.0 * dip.width.powi(2))).exp()
+            }
+            None => 1.0,
+        };
+        self.amplitude * bell * notch
+    }
+}
+
+struct Pilot {
+    profile: PilotProfile,
+    frequency: u16,
+    passes: Vec<Pass>,
+    /// Upcoming gate pass, `None` once the pilot is out of the race.
+    next_pass: Option<Pass>,
+    lap_id: u8,
+    last_lap_at: f64,
+    counted: usize,
+}
+
+impl Pilot {
+    fn new(profile: PilotProfile, noise_floor: f64, rng: &mut StdRng) -> Self {
+        let first_pass = draw_pass(&profile, draw_lap(&profile, rng), noise_floor, rng);
+        Pilot {
+            profile,
+            frequency: 5658,
+            passes: Vec::new(),
+            next_pass: Some(first_pass),
+            lap_id: 0,
+            last_lap_at: 0.0,
+            counted: 0,
+        }
+    }
+
+    /// Schedule the passes that start to matter at `t` and count the laps
+    /// whose peak is behind us.
+    fn advance(&mut self, t: f64, noise_floor: f64, rng: &mut StdRng) {
+        while let Some(pass) = self.next_pass.take() {
+            if pass.time - PASS_REACH * pass.width > t {
+                self.next_pass = Some(pass);
+                break;
+            }
+
+            if !rng.gen_bool(self.profile.dnf_probability.clamp(0.0, 1.0)) {
+                let mut lap = draw_lap(&self.profile, rng);
+                if rng.gen_bool(self.profile.crash_probability.clamp(0.0, 1.0)) {
+                    let (min, max) = self.profile.crash_delay_s;
+                    lap += rng.gen_range(min..=max.max(min));
+                }
+                self.next_pass = Some(draw_pass(&self.profile, pass.time + lap, noise_floor, rng));
+            }
+            self.passes.push(pass);
+        }
+
+        while self.counted < self.passes.len() && self.passes[self.counted].time <= t {
+            self.lap_id = self.lap_id.wrapping_add(1);
+            self.last_lap_at = self.passes[self.counted].time;
+            self.counted += 1;
+        }
+
+        let before = self.passes.len();
+        self.passes
+            .retain(|pass| t - pass.time < PASS_REACH * pass.width);
+        self.counted -= before - self.passes.len();
+    }
+
+    fn rssi(&self, t: f64) -> f64 {
+        self.passes.iter().map(|pass| pass.rssi(t)).sum()
+    }
+}
+
+fn draw_pass(profile: &PilotProfile, time: f64, noise_floor: f64, rng: &mut StdRng) -> Pass {
+    let width = profile.pass_width_s * rng.gen_range(0.8..1.2);
+    let amplitude = (profile.peak - noise_floor).max(0.0) * rng.gen_range(0.85..1.0);
+    let dip = rng
+        .gen_bool(profile.multipath_probability.clamp(0.0, 1.0))
+        .then(|| Dip {
+            offset: rng.gen_range(-1.0..1.0) * width,
+            depth: rng.gen_range(0.2..0.6),
+            width: width * 0.2,
+        });
+    Pass {
+        time,
+        amplitude,
+        width,
+        dip,
+    }
+}
+
+/// Normally distributed lap time, kept to at least half the mean so a wide
+/// deviation cannot produce absurdly short laps.
+fn draw_lap(profile: &PilotProfile, rng: &mut StdRng) -> f64 {
+    let lap = profile.lap_mean_s + profile.lap_stddev_s * standard_normal(rng);
+    lap.max(profile.lap_mean_s * 0.5)
+}
+
+/// Box-Muller transform.
+fn standard_normal(rng: &mut StdRng) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Generates lap-shaped RSSI: a noisy floor with bell-shaped gate passes at
+/// intervals drawn from each pilot's lap-time distribution, including crashes,
+/// DNFs and multipath dips.
+pub struct SimulatorBackend {
+    config: SimulatorConfig,
+    rng: StdRng,
+    opened_at: Instant,
+    slot: usize,
+    pilots: Vec<Pilot>,
+    stream: Option<SimulatedStream>,
+}
+
+/// Stream state: frames go through a real decoder so the counters behave as
+/// they would on the wire.
+struct SimulatedStream {
+    decoder: StreamDecoder,
+    seq: u8,
+    /// Time of the next sample, in seconds since open.
+    next_at: f64,
+}
+
+impl SimulatorBackend {
+    pub fn new(config: &SimulatorConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        SimulatorBackend {
+            config: config.clone(),
+            rng,
+            opened_at: Instant::now(),
+            slot: 0,
+            pilots: Vec::new(),
+            stream: None,
+        }
+    }
+
+    fn pilot(&mut self) -> &mut Pilot {
+        &mut self.pilots[self.slot]
+    }
+
+    /// RSSI of `slot` at `t` seconds since open.
+    fn sample(&mut self, slot: usize, t: f64) -> Peak {
+        let noise_floor = self.config.noise_floor;
+        let noise = self.config.noise * standard_normal(&mut self.rng);
+        let pilot = &mut self.pilots[slot];
+        pilot.advance(t, noise_floor, &mut self.rng);
+        let rssi = noise_floor + noise + pilot.rssi(t);
+        Peak {
+            lap_id: pilot.lap_id,
+            ms_val: ((t - pilot.last_lap_at) * 1000.0).min(u16::MAX as f64) as u16,
+            rssi: rssi.round().clamp(0.0, 255.0) as u8,
+        }
+    }
+}
+
+impl NodeBackend for SimulatorBackend {
+    fn open(&mut self) -> Result<(), ProtocolError> {
+        println!("Using simulated node.");
+        self.opened_at = Instant::now();
+        self.stream = None;
+        self.pilots = (0..SLOT_COUNT as usize)
+            .map(|slot| {
+                let profile = self.config.pilots.get(slot).cloned().unwrap_or_default();
+                Pilot::new(profile, self.config.noise_floor, &mut self.rng)
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn read_peak(&mut self) -> Result<Peak, ProtocolError> {
+        std::thread::sleep(Duration::from_millis(self.config.sample_interval_ms));
+
+        let t = self.opened_at.elapsed().as_secs_f64();
+        Ok(self.sample(self.slot, t))
+    }
+
+    fn read_time(&mut self) -> Result<u32, ProtocolError> {
+        Ok(self.opened_at.elapsed().as_millis() as u32)
+    }
+
+    fn read_version(&mut self) -> Result<u16, ProtocolError> {
+        Ok(firmware::LATEST_VERSION)
+    }
+
+    fn read_slot_count(&mut self) -> Result<u8, ProtocolError> {
+        Ok(SLOT_COUNT)
+    }
+
+    fn select_slot(&mut self, slot: u8) -> Result<(), ProtocolError> {
+        self.slot = usize::from(slot.min(SLOT_COUNT - 1));
+        Ok(())
+    }
+
+    fn read_frequency(&mut self) -> Result<u16, ProtocolError> {
+        Ok(self.pilot().frequency)
+    }
+
+    fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError> {
+        self.pilot().frequency = frequency;
+        Ok(frequency)
+    }
+
+    fn set_streaming(&mut self, on: bool) -> Result<(), ProtocolError> {
+        self.stream = on.then(|| SimulatedStream {
+            decoder: StreamDecoder::new(),
+            seq: 0,
+            next_at: self.opened_at.elapsed().as_secs_f64(),
+        });
+        Ok(())
+    }
+
+    /// Every slot is sampled each `sample_interval_ms`, and the samples due
+    /// since the last read are pushed at once.
+    fn read_stream(&mut self) -> Result<Vec<StreamFrame>, ProtocolError> {
+        let Some(mut stream) = self.stream.take() else {
+            return Err(super::not_streaming());
+        };
+        let interval = self.config.sample_interval_ms.max(1) as f64 / 1000.0;
+        let now = self.opened_at.elapsed().as_secs_f64();
+        if stream.next_at > now {
+            std::thread::sleep(Duration::from_secs_f64(stream.next_at - now));
+        }
+        let now = self.opened_at.elapsed().as_secs_f64();
+        while stream.next_at < now - MAX_STREAM_BACKLOG {
+            stream.next_at += interval;
+            stream.seq = stream.seq.wrapping_add(SLOT_COUNT);
+        }
+
+        let mut bytes = Vec::new();
+        while stream.next_at <= now {
+            for slot in 0..SLOT_COUNT {
+                let frame = StreamFrame {
+                    seq: stream.seq,
+                    slot,
+                    millis: (stream.next_at * 1000.0) as u32,
+                    peak: self.sample(usize::from(slot), stream.next_at),
+                };
+                bytes.extend_from_slice(&frame.encode());
+                stream.seq = stream.seq.wrapping_add(1);
+            }
+            stream.next_at += interval;
+        }
+        let frames = stream.decoder.push(&bytes);
+        self.stream = Some(stream);
+        Ok(frames)
+    }
+
+    fn stream_stats(&self) -> Option<StreamStats> {
+        self.stream.as_ref().map(|stream| stream.decoder.stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> PilotProfile {
+        PilotProfile {
+            lap_mean_s: 10.0,
+            lap_stddev_s: 0.0,
+            peak: 120.0,
+            pass_width_s: 0.2,
+            crash_probability: 0.0,
+            crash_delay_s: (5.0, 5.0),
+            dnf_probability: 0.0,
+            multipath_probability: 0.0,
+        }
+    }
+
+    fn simulator(profile: PilotProfile) -> SimulatorBackend {
+        let config = SimulatorConfig {
+            noise_floor: 40.0,
+            noise: 0.0,
+            sample_interval_ms: 0,
+            seed: Some(7),
+            pilots: vec![profile],
+        };
+        let mut simulator = SimulatorBackend::new(&config);
+        simulator.open().unwrap();
+        simulator
+    }
+
+    /// Samples of slot 0 every 10 ms for `seconds`, with their time.
+    fn run(simulator: &mut SimulatorBackend, seconds: f64) -> Vec<(f64, Peak)> {
+        (0..(seconds * 100.0) as usize)
+            .map(|step| {
+                let t = step as f64 / 100.0;
+                (t, simulator.sample(0, t))
+            })
+            .collect()
+    }
+
+    /// Times the lap counter moved on.
+    fn laps(samples: &[(f64, Peak)]) -> Vec<f64> {
+        samples
+            .windows(2)
+            .filter(|pair| pair[1].1.lap_id != pair[0].1.lap_id)
+            .map(|pair| pair[1].0)
+            .collect()
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() <= 0.011, "{:?}", actual);
+        }
+    }
+
+    #[test]
+    fn passes_the_gate_every_lap() {
+        let mut simulator = simulator(profile());
+        let samples = run(&mut simulator, 35.0);
+        assert_close(&laps(&samples), &[10.0, 20.0, 30.0]);
+
+        for (t, peak) in &samples {
+            let to_pass = (t - (t / 10.0).round() * 10.0).abs();
+            if *t > 5.0 && to_pass < 0.005 {
+                // Between 85% and all of the peak above the floor.
+                assert!((108..=120).contains(&peak.rssi), "{} at {}", peak.rssi, t);
+            } else if to_pass > 1.5 {
+                assert_eq!(peak.rssi, 40, "at {}", t);
+            }
+        }
+        let (_, peak) = samples.iter().find(|(t, _)| *t >= 12.0).unwrap();
+        assert_eq!((peak.lap_id, peak.ms_val), (1, 2000));
+    }
+
+    #[test]
+    fn crashes_add_time_and_dnfs_end_the_race() {
+        let crashing = PilotProfile {
+            crash_probability: 1.0,
+            ..profile()
+        };
+        let samples = run(&mut simulator(crashing), 45.0);
+        assert_close(&laps(&samples), &[10.0, 25.0, 40.0]);
+
+        let retiring = PilotProfile {
+            dnf_probability: 1.0,
+            ..profile()
+        };
+        let samples = run(&mut simulator(retiring), 45.0);
+        assert_close(&laps(&samples), &[10.0]);
+    }
+
+    #[test]
+    fn repeats_a_seeded_run() {
+        let varied = PilotProfile {
+            lap_stddev_s: 2.0,
+            multipath_probability: 0.5,
+            ..profile()
+        };
+        let first = run(&mut simulator(varied.clone()), 60.0);
+        let second = run(&mut simulator(varied), 60.0);
+        assert_eq!(first, second);
+        assert!(laps(&first).len() >= 4);
+    }
+}
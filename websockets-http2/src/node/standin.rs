@@ -0,0 +1,50 @@
+use super::NodeBackend;
+use crate::config::{Config, NodeConfig, StandinConfig};
+use rustimer::protocol::{NodeCommand, NodeResponse, ProtocolError};
+use rustimer::standin;
+use std::io;
+use std::net::TcpListener;
+use std::thread;
+
+/// Serve the configured backend as a network node on a thread of its own.
+pub fn spawn(config: &Config, standin: &StandinConfig) -> io::Result<()> {
+    let listener = TcpListener::bind(&standin.listen)?;
+    let node_config = NodeConfig {
+        backend: Some(standin.backend),
+        ..NodeConfig::new(&standin.listen)
+    };
+    let mut backend = super::create_backend(config, &node_config);
+    backend.open().map_err(io::Error::other)?;
+    println!("Stand-in node listening on {}", listener.local_addr()?);
+
+    thread::spawn(move || {
+        if let Err(e) = standin::serve(listener, |command| answer(backend.as_mut(), command)) {
+            eprintln!("Stand-in node stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Run a command against the backend as a node would.
+fn answer(
+    backend: &mut dyn NodeBackend,
+    command: NodeCommand,
+) -> Result<NodeResponse, ProtocolError> {
+    Ok(match command {
+        NodeCommand::ReadPeak => NodeResponse::Peak(backend.read_peak()?),
+        NodeCommand::ReadMillis => NodeResponse::Millis(backend.read_time()?),
+        NodeCommand::ReadVersion => NodeResponse::Version(backend.read_version()?),
+        NodeCommand::ReadSlotCount => NodeResponse::SlotCount(backend.read_slot_count()?),
+        NodeCommand::SelectSlot(slot) => {
+            backend.select_slot(slot)?;
+            NodeResponse::Ack
+        }
+        NodeCommand::ReadFrequency => NodeResponse::Frequency(backend.read_frequency()?),
+        NodeCommand::SetFrequency(frequency) => {
+            backend.set_frequency(frequency)?;
+            NodeResponse::Ack
+        }
+        // Answers only; a stream would need frames pushed between requests.
+        NodeCommand::SetStreaming(_) => return Err(ProtocolError::UnknownCommand(command.code())),
+    })
+}
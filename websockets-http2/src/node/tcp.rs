@@ -0,0 +1,196 @@
+use super::network::{self, Latency};
+use super::NodeBackend;
+use crate::config::NodeConfig;
+use rustimer::protocol::{self, Peak, ProtocolError};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Network node speaking the serial protocol over a TCP connection.
+pub struct TcpBackend {
+    address: String,
+    timeout: Duration,
+    connection: Option<Connection>,
+    latency: Latency,
+}
+
+/// TCP stream on which the node closing the connection is an error. A closed
+/// stream reads as zero bytes, which the protocol would take for a timeout and
+/// keep retrying instead of reconnecting.
+struct Connection(TcpStream);
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf)? {
+            0 if !buf.is_empty() => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "node closed the connection",
+            )),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl TcpBackend {
+    pub fn new(node_config: &NodeConfig) -> Self {
+        TcpBackend {
+            address: node_config.port.clone(),
+            timeout: Duration::from_millis(node_config.timeout_ms),
+            connection: None,
+            latency: Latency::default(),
+        }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for address in network::resolve(&self.address)? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(network::not_connected))
+    }
+
+    /// Run one exchange on the connection and time it.
+    fn exchange<T>(
+        &mut self,
+        exchange: impl FnOnce(&mut Connection) -> Result<T, ProtocolError>,
+    ) -> Result<T, ProtocolError> {
+        let connection = self
+            .connection
+            .as_mut()
+            .ok_or_else(network::not_connected)?;
+        self.latency.measure(|| exchange(connection))
+    }
+}
+
+impl NodeBackend for TcpBackend {
+    fn open(&mut self) -> Result<(), ProtocolError> {
+        self.connection = None;
+        println!("Connecting to network node {}", self.address);
+        let stream = self.connect()?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        self.connection = Some(Connection(stream));
+        Ok(())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.latency.get()
+    }
+
+    fn read_peak(&mut self) -> Result<Peak, ProtocolError> {
+        self.exchange(protocol::read_peak)
+    }
+
+    fn read_time(&mut self) -> Result<u32, ProtocolError> {
+        self.exchange(protocol::read_millis)
+    }
+
+    fn read_version(&mut self) -> Result<u16, ProtocolError> {
+        self.exchange(protocol::read_version)
+    }
+
+    fn read_slot_count(&mut self) -> Result<u8, ProtocolError> {
+        self.exchange(protocol::read_slot_count)
+    }
+
+    /// Not timed: the node does not answer it.
+    fn select_slot(&mut self, slot: u8) -> Result<(), ProtocolError> {
+        let connection = self
+            .connection
+            .as_mut()
+            .ok_or_else(network::not_connected)?;
+        protocol::select_slot(connection, slot)
+    }
+
+    fn read_frequency(&mut self) -> Result<u16, ProtocolError> {
+        self.exchange(protocol::read_frequency)
+    }
+
+    fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError> {
+        self.exchange(|connection| protocol::set_frequency(connection, frequency))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustimer::firmware::LATEST_VERSION;
+    use rustimer::protocol::{NodeCommand, NodeResponse};
+    use rustimer::standin;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const PEAK: Peak = Peak {
+        lap_id: 2,
+        ms_val: 1500,
+        rssi: 80,
+    };
+
+    fn backend(listener: &TcpListener) -> TcpBackend {
+        let config = NodeConfig {
+            timeout_ms: 200,
+            ..NodeConfig::new(&listener.local_addr().unwrap().to_string())
+        };
+        TcpBackend::new(&config)
+    }
+
+    #[test]
+    fn talks_to_a_network_node() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut backend = backend(&listener);
+        thread::spawn(move || {
+            let mut frequency = 5658;
+            standin::serve(listener, move |command| match command {
+                NodeCommand::ReadPeak => Ok(NodeResponse::Peak(PEAK)),
+                NodeCommand::ReadVersion => Ok(NodeResponse::Version(LATEST_VERSION)),
+                NodeCommand::ReadSlotCount => Ok(NodeResponse::SlotCount(4)),
+                NodeCommand::ReadFrequency => Ok(NodeResponse::Frequency(frequency)),
+                NodeCommand::SetFrequency(f) => {
+                    frequency = f;
+                    Ok(NodeResponse::Ack)
+                }
+                NodeCommand::SelectSlot(_) => Ok(NodeResponse::Ack),
+                _ => Err(ProtocolError::Timeout),
+            })
+        });
+
+        backend.open().unwrap();
+        assert_eq!(backend.latency(), None);
+        assert_eq!(backend.read_version().unwrap(), LATEST_VERSION);
+        assert_eq!(backend.read_slot_count().unwrap(), 4);
+        backend.select_slot(1).unwrap();
+        assert_eq!(backend.set_frequency(5917).unwrap(), 5917);
+        assert_eq!(backend.read_peak().unwrap(), PEAK);
+        assert!(backend.latency().is_some());
+        // Unanswered requests time out and leave the connection usable.
+        assert!(matches!(backend.read_time(), Err(ProtocolError::Timeout)));
+        assert_eq!(backend.read_frequency().unwrap(), 5917);
+    }
+
+    #[test]
+    fn fails_when_the_node_hangs_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut backend = backend(&listener);
+        backend.open().unwrap();
+        drop(listener.accept().unwrap());
+
+        // A closed connection is an I/O error to reconnect on, not a timeout.
+        assert!(matches!(backend.read_peak(), Err(ProtocolError::Io(_))));
+        let mut unopened = TcpBackend::new(&NodeConfig::new("127.0.0.1:1"));
+        assert!(matches!(unopened.read_peak(), Err(ProtocolError::Io(_))));
+    }
+}
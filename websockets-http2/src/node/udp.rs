@@ -0,0 +1,307 @@
+use super::network::{self, Latency};
+use super::NodeBackend;
+use crate::config::NodeConfig;
+use rustimer::protocol::{NodeCommand, NodeResponse, Peak, ProtocolError};
+use std::io;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+/// Largest datagram a node sends: command code and the longest response.
+const MAX_DATAGRAM: usize = 16;
+
+/// Network node on UDP, the lightweight variant for receivers on WiFi.
+///
+/// Each request is one datagram holding the command as written on the serial
+/// line. Each answer is one datagram holding the command code followed by the
+/// response frame. Besides answering, the node pushes a peak datagram, in the
+/// same layout, to whoever last sent it a command, every time it samples. Reads
+/// take the newest pushed peak and only ask for one when nothing was pushed
+/// since the last read, which also subscribes a node that restarted.
+pub struct UdpBackend {
+    address: String,
+    timeout: Duration,
+    socket: Option<UdpSocket>,
+    /// Newest peak pushed by the node and not read yet.
+    pushed: Option<Peak>,
+    latency: Latency,
+}
+
+fn unexpected(command: NodeCommand, response: NodeResponse) -> ProtocolError {
+    ProtocolError::UnexpectedResponse { command, response }
+}
+
+impl UdpBackend {
+    pub fn new(node_config: &NodeConfig) -> Self {
+        UdpBackend {
+            address: node_config.port.clone(),
+            timeout: Duration::from_millis(node_config.timeout_ms),
+            socket: None,
+            pushed: None,
+            latency: Latency::default(),
+        }
+    }
+
+    /// Take every datagram already queued without waiting.
+    ///
+    /// A datagram that fails to parse is dropped: corrupt datagrams are normal
+    /// on WiFi and the next push replaces it anyway.
+    fn drain(&mut self) -> Result<(), ProtocolError> {
+        let socket = self.socket.as_ref().ok_or_else(network::not_connected)?;
+        socket.set_nonblocking(true)?;
+        let mut buffer = [0u8; MAX_DATAGRAM];
+        let result = loop {
+            match socket.recv(&mut buffer) {
+                Ok(n) => match parse(&buffer[..n], None) {
+                    Ok(Datagram::Pushed(peak)) => self.pushed = Some(peak),
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Dropping datagram from {}: {}", self.address, e),
+                },
+                // Checked before converting: here it means the queue is empty.
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        socket.set_nonblocking(false)?;
+        result
+    }
+
+    /// Send `command` and wait for its answer, timing the round trip.
+    fn exchange(&mut self, command: NodeCommand) -> Result<NodeResponse, ProtocolError> {
+        let socket = self.socket.as_ref().ok_or_else(network::not_connected)?;
+        let sent = Instant::now();
+        socket.send(&command.encode())?;
+        if command.response_len() == 0 {
+            return Ok(NodeResponse::Ack);
+        }
+
+        let deadline = sent + self.timeout;
+        let mut buffer = [0u8; MAX_DATAGRAM];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ProtocolError::Timeout);
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let n = socket.recv(&mut buffer)?;
+            // Anything but a good answer is skipped and the wait goes on until
+            // the deadline, so a corrupt datagram costs at most a timeout.
+            match parse(&buffer[..n], Some(command)) {
+                Ok(Datagram::Answer(response)) => {
+                    self.latency.record(sent.elapsed());
+                    return Ok(response);
+                }
+                Ok(Datagram::Pushed(peak)) => self.pushed = Some(peak),
+                Ok(Datagram::Stale) => {}
+                Err(e) => eprintln!("Dropping datagram from {}: {}", self.address, e),
+            }
+        }
+    }
+}
+
+enum Datagram {
+    /// The answer to the request being waited for.
+    Answer(NodeResponse),
+    Pushed(Peak),
+    /// A late answer to a request that already timed out.
+    Stale,
+}
+
+fn parse(datagram: &[u8], awaiting: Option<NodeCommand>) -> Result<Datagram, ProtocolError> {
+    let Some((&code, frame)) = datagram.split_first() else {
+        return Ok(Datagram::Stale);
+    };
+    if let Some(command) = awaiting.filter(|command| command.code() == code) {
+        return Ok(Datagram::Answer(command.decode(frame)?));
+    }
+    if code == NodeCommand::ReadPeak.code() {
+        if let NodeResponse::Peak(peak) = NodeCommand::ReadPeak.decode(frame)? {
+            return Ok(Datagram::Pushed(peak));
+        }
+    }
+    Ok(Datagram::Stale)
+}
+
+impl NodeBackend for UdpBackend {
+    fn open(&mut self) -> Result<(), ProtocolError> {
+        self.socket = None;
+        self.pushed = None;
+        println!("Using UDP network node {}", self.address);
+        let address = network::resolve(&self.address)?[0];
+        let local = if address.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.latency.get()
+    }
+
+    fn read_peak(&mut self) -> Result<Peak, ProtocolError> {
+        self.drain()?;
+        if let Some(peak) = self.pushed.take() {
+            return Ok(peak);
+        }
+        match self.exchange(NodeCommand::ReadPeak)? {
+            NodeResponse::Peak(peak) => Ok(peak),
+            response => Err(unexpected(NodeCommand::ReadPeak, response)),
+        }
+    }
+
+    fn read_time(&mut self) -> Result<u32, ProtocolError> {
+        match self.exchange(NodeCommand::ReadMillis)? {
+            NodeResponse::Millis(millis) => Ok(millis),
+            response => Err(unexpected(NodeCommand::ReadMillis, response)),
+        }
+    }
+
+    fn read_version(&mut self) -> Result<u16, ProtocolError> {
+        match self.exchange(NodeCommand::ReadVersion)? {
+            NodeResponse::Version(version) => Ok(version),
+            response => Err(unexpected(NodeCommand::ReadVersion, response)),
+        }
+    }
+
+    fn read_slot_count(&mut self) -> Result<u8, ProtocolError> {
+        match self.exchange(NodeCommand::ReadSlotCount)? {
+            NodeResponse::SlotCount(count) => Ok(count),
+            response => Err(unexpected(NodeCommand::ReadSlotCount, response)),
+        }
+    }
+
+    fn select_slot(&mut self, slot: u8) -> Result<(), ProtocolError> {
+        self.exchange(NodeCommand::SelectSlot(slot)).map(|_| ())
+    }
+
+    fn read_frequency(&mut self) -> Result<u16, ProtocolError> {
+        match self.exchange(NodeCommand::ReadFrequency)? {
+            NodeResponse::Frequency(frequency) => Ok(frequency),
+            response => Err(unexpected(NodeCommand::ReadFrequency, response)),
+        }
+    }
+
+    fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError> {
+        self.exchange(NodeCommand::SetFrequency(frequency))?;
+        self.read_frequency()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEAK: Peak = Peak {
+        lap_id: 3,
+        ms_val: 1200,
+        rssi: 99,
+    };
+
+    /// Datagram a node sends for `response` to `command`.
+    fn datagram(command: NodeCommand, response: NodeResponse) -> Vec<u8> {
+        let mut datagram = vec![command.code()];
+        datagram.extend(response.encode());
+        datagram
+    }
+
+    fn corrupt(mut datagram: Vec<u8>) -> Vec<u8> {
+        *datagram.last_mut().unwrap() ^= 0xFF;
+        datagram
+    }
+
+    /// Backend opened on a fake node, and the node's socket.
+    fn connect() -> (UdpBackend, UdpSocket) {
+        let node = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = NodeConfig {
+            timeout_ms: 100,
+            ..NodeConfig::new(&node.local_addr().unwrap().to_string())
+        };
+        let mut backend = UdpBackend::new(&config);
+        backend.open().unwrap();
+        let local = backend.socket.as_ref().unwrap().local_addr().unwrap();
+        node.connect(("127.0.0.1", local.port())).unwrap();
+        node.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        (backend, node)
+    }
+
+    #[test]
+    fn parses_answers_pushes_and_stale_datagrams() {
+        let peak = datagram(NodeCommand::ReadPeak, NodeResponse::Peak(PEAK));
+        let millis = datagram(NodeCommand::ReadMillis, NodeResponse::Millis(5000));
+
+        let answer = parse(&millis, Some(NodeCommand::ReadMillis));
+        assert!(matches!(
+            answer,
+            Ok(Datagram::Answer(NodeResponse::Millis(5000)))
+        ));
+        let pushed = parse(&peak, Some(NodeCommand::ReadMillis));
+        assert!(matches!(pushed, Ok(Datagram::Pushed(peak)) if peak == PEAK));
+        assert!(matches!(parse(&millis, None), Ok(Datagram::Stale)));
+        assert!(matches!(parse(&[], None), Ok(Datagram::Stale)));
+        assert!(matches!(
+            parse(&corrupt(peak), None),
+            Err(ProtocolError::Checksum { .. })
+        ));
+        assert!(matches!(
+            parse(&millis[..3], Some(NodeCommand::ReadMillis)),
+            Err(ProtocolError::ShortRead { .. })
+        ));
+    }
+
+    #[test]
+    fn reads_pushed_peaks_and_drops_corrupt_ones() {
+        let (mut backend, node) = connect();
+        let peak = datagram(NodeCommand::ReadPeak, NodeResponse::Peak(PEAK));
+        node.send(&corrupt(peak.clone())).unwrap();
+        node.send(&peak).unwrap();
+        node.send(&corrupt(peak)).unwrap();
+        // Let the datagrams arrive before the backend drains them.
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(backend.read_peak().unwrap(), PEAK);
+        // The push answered the read; nothing was asked.
+        let mut buffer = [0u8; MAX_DATAGRAM];
+        assert!(node.recv(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn skips_other_datagrams_waiting_for_an_answer() {
+        let (mut backend, node) = connect();
+        // A late answer, a push and a corrupt answer come in before the
+        // answer proper.
+        let version = datagram(NodeCommand::ReadVersion, NodeResponse::Version(0x2504));
+        node.send(&datagram(
+            NodeCommand::ReadFrequency,
+            NodeResponse::Frequency(5658),
+        ))
+        .unwrap();
+        node.send(&datagram(NodeCommand::ReadPeak, NodeResponse::Peak(PEAK)))
+            .unwrap();
+        node.send(&corrupt(version.clone())).unwrap();
+        node.send(&version).unwrap();
+
+        assert_eq!(backend.read_version().unwrap(), 0x2504);
+        let mut buffer = [0u8; MAX_DATAGRAM];
+        let n = node.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], NodeCommand::ReadVersion.encode());
+        assert!(backend.latency().is_some());
+        // The push that came in meanwhile is kept for the next read.
+        assert_eq!(backend.read_peak().unwrap(), PEAK);
+    }
+
+    #[test]
+    fn times_out_without_an_answer() {
+        let (mut backend, node) = connect();
+        node.send(&datagram(NodeCommand::ReadMillis, NodeResponse::Millis(1)))
+            .unwrap();
+        assert!(matches!(
+            backend.read_version(),
+            Err(ProtocolError::Timeout)
+        ));
+    }
+}
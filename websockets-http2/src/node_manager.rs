@@ -0,0 +1,1528 @@
+use crate::calibration::Calibration;
+use crate::config::{Config, LapSource, NodeConfig, PassConfig, Sampling};
+use crate::filter::{FilterChain, FilterConfig};
+use crate::lap_tracker::{LapTracker, NodeLap};
+use crate::node::recording::{Recorder, RecordingBackend};
+use crate::node::replay::{ReplayBackend, ReplaySession};
+use crate::node::{self, NodeBackend};
+use crate::pass_detector::PassDetector;
+use crate::race_format::RaceFormat;
+use crate::sampler::{DeviceSampler, Readings};
+use crate::structs::calibration::{CalibrationStatus, PassProfile};
+use crate::structs::lap::CreateLap;
+use crate::structs::node::CreateNode;
+use crate::structs::node_status::NodeStatus;
+use crate::structs::race::FalseStart;
+use crate::structs::rssi::RssiSample;
+use rustimer::clock::{ClockEstimate, ClockReading, ClockSync};
+use rustimer::firmware::{Firmware, FirmwareError};
+use rustimer::protocol::{NodeCommand, ProtocolError};
+use rustimer::stream::{StreamFrame, StreamStats};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Change threshold of nodes without a calibrated noise floor.
+const THRESHOLD: u32 = 3;
+/// Consecutive failed reads after which a device counts as disconnected.
+/// I/O errors disconnect it straight away.
+const MAX_FAILURES: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Span the sample rate is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+/// Time between node clock reads of a polled device. Several reads per
+/// second of the estimator's sample spacing let it keep the fastest.
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_millis(250);
+
+pub type SharedBackend = Arc<Mutex<Box<dyn NodeBackend>>>;
+
+/// One RSSI reading and the time it was taken. Once the node clock is in sync
+/// the time is corrected for the link delay, or taken from the node clock for
+/// streamed samples, so USB and scheduling delays do not move it.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub rssi: u32,
+    pub at: Instant,
+    /// Node lap counter.
+    pub lap_id: u8,
+    /// Node time since its last lap, in milliseconds.
+    pub ms_since_lap: u16,
+    /// Round trip to the device, for backends that measure it.
+    pub latency: Option<Duration>,
+}
+
+/// Clock sync of one device and when a polled device has its clock read next.
+#[derive(Default)]
+struct NodeClock {
+    sync: ClockSync,
+    next_read: Option<Instant>,
+}
+
+/// Cheap handle used from the blocking pool to read one node. Slots of the
+/// same Arduino share a backend, so each access selects its slot first.
+#[derive(Clone)]
+pub struct NodeReader {
+    pub index: usize,
+    device: usize,
+    backend: SharedBackend,
+    clock: Arc<Mutex<NodeClock>>,
+    firmware: Firmware,
+    slot: Option<u8>,
+    /// Whether the device pushes its samples instead of being polled.
+    streaming: bool,
+    stream_stats: Arc<Mutex<Option<StreamStats>>>,
+}
+
+impl NodeReader {
+    fn lock(&self) -> Result<MutexGuard<'_, Box<dyn NodeBackend>>, ProtocolError> {
+        let mut backend = self.backend.lock().unwrap();
+        if let Some(slot) = self.slot {
+            backend.select_slot(slot)?;
+        }
+        Ok(backend)
+    }
+
+    /// Reads the peak response and stamps it with the time the node most
+    /// likely took it: half the fastest clock round trip before the answer
+    /// arrived, or the arrival itself before the clock is in sync. The node
+    /// clock is read every [`CLOCK_SYNC_INTERVAL`] rather than after each
+    /// peak, so syncing takes little from the polling rate.
+    pub fn read_peak(&self) -> Result<Sample, ProtocolError> {
+        let mut backend = self.lock()?;
+        let peak = backend.read_peak()?;
+        let received = backend.now();
+        let has_clock = self
+            .firmware
+            .capabilities
+            .supports(&NodeCommand::ReadMillis);
+
+        let mut clock = self.clock.lock().unwrap();
+        if has_clock && clock.next_read.is_none_or(|at| received >= at) {
+            if let Ok(reading) = backend.read_clock() {
+                clock.sync.add(reading);
+            }
+            clock.next_read = Some(received + CLOCK_SYNC_INTERVAL);
+        }
+        let at = clock
+            .sync
+            .estimate()
+            .and_then(|estimate| received.checked_sub(estimate.round_trip / 2))
+            .unwrap_or(received);
+        Ok(Sample {
+            rssi: peak.rssi as u32,
+            at,
+            lap_id: peak.lap_id,
+            ms_since_lap: peak.ms_val,
+            latency: backend.latency(),
+        })
+    }
+
+    /// Reads the frames the device pushed since the last read, for all its
+    /// slots. Each frame carries the node clock, so every sample gets its own
+    /// time even though a read returns many at once.
+    fn read_stream(&self) -> Result<Vec<(u8, Sample)>, ProtocolError> {
+        let mut backend = self.backend.lock().unwrap();
+        let frames = backend.read_stream()?;
+        let received = backend.now();
+        *self.stream_stats.lock().unwrap() = backend.stream_stats();
+
+        let mut clock = self.clock.lock().unwrap();
+        for frame in &frames {
+            clock.sync.add(ClockReading {
+                sent: received,
+                millis: frame.millis,
+                received,
+            });
+        }
+        let sample = |frame: &StreamFrame| Sample {
+            rssi: frame.peak.rssi as u32,
+            at: clock.sync.to_host(frame.millis).unwrap_or(received),
+            lap_id: frame.peak.lap_id,
+            ms_since_lap: frame.peak.ms_val,
+            latency: backend.latency(),
+        };
+        Ok(frames
+            .iter()
+            .map(|frame| (frame.slot, sample(frame)))
+            .collect())
+    }
+
+    pub fn read_frequency(&self) -> Result<u16, ProtocolError> {
+        self.lock()?.read_frequency()
+    }
+
+    pub fn set_frequency(&self, frequency: u16) -> Result<u16, ProtocolError> {
+        self.lock()?.set_frequency(frequency)
+    }
+}
+
+/// A device just opened.
+pub struct OpenDevice {
+    pub firmware: Firmware,
+    /// Whether it was switched to streaming.
+    pub streaming: bool,
+}
+
+/// Opens a device, detects its firmware and, with [`Sampling::Stream`],
+/// switches it to streaming when both the firmware and the backend can.
+pub fn open_device(
+    backend: &mut dyn NodeBackend,
+    sampling: Sampling,
+) -> Result<OpenDevice, FirmwareError> {
+    backend.open()?;
+    let firmware = Firmware::detect(backend.read_version())?;
+    let streaming = sampling == Sampling::Stream
+        && firmware.capabilities.streaming
+        && match backend.set_streaming(true) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Failed to start streaming, polling instead: {}", e);
+                false
+            }
+        };
+    Ok(OpenDevice {
+        firmware,
+        streaming,
+    })
+}
+
+/// Reads every node and returns the results by node index. Polled nodes give
+/// one sample each; a streaming device is read once for all its nodes, and
+/// gives as many samples as it pushed.
+pub fn read_all(readers: &[NodeReader]) -> Vec<(usize, Result<Sample, ProtocolError>)> {
+    let mut readings = Vec::new();
+    let mut streamed = Vec::new();
+    for reader in readers {
+        if !reader.streaming {
+            readings.push((reader.index, reader.read_peak()));
+            continue;
+        }
+        if streamed.contains(&reader.device) {
+            continue;
+        }
+        streamed.push(reader.device);
+
+        let siblings: Vec<&NodeReader> = readers
+            .iter()
+            .filter(|other| other.device == reader.device)
+            .collect();
+        match reader.read_stream() {
+            Ok(samples) => {
+                for (slot, sample) in samples {
+                    if let Some(node) = siblings.iter().find(|node| node.slot.unwrap_or(0) == slot)
+                    {
+                        readings.push((node.index, Ok(sample)));
+                    }
+                }
+            }
+            // Failures count against the device, so report it once.
+            Err(e) => readings.push((reader.index, Err(e))),
+        }
+    }
+    readings
+}
+
+/// Samples per second over the last [`RATE_WINDOW`], by sample time.
+struct SampleRate {
+    recent: VecDeque<Instant>,
+}
+
+impl SampleRate {
+    fn new() -> Self {
+        SampleRate {
+            recent: VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, at: Instant) {
+        self.recent.push_back(at);
+        while let Some(&oldest) = self.recent.front() {
+            if at.saturating_duration_since(oldest) <= RATE_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    fn get(&self) -> Option<f64> {
+        let (first, last) = (self.recent.front()?, self.recent.back()?);
+        let span = last.saturating_duration_since(*first).as_secs_f64();
+        (span > 0.0).then(|| (self.recent.len() - 1) as f64 / span)
+    }
+}
+
+/// Per-node change detection: a row is stored each time the RSSI leaves the
+/// threshold band around the last stored value.
+struct Detection {
+    threshold: u32,
+    last_peak: u32,
+    last_peak_time: Option<Instant>,
+}
+
+impl Detection {
+    fn new(threshold: u32) -> Self {
+        Detection {
+            threshold,
+            last_peak: 0,
+            last_peak_time: None,
+        }
+    }
+
+    /// Twice the calibrated noise, so noise alone rarely closes a segment.
+    fn threshold(profile: Option<&PassProfile>) -> u32 {
+        profile.map_or(THRESHOLD, |profile| {
+            (2.0 * profile.noise_sd).ceil().max(1.0) as u32
+        })
+    }
+
+    /// Returns the finished `(peak, start_time, duration)` segment, if any.
+    /// Times come from the samples, so replayed sessions detect the same
+    /// segments as the recording.
+    fn update(&mut self, sample: Sample, race_start_time: Instant) -> Option<(u32, f64, f64)> {
+        let peak = sample.rssi;
+        let last_peak_time = match self.last_peak_time {
+            Some(time) if self.last_peak != 0 => time,
+            _ => {
+                self.last_peak = peak;
+                self.last_peak_time = Some(sample.at);
+                sample.at
+            }
+        };
+        if peak >= self.last_peak.saturating_sub(self.threshold)
+            && peak <= self.last_peak.saturating_add(self.threshold)
+        {
+            return None;
+        }
+
+        let duration = sample
+            .at
+            .saturating_duration_since(last_peak_time)
+            .as_secs_f64();
+        let start_time = last_peak_time
+            .saturating_duration_since(race_start_time)
+            .as_secs_f64();
+        let segment = (self.last_peak, start_time, duration);
+
+        self.last_peak = peak;
+        self.last_peak_time = Some(sample.at);
+        Some(segment)
+    }
+}
+
+enum Link {
+    Connected { failures: u32 },
+    Disconnected { retry_at: Instant, delay: Duration },
+    Reconnecting { delay: Duration },
+}
+
+/// One opened backend, its firmware, the state of its link and the sync of
+/// its clock.
+struct Device {
+    name: String,
+    backend: SharedBackend,
+    firmware: Firmware,
+    clock: Arc<Mutex<NodeClock>>,
+    link: Link,
+    streaming: bool,
+    stream_stats: Arc<Mutex<Option<StreamStats>>>,
+}
+
+/// When a race ran, on the sample clock. Samples are attributed to a race by
+/// the time they were taken, not the time the worker gets to them.
+#[derive(Debug, Clone, Copy)]
+pub struct RaceWindow {
+    pub id: i32,
+    pub start: Instant,
+    /// `None` while the race runs.
+    pub stop: Option<Instant>,
+}
+
+impl RaceWindow {
+    pub fn is_running(&self) -> bool {
+        self.stop.is_none()
+    }
+
+    fn contains(&self, at: Instant) -> bool {
+        at >= self.start && self.stop.is_none_or(|stop| at < stop)
+    }
+}
+
+/// Store laps counted by node `index` during `race`.
+fn push_node_laps(store: &mut Vec<CreateLap>, index: usize, race: RaceWindow, laps: Vec<NodeLap>) {
+    let race_time = |at: Instant| at.saturating_duration_since(race.start).as_secs_f64();
+    for lap in laps {
+        println!(
+            "Node {}: lap {} at {} seconds",
+            index,
+            lap.number,
+            race_time(lap.at)
+        );
+        store.push(CreateLap {
+            race_id: race.id,
+            node_index: index as i32,
+            time: race_time(lap.at),
+            host_time: lap.host_at.map(race_time),
+            uncertainty: None,
+        });
+    }
+}
+
+/// Change of a node's link, reported for every node of the device.
+pub enum LinkEvent {
+    Disconnected {
+        node_index: usize,
+        at: Instant,
+        error: String,
+    },
+    Reconnected {
+        node_index: usize,
+        at: Instant,
+    },
+}
+
+struct ManagedNode {
+    reader: NodeReader,
+    config: NodeConfig,
+    detection: Detection,
+    /// Set when laps come from the node's lap counter.
+    laps: Option<LapTracker>,
+    /// Set when laps are detected on the host.
+    passes: Option<PassDetector>,
+    /// Pilot flying the node, if known.
+    pilot: Option<String>,
+    /// Calibrated thresholds in use, instead of the configured ones.
+    profile: Option<PassProfile>,
+    calibration: Option<Calibration>,
+    filters: FilterChain,
+    frequency: Option<u16>,
+    last_rssi: Option<u32>,
+    last_filtered: Option<f64>,
+    last_latency: Option<Duration>,
+    last_error: Option<String>,
+    samples: u64,
+    rate: SampleRate,
+}
+
+fn log_firmware(device: &str, firmware: &Firmware) {
+    println!("{}: firmware {}", device, firmware);
+    if !firmware.known {
+        eprintln!(
+            "{}: firmware is newer than this server, some features may be missing",
+            device
+        );
+    }
+}
+
+/// Lap tracking for a node, when laps come from the node and its firmware
+/// counts them.
+fn lap_tracker(config: &Config, index: usize, firmware: &Firmware) -> Option<LapTracker> {
+    if config.lap_source != LapSource::Node {
+        return None;
+    }
+    if !firmware.capabilities.lap_counter {
+        eprintln!(
+            "Node {}: firmware {} has no lap counter, laps are not recorded",
+            index, firmware
+        );
+        return None;
+    }
+    Some(LapTracker::new())
+}
+
+impl ManagedNode {
+    fn reset(&mut self) {
+        self.detection = Detection::new(self.detection.threshold);
+        self.filters.reset();
+        if let Some(laps) = &mut self.laps {
+            *laps = LapTracker::new();
+        }
+        if let Some(passes) = &mut self.passes {
+            passes.reset();
+        }
+    }
+}
+
+pub struct NodeManager {
+    nodes: Vec<ManagedNode>,
+    devices: Vec<Device>,
+    /// Reader thread of each device, once sampling started.
+    samplers: Vec<DeviceSampler>,
+    racing: bool,
+    /// Configured pass thresholds, for nodes without a profile.
+    pass: PassConfig,
+    /// Start of the sample times sent to clients.
+    epoch: Instant,
+    rssi: Vec<RssiSample>,
+    recorder: Option<Arc<Recorder>>,
+    link_events: Vec<LinkEvent>,
+    laps: Vec<CreateLap>,
+    /// Race counting down to its start, watched for false starts.
+    countdown: Option<Countdown>,
+    false_starts: Vec<FalseStart>,
+}
+
+/// A countdown in progress: when it was armed, and for each node whether its
+/// RSSI has been clear of the gate since and whether it was flagged.
+struct Countdown {
+    race_id: i32,
+    armed_at: Instant,
+    start: Instant,
+    clear: Vec<bool>,
+    flagged: Vec<bool>,
+}
+
+impl NodeManager {
+    /// Opens every configured node. Nodes that name the same port share one
+    /// backend. When replaying, each port is played back from the recorded
+    /// device with the same number instead.
+    pub fn open(
+        config: &Config,
+        replay: Option<Arc<ReplaySession>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut device_indices: HashMap<String, usize> = HashMap::new();
+        let mut devices: Vec<Device> = Vec::new();
+        let node_configs = match &replay {
+            _ if !config.nodes.is_empty() => config.nodes.clone(),
+            Some(session) => (0..session.device_count())
+                .map(|device| NodeConfig::new(&format!("replay-{}", device)))
+                .collect(),
+            None => node::discover_nodes(config),
+        };
+        if node_configs.is_empty() {
+            return Err("no nodes configured or found".into());
+        }
+        let mut nodes = Vec::with_capacity(node_configs.len());
+        let recorder = match (&config.record, &replay) {
+            (Some(path), None) => Some(Recorder::create(path)?),
+            _ => None,
+        };
+
+        for (index, node_config) in node_configs.iter().enumerate() {
+            let name = node_config.device();
+            let device = match device_indices.get(&name) {
+                Some(device) => *device,
+                None => {
+                    let device = devices.len() as u8;
+                    let mut backend: Box<dyn NodeBackend> = match (&replay, &recorder) {
+                        (Some(session), _) => {
+                            Box::new(ReplayBackend::new(Arc::clone(session), device))
+                        }
+                        (None, Some(recorder)) => Box::new(RecordingBackend::new(
+                            node::create_backend(config, node_config),
+                            device,
+                            Arc::clone(recorder),
+                        )),
+                        (None, None) => node::create_backend(config, node_config),
+                    };
+                    let opened = open_device(backend.as_mut(), config.sampling)
+                        .map_err(|e| format!("{}: {}", name, e))?;
+                    log_firmware(&name, &opened.firmware);
+                    if opened.streaming {
+                        println!("{}: streaming", name);
+                    }
+                    devices.push(Device {
+                        name: name.clone(),
+                        backend: Arc::new(Mutex::new(backend)),
+                        firmware: opened.firmware,
+                        clock: Arc::new(Mutex::new(NodeClock::default())),
+                        link: Link::Connected { failures: 0 },
+                        streaming: opened.streaming,
+                        stream_stats: Arc::new(Mutex::new(None)),
+                    });
+                    device_indices.insert(name.clone(), devices.len() - 1);
+                    devices.len() - 1
+                }
+            };
+            let backend = Arc::clone(&devices[device].backend);
+            let firmware = devices[device].firmware;
+
+            let device_nodes = nodes
+                .iter()
+                .filter(|node: &&ManagedNode| node.reader.device == device)
+                .count();
+            if device_nodes >= firmware.capabilities.max_nodes as usize {
+                return Err(format!(
+                    "node {}: firmware {} on {} drives at most {} receivers",
+                    index, firmware, name, firmware.capabilities.max_nodes
+                )
+                .into());
+            }
+
+            if let Some(slot) = node_config.slot {
+                if !firmware
+                    .capabilities
+                    .supports(&NodeCommand::SelectSlot(slot))
+                {
+                    return Err(format!(
+                        "node {}: firmware {} on {} has no receiver slots",
+                        index, firmware, name
+                    )
+                    .into());
+                }
+                let slot_count = backend.lock().unwrap().read_slot_count()?;
+                if slot >= slot_count {
+                    return Err(format!(
+                        "node {}: slot {} not available on {} ({} slots)",
+                        index, slot, name, slot_count
+                    )
+                    .into());
+                }
+            }
+
+            let reader = NodeReader {
+                index,
+                device,
+                backend,
+                clock: Arc::clone(&devices[device].clock),
+                firmware,
+                slot: node_config.slot,
+                streaming: devices[device].streaming,
+                stream_stats: Arc::clone(&devices[device].stream_stats),
+            };
+            let frequency = match reader.read_frequency() {
+                Ok(frequency) => Some(frequency),
+                Err(e) => {
+                    eprintln!("Node {}: failed to read frequency: {}", index, e);
+                    None
+                }
+            };
+
+            println!(
+                "Node {}: {} slot {:?} at {:?} MHz",
+                index, name, node_config.slot, frequency
+            );
+            nodes.push(ManagedNode {
+                reader,
+                config: node_config.clone(),
+                detection: Detection::new(THRESHOLD),
+                laps: lap_tracker(config, index, &firmware),
+                passes: (config.lap_source == LapSource::Host)
+                    .then(|| PassDetector::new(config.pass)),
+                pilot: None,
+                profile: None,
+                calibration: None,
+                filters: FilterChain::new(
+                    node_config
+                        .filters
+                        .clone()
+                        .unwrap_or_else(|| config.filters.clone()),
+                ),
+                frequency,
+                last_rssi: None,
+                last_filtered: None,
+                last_latency: None,
+                last_error: None,
+                samples: 0,
+                rate: SampleRate::new(),
+            });
+        }
+
+        Ok(NodeManager {
+            nodes,
+            devices,
+            samplers: Vec::new(),
+            racing: false,
+            pass: config.pass,
+            epoch: Instant::now(),
+            rssi: Vec::new(),
+            recorder,
+            link_events: Vec::new(),
+            countdown: None,
+            false_starts: Vec::new(),
+            laps: Vec::new(),
+        })
+    }
+
+    /// Mark a race start in the recording, if any, and return the start time
+    /// detection should use.
+    pub fn record_race_start(&self, race_id: i32, format: RaceFormat, time: Instant) -> Instant {
+        match &self.recorder {
+            Some(recorder) => recorder.race_start(race_id, format, time),
+            None => time,
+        }
+    }
+
+    /// Mark a race stop in the recording, if any, and return the stop time
+    /// detection should use.
+    pub fn record_race_stop(&self, race_id: i32, time: Instant) -> Instant {
+        match &self.recorder {
+            Some(recorder) => recorder.race_stop(race_id, time),
+            None => time,
+        }
+    }
+
+    /// Start a reader thread for every device, sending its readings to
+    /// `sender`.
+    pub fn start_sampling(&mut self, sender: mpsc::Sender<Readings>) {
+        self.samplers = self
+            .devices
+            .iter()
+            .map(|device| DeviceSampler::spawn(&device.name, sender.clone()))
+            .collect();
+        for device in 0..self.devices.len() {
+            self.update_sampler(device);
+        }
+    }
+
+    /// Polled nodes are only read during races. A race cuts calibrations
+    /// short, since its pilots are not flying flyovers.
+    pub fn set_racing(&mut self, racing: bool) {
+        self.racing = racing;
+        if racing {
+            for node in &mut self.nodes {
+                if node.calibration.take().is_some() {
+                    eprintln!(
+                        "Node {}: calibration cancelled by the race",
+                        node.reader.index
+                    );
+                }
+            }
+        }
+        for device in 0..self.devices.len() {
+            self.update_sampler(device);
+        }
+    }
+
+    /// Hand a device's reader thread the nodes it should read now: none while
+    /// the device is down, and outside races only streaming ones, since
+    /// their stream has to be drained anyway, and those being calibrated.
+    fn update_sampler(&self, device: usize) {
+        let Some(sampler) = self.samplers.get(device) else {
+            return;
+        };
+        let readers = if self.is_connected(device) {
+            self.nodes
+                .iter()
+                .filter(|node| node.reader.device == device)
+                .filter(|node| self.racing || node.reader.streaming || node.calibration.is_some())
+                .map(|node| node.reader.clone())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        sampler.set_readers(readers);
+    }
+
+    fn clock_estimate(&self, device: usize) -> Option<ClockEstimate> {
+        self.devices[device].clock.lock().unwrap().sync.estimate()
+    }
+
+    fn is_connected(&self, device: usize) -> bool {
+        matches!(self.devices[device].link, Link::Connected { .. })
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn reader(&self, index: usize) -> Option<NodeReader> {
+        self.nodes.get(index).map(|node| node.reader.clone())
+    }
+
+    /// Record the frequency a node reported after being tuned.
+    pub fn set_frequency(&mut self, index: usize, frequency: u16) {
+        if let Some(node) = self.nodes.get_mut(index) {
+            node.frequency = Some(frequency);
+        }
+    }
+
+    /// Start calibrating a node's thresholds, over any calibration already
+    /// running on it.
+    pub fn start_calibration(
+        &mut self,
+        index: usize,
+        pilot: Option<String>,
+        noise_duration: Duration,
+    ) -> Result<(), String> {
+        if self.racing {
+            return Err("cannot calibrate during a race".to_string());
+        }
+        let node = self
+            .nodes
+            .get_mut(index)
+            .ok_or_else(|| format!("unknown node {}", index))?;
+        println!(
+            "Node {}: calibrating{}, measuring the noise floor",
+            index,
+            pilot
+                .as_ref()
+                .map_or(String::new(), |pilot| format!(" for {}", pilot))
+        );
+        node.calibration = Some(Calibration::new(index, pilot, noise_duration));
+        let device = node.reader.device;
+        self.update_sampler(device);
+        Ok(())
+    }
+
+    pub fn calibration_status(&self, index: usize) -> Option<CalibrationStatus> {
+        self.nodes
+            .get(index)?
+            .calibration
+            .as_ref()
+            .map(Calibration::status)
+    }
+
+    /// End a node's calibration with the thresholds it proposes. Those are
+    /// used straight away when calibrated for the pilot flying the node, or
+    /// for the node itself while its pilot has no profile of their own.
+    pub fn finish_calibration(&mut self, index: usize) -> Result<PassProfile, String> {
+        let node = self
+            .nodes
+            .get_mut(index)
+            .ok_or_else(|| format!("unknown node {}", index))?;
+        let calibration = node
+            .calibration
+            .as_ref()
+            .ok_or_else(|| format!("node {} is not calibrating", index))?;
+        let profile = calibration.propose()?;
+        let pilot = calibration.pilot().map(str::to_string);
+        node.calibration = None;
+        let device = node.reader.device;
+
+        let own_profile = node
+            .profile
+            .as_ref()
+            .is_some_and(|active| !active.pilot.is_empty());
+        if pilot == node.pilot || (pilot.is_none() && !own_profile) {
+            let node_pilot = node.pilot.clone();
+            self.set_pass_profile(index, node_pilot, Some(profile.clone()))?;
+        }
+        self.update_sampler(device);
+        Ok(profile)
+    }
+
+    /// Set the pilot flying a node and the profile to detect their passes
+    /// with; without one, the configured thresholds apply.
+    pub fn set_pass_profile(
+        &mut self,
+        index: usize,
+        pilot: Option<String>,
+        profile: Option<PassProfile>,
+    ) -> Result<(), String> {
+        let node = self
+            .nodes
+            .get_mut(index)
+            .ok_or_else(|| format!("unknown node {}", index))?;
+        if let Some(passes) = &mut node.passes {
+            let min_lap_s = passes.thresholds().min_lap_s;
+            let thresholds = profile.as_ref().map_or(self.pass, |profile| PassConfig {
+                enter_rssi: profile.enter_rssi,
+                exit_rssi: profile.exit_rssi,
+                min_lap_s,
+            });
+            passes.set_thresholds(thresholds);
+        }
+        node.detection.threshold = Detection::threshold(profile.as_ref());
+        match &profile {
+            Some(profile) => println!(
+                "Node {}: pass thresholds {}/{}{}",
+                index,
+                profile.enter_rssi,
+                profile.exit_rssi,
+                pilot
+                    .as_ref()
+                    .map_or(String::new(), |pilot| format!(" for {}", pilot))
+            ),
+            None => println!("Node {}: configured pass thresholds", index),
+        }
+        node.pilot = pilot;
+        node.profile = profile;
+        Ok(())
+    }
+
+    /// Forget detection state so a new race does not inherit the last segment
+    /// or count laps from the last race.
+    pub fn reset(&mut self) {
+        for node in &mut self.nodes {
+            node.reset();
+        }
+    }
+
+    /// Samples handled since the last call, raw and filtered.
+    pub fn take_rssi(&mut self) -> Vec<RssiSample> {
+        std::mem::take(&mut self.rssi)
+    }
+
+    /// Replace a node's filters; the new ones start from the next sample.
+    pub fn set_filters(&mut self, index: usize, filters: Vec<FilterConfig>) -> Result<(), String> {
+        crate::filter::validate(&filters)?;
+        let node = self
+            .nodes
+            .get_mut(index)
+            .ok_or_else(|| format!("unknown node {}", index))?;
+        println!("Node {}: filters {:?}", index, filters);
+        node.filters = FilterChain::new(filters);
+        node.last_filtered = None;
+        Ok(())
+    }
+
+    /// Report the laps node lap counters still hold back for reconciliation,
+    /// once `race` stopped and its samples end.
+    pub fn flush_laps(&mut self, race: RaceWindow) {
+        for (index, node) in self.nodes.iter_mut().enumerate() {
+            if let Some(tracker) = &mut node.laps {
+                push_node_laps(&mut self.laps, index, race, tracker.flush());
+            }
+        }
+    }
+
+    /// Laps counted by the nodes or detected on the host since the last call.
+    pub fn take_laps(&mut self) -> Vec<CreateLap> {
+        std::mem::take(&mut self.laps)
+    }
+
+    /// Watch the nodes for pilots crossing the gate between `armed_at` and
+    /// the start signal at `start`.
+    pub fn start_countdown(&mut self, race_id: i32, armed_at: Instant, start: Instant) {
+        self.countdown = Some(Countdown {
+            race_id,
+            armed_at,
+            start,
+            clear: vec![false; self.nodes.len()],
+            flagged: vec![false; self.nodes.len()],
+        });
+    }
+
+    pub fn end_countdown(&mut self) {
+        self.countdown = None;
+    }
+
+    /// False starts flagged since the last call.
+    pub fn take_false_starts(&mut self) -> Vec<FalseStart> {
+        std::mem::take(&mut self.false_starts)
+    }
+
+    /// A pilot crosses the gate early when their RSSI, having been at or
+    /// below the exit threshold since the countdown was armed, reaches the
+    /// enter threshold before the start. A drone parked by the gate at arming
+    /// is not flagged until it has moved away first.
+    fn watch_start(&mut self, index: usize, sample: Sample) {
+        let Some(countdown) = &mut self.countdown else {
+            return;
+        };
+        if sample.at < countdown.armed_at || sample.at >= countdown.start {
+            return;
+        }
+        let node = &self.nodes[index];
+        let thresholds = match (&node.passes, &node.profile) {
+            (Some(passes), _) => passes.thresholds(),
+            (None, Some(profile)) => PassConfig {
+                enter_rssi: profile.enter_rssi,
+                exit_rssi: profile.exit_rssi,
+                ..self.pass
+            },
+            (None, None) => self.pass,
+        };
+        if sample.rssi <= thresholds.exit_rssi {
+            countdown.clear[index] = true;
+        } else if sample.rssi >= thresholds.enter_rssi
+            && countdown.clear[index]
+            && !countdown.flagged[index]
+        {
+            countdown.flagged[index] = true;
+            let time = sample
+                .at
+                .saturating_duration_since(countdown.armed_at)
+                .as_secs_f64();
+            println!(
+                "Node {}: false start {:.3} seconds after arming",
+                index, time
+            );
+            self.false_starts.push(FalseStart {
+                race_id: countdown.race_id,
+                node_index: index as i32,
+                pilot: node.pilot.clone(),
+                time,
+                rssi: sample.rssi,
+            });
+        }
+    }
+
+    /// Feeds one reading into the node's detection state and returns the row
+    /// to store, if the reading closed a segment. Samples taken outside the
+    /// last race only update the node status.
+    pub fn handle_reading(
+        &mut self,
+        index: usize,
+        result: Result<Sample, ProtocolError>,
+        race: Option<RaceWindow>,
+    ) -> Option<CreateNode> {
+        let node = self.nodes.get_mut(index)?;
+        let device = node.reader.device;
+        let sample = match result {
+            Ok(sample) => sample,
+            Err(e) => {
+                eprintln!("Worker: Failed to read node {}: {}", index, e);
+                node.last_error = Some(e.to_string());
+                self.read_failed(device, &e);
+                return None;
+            }
+        };
+
+        if let Link::Connected { failures } = &mut self.devices[device].link {
+            *failures = 0;
+        }
+        let node = &mut self.nodes[index];
+        node.last_rssi = Some(sample.rssi);
+        node.last_latency = sample.latency;
+        node.last_error = None;
+        node.samples += 1;
+        node.rate.add(sample.at);
+
+        // Everything from here on sees the filtered RSSI.
+        let filtered = node.filters.apply(sample.rssi, sample.at);
+        node.last_filtered = Some(filtered);
+        self.rssi.push(RssiSample {
+            node_index: index,
+            time: sample
+                .at
+                .saturating_duration_since(self.epoch)
+                .as_secs_f64(),
+            raw: sample.rssi,
+            filtered,
+        });
+        let sample = Sample {
+            rssi: filtered.round().max(0.0) as u32,
+            ..sample
+        };
+        if let Some(calibration) = &mut node.calibration {
+            calibration.update(sample);
+        }
+        self.watch_start(index, sample);
+        let node = &mut self.nodes[index];
+
+        let race = race.filter(|race| race.contains(sample.at))?;
+        let (race_id, race_start_time) = (race.id, race.start);
+        let race_time = |at: Instant| at.saturating_duration_since(race_start_time).as_secs_f64();
+        if let Some(tracker) = &mut node.laps {
+            let mut laps = tracker.update(sample);
+            // A sample still queued from a race that stopped: nothing later
+            // will close the window of its laps.
+            if !race.is_running() {
+                laps.extend(tracker.flush());
+            }
+            push_node_laps(&mut self.laps, index, race, laps);
+        }
+        let pass = node
+            .passes
+            .as_mut()
+            .and_then(|passes| passes.update(sample));
+        if let Some(lap) = pass {
+            let uncertainty = lap.uncertainty.as_secs_f64();
+            println!(
+                "Node {}: lap {} at {} ± {:.4} seconds",
+                index,
+                lap.number,
+                race_time(lap.at),
+                uncertainty
+            );
+            self.laps.push(CreateLap {
+                race_id,
+                node_index: index as i32,
+                time: race_time(lap.at),
+                host_time: Some(race_time(lap.peak_at)),
+                uncertainty: Some(uncertainty),
+            });
+        }
+
+        let (peak, time, duration) = node.detection.update(sample, race_start_time)?;
+        println!(
+            "Node {}: peak {} during {} seconds at {} seconds",
+            index, peak, duration, time
+        );
+        Some(CreateNode {
+            node_index: index as i32,
+            peak,
+            time,
+            duration,
+            race_id,
+        })
+    }
+
+    /// Count a failed read against the device and drop the link once it looks
+    /// gone, so no more reads go to it until it is reopened.
+    fn read_failed(&mut self, device: usize, error: &ProtocolError) {
+        let Link::Connected { failures } = &mut self.devices[device].link else {
+            return;
+        };
+        *failures += 1;
+        if !matches!(error, ProtocolError::Io(_)) && *failures < MAX_FAILURES {
+            return;
+        }
+
+        eprintln!(
+            "Worker: Lost device {}: {}",
+            self.devices[device].name, error
+        );
+        let at = Instant::now();
+        self.devices[device].link = Link::Disconnected {
+            retry_at: at + RECONNECT_DELAY,
+            delay: RECONNECT_DELAY,
+        };
+        for node in self
+            .nodes
+            .iter()
+            .filter(|node| node.reader.device == device)
+        {
+            self.link_events.push(LinkEvent::Disconnected {
+                node_index: node.reader.index,
+                at,
+                error: error.to_string(),
+            });
+        }
+        self.update_sampler(device);
+    }
+
+    /// Link changes since the last call.
+    pub fn take_link_events(&mut self) -> Vec<LinkEvent> {
+        std::mem::take(&mut self.link_events)
+    }
+
+    /// When the next reconnect attempt is due, if any device is down.
+    pub fn next_reconnect(&self) -> Option<Instant> {
+        self.devices
+            .iter()
+            .filter_map(|device| match device.link {
+                Link::Disconnected { retry_at, .. } => Some(retry_at),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Devices whose reconnect attempt is due, to be reopened off the worker.
+    /// Each one must be reported back through [`NodeManager::finish_reconnect`].
+    pub fn start_reconnects(&mut self) -> Vec<(usize, SharedBackend)> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for (index, device) in self.devices.iter_mut().enumerate() {
+            if let Link::Disconnected { retry_at, delay } = device.link {
+                if retry_at <= now {
+                    println!("Worker: Reconnecting to {}", device.name);
+                    device.link = Link::Reconnecting { delay };
+                    due.push((index, Arc::clone(&device.backend)));
+                }
+            }
+        }
+        due
+    }
+
+    /// Take the outcome of reopening a device. On success, detection carries
+    /// on where it was, only the clock sync and the lap counter baseline
+    /// starting over, and the frequencies to re-apply are returned, since the
+    /// receivers lose their tuning with power; on failure the next attempt is
+    /// scheduled with twice the delay.
+    pub fn finish_reconnect(
+        &mut self,
+        device: usize,
+        result: Result<OpenDevice, FirmwareError>,
+    ) -> Vec<(usize, u16)> {
+        let Link::Reconnecting { delay } = self.devices[device].link else {
+            return Vec::new();
+        };
+        let result = result.map_err(|e| e.to_string()).and_then(|opened| {
+            self.check_firmware(device, opened.firmware)?;
+            Ok(opened)
+        });
+        let opened = match result {
+            Ok(opened) => opened,
+            Err(e) => {
+                let delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                eprintln!(
+                    "Worker: Failed to reconnect to {}: {}, retrying in {:?}",
+                    self.devices[device].name, e, delay
+                );
+                self.devices[device].link = Link::Disconnected {
+                    retry_at: Instant::now() + delay,
+                    delay,
+                };
+                return Vec::new();
+            }
+        };
+
+        println!("Worker: Reconnected to {}", self.devices[device].name);
+        let firmware = opened.firmware;
+        if firmware != self.devices[device].firmware {
+            log_firmware(&self.devices[device].name, &firmware);
+            self.devices[device].firmware = firmware;
+        }
+        self.devices[device].link = Link::Connected { failures: 0 };
+        *self.devices[device].clock.lock().unwrap() = NodeClock::default();
+        self.devices[device].streaming = opened.streaming;
+        *self.devices[device].stream_stats.lock().unwrap() = None;
+        let at = Instant::now();
+        let mut retune = Vec::new();
+        for node in self
+            .nodes
+            .iter_mut()
+            .filter(|node| node.reader.device == device)
+        {
+            // A power cycle restarts the lap counter too.
+            if let Some(laps) = &mut node.laps {
+                laps.rebase();
+            }
+            node.reader.firmware = firmware;
+            node.reader.streaming = opened.streaming;
+            node.last_error = None;
+            self.link_events.push(LinkEvent::Reconnected {
+                node_index: node.reader.index,
+                at,
+            });
+            if let Some(frequency) = node.frequency {
+                retune.push((node.reader.index, frequency));
+            }
+        }
+        self.update_sampler(device);
+        retune
+    }
+
+    /// Refuse firmware found on reconnect that cannot drive the nodes
+    /// configured on the device, e.g. after it was flashed with an older one.
+    fn check_firmware(&self, device: usize, firmware: Firmware) -> Result<Firmware, String> {
+        let nodes: Vec<&ManagedNode> = self
+            .nodes
+            .iter()
+            .filter(|node| node.reader.device == device)
+            .collect();
+        let uses_slots = nodes.iter().any(|node| node.reader.slot.is_some());
+        if nodes.len() > firmware.capabilities.max_nodes as usize
+            || (uses_slots && !firmware.capabilities.slots)
+        {
+            return Err(format!(
+                "firmware {} cannot drive the {} configured receivers",
+                firmware,
+                nodes.len()
+            ));
+        }
+        Ok(firmware)
+    }
+
+    pub fn status(&self) -> Vec<NodeStatus> {
+        self.nodes
+            .iter()
+            .map(|node| {
+                let clock = self.clock_estimate(node.reader.device);
+                let stream = *self.devices[node.reader.device]
+                    .stream_stats
+                    .lock()
+                    .unwrap();
+                let thresholds = node.passes.as_ref().map(PassDetector::thresholds);
+                NodeStatus {
+                    index: node.reader.index,
+                    port: node.config.port.clone(),
+                    serial_number: node.config.serial_number.clone(),
+                    slot: node.config.slot,
+                    connected: self.is_connected(node.reader.device),
+                    firmware: node.reader.firmware.into(),
+                    clock_drift_ppm: clock.map(|clock| clock.drift_ppm),
+                    clock_round_trip_ms: clock.map(|clock| clock.round_trip.as_secs_f64() * 1000.0),
+                    frequency: node.frequency,
+                    rssi: node.last_rssi,
+                    filtered_rssi: node.last_filtered,
+                    filters: node.filters.config().to_vec(),
+                    latency_ms: node
+                        .last_latency
+                        .map(|latency| latency.as_secs_f64() * 1000.0),
+                    samples: node.samples,
+                    sample_rate: node.rate.get(),
+                    streaming: node.reader.streaming,
+                    stream: stream.map(Into::into),
+                    pilot: node.pilot.clone(),
+                    enter_rssi: thresholds.map(|thresholds| thresholds.enter_rssi),
+                    exit_rssi: thresholds.map(|thresholds| thresholds.exit_rssi),
+                    calibrated: node.profile.is_some(),
+                    calibrating: node.calibration.is_some(),
+                    error: node.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackendKind;
+    use rustimer::firmware::LATEST_VERSION;
+    use rustimer::protocol::Peak;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const STEP: Duration = Duration::from_millis(10);
+    const ROUND_TRIP: Duration = Duration::from_millis(4);
+
+    /// Node answering in no time on a simulated clock that moves `STEP` per
+    /// peak read. Clock reads take `ROUND_TRIP` and are counted.
+    struct StubBackend {
+        start: Instant,
+        now: Instant,
+        clock_reads: Arc<AtomicUsize>,
+    }
+
+    impl StubBackend {
+        fn new(start: Instant) -> Self {
+            StubBackend {
+                start,
+                now: start,
+                clock_reads: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    impl NodeBackend for StubBackend {
+        fn open(&mut self) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+
+        fn now(&self) -> Instant {
+            self.now
+        }
+
+        fn read_peak(&mut self) -> Result<Peak, ProtocolError> {
+            self.now += STEP;
+            Ok(Peak {
+                lap_id: 0,
+                ms_val: 0,
+                rssi: 80,
+            })
+        }
+
+        fn read_time(&mut self) -> Result<u32, ProtocolError> {
+            Ok((self.now - self.start).as_millis() as u32)
+        }
+
+        fn read_clock(&mut self) -> Result<ClockReading, ProtocolError> {
+            self.clock_reads.fetch_add(1, Ordering::SeqCst);
+            Ok(ClockReading {
+                sent: self.now,
+                millis: (self.now - self.start).as_millis() as u32,
+                received: self.now + ROUND_TRIP,
+            })
+        }
+
+        fn read_version(&mut self) -> Result<u16, ProtocolError> {
+            Ok(LATEST_VERSION)
+        }
+
+        fn read_slot_count(&mut self) -> Result<u8, ProtocolError> {
+            Ok(1)
+        }
+
+        fn select_slot(&mut self, _slot: u8) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+
+        fn read_frequency(&mut self) -> Result<u16, ProtocolError> {
+            Ok(5658)
+        }
+
+        fn set_frequency(&mut self, frequency: u16) -> Result<u16, ProtocolError> {
+            Ok(frequency)
+        }
+    }
+
+    fn reader(backend: StubBackend) -> NodeReader {
+        NodeReader {
+            index: 0,
+            device: 0,
+            backend: Arc::new(Mutex::new(Box::new(backend))),
+            clock: Arc::new(Mutex::new(NodeClock::default())),
+            firmware: Firmware::from_version(LATEST_VERSION).unwrap(),
+            slot: None,
+            streaming: false,
+            stream_stats: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    #[test]
+    fn reads_the_clock_on_a_timer() {
+        let start = Instant::now();
+        let backend = StubBackend::new(start);
+        let clock_reads = Arc::clone(&backend.clock_reads);
+        let reader = reader(backend);
+
+        let samples: Vec<Sample> = (0..100).map(|_| reader.read_peak().unwrap()).collect();
+        // Peaks arrive every 10 ms for a second; the clock is read at 10,
+        // 260, 510 and 760 ms.
+        assert_eq!(clock_reads.load(Ordering::SeqCst), 4);
+        // Once in sync, samples are stamped half the round trip before they
+        // arrived.
+        let last = samples.last().unwrap();
+        assert_eq!(last.at, start + Duration::from_secs(1) - ROUND_TRIP / 2);
+    }
+
+    /// Manager of `count` nodes on one mock device, one slot each.
+    fn manager(count: u8) -> NodeManager {
+        let config = Config {
+            backend: BackendKind::Mock,
+            nodes: (0..count)
+                .map(|slot| NodeConfig {
+                    slot: Some(slot),
+                    ..NodeConfig::new("mock")
+                })
+                .collect(),
+            ..Config::default()
+        };
+        NodeManager::open(&config, None).unwrap()
+    }
+
+    fn sample(start: Instant, at_ms: u64, rssi: u32) -> Sample {
+        Sample {
+            rssi,
+            at: start + Duration::from_millis(at_ms),
+            lap_id: 0,
+            ms_since_lap: 0,
+            latency: None,
+        }
+    }
+
+    #[test]
+    fn flags_pilots_crossing_during_the_countdown() {
+        let mut manager = manager(3);
+        let armed = Instant::now();
+        manager.start_countdown(7, armed, armed + Duration::from_secs(3));
+
+        // Node 0 leaves the gate, then crosses it, twice.
+        for (at_ms, rssi) in [(100, 60), (500, 95), (700, 60), (900, 95)] {
+            manager.handle_reading(0, Ok(sample(armed, at_ms, rssi)), None);
+        }
+        // Node 1 sits by the gate through the countdown.
+        for at_ms in [100, 500, 900] {
+            manager.handle_reading(1, Ok(sample(armed, at_ms, 95)), None);
+        }
+        // Node 2 crosses only once the race started.
+        manager.handle_reading(2, Ok(sample(armed, 100, 60)), None);
+        manager.handle_reading(2, Ok(sample(armed, 3000, 95)), None);
+
+        let false_starts = manager.take_false_starts();
+        assert_eq!(false_starts.len(), 1);
+        assert_eq!(false_starts[0].race_id, 7);
+        assert_eq!(false_starts[0].node_index, 0);
+        assert_eq!(false_starts[0].rssi, 95);
+        assert!((false_starts[0].time - 0.5).abs() < 1e-9);
+        assert!(manager.take_false_starts().is_empty());
+    }
+
+    #[test]
+    fn stops_watching_when_the_countdown_ends() {
+        let mut manager = manager(1);
+        let armed = Instant::now();
+        manager.start_countdown(7, armed, armed + Duration::from_secs(3));
+        manager.handle_reading(0, Ok(sample(armed, 100, 60)), None);
+        manager.end_countdown();
+        manager.handle_reading(0, Ok(sample(armed, 500, 95)), None);
+        assert!(manager.take_false_starts().is_empty());
+    }
+
+    /// Make the pending reconnect of device 0 due, returning its delay.
+    fn make_due(manager: &mut NodeManager) -> Duration {
+        let Link::Disconnected { delay, .. } = manager.devices[0].link else {
+            panic!("device is not disconnected");
+        };
+        manager.devices[0].link = Link::Disconnected {
+            retry_at: Instant::now(),
+            delay,
+        };
+        delay
+    }
+
+    fn timeout() -> Result<Sample, ProtocolError> {
+        Err(ProtocolError::Timeout)
+    }
+
+    #[test]
+    fn drops_the_link_after_repeated_failures() {
+        let mut manager = manager(2);
+        for _ in 1..MAX_FAILURES {
+            manager.handle_reading(0, timeout(), None);
+        }
+        // A good read in between starts the count over.
+        manager.handle_reading(1, Ok(sample(Instant::now(), 0, 50)), None);
+        for _ in 1..MAX_FAILURES {
+            manager.handle_reading(0, timeout(), None);
+        }
+        assert!(manager.next_reconnect().is_none());
+        assert!(manager.take_link_events().is_empty());
+
+        manager.handle_reading(0, timeout(), None);
+        assert!(manager.next_reconnect().is_some());
+        let dropped: Vec<usize> = manager
+            .take_link_events()
+            .iter()
+            .map(|event| match event {
+                LinkEvent::Disconnected { node_index, .. } => *node_index,
+                LinkEvent::Reconnected { .. } => panic!("reconnected"),
+            })
+            .collect();
+        assert_eq!(dropped, [0, 1]);
+        assert!(manager.status().iter().all(|node| !node.connected));
+    }
+
+    #[test]
+    fn drops_the_link_on_io_errors() {
+        let mut manager = manager(1);
+        let error = io::Error::new(io::ErrorKind::BrokenPipe, "unplugged");
+        manager.handle_reading(0, Err(ProtocolError::Io(error)), None);
+        assert!(manager.next_reconnect().is_some());
+    }
+
+    #[test]
+    fn backs_off_failed_reconnects() {
+        let mut manager = manager(1);
+        manager.handle_reading(0, Err(ProtocolError::Io(io::ErrorKind::Other.into())), None);
+        assert!(manager.start_reconnects().is_empty());
+
+        let mut delays = Vec::new();
+        for _ in 0..8 {
+            delays.push(make_due(&mut manager));
+            assert_eq!(manager.start_reconnects().len(), 1);
+            assert!(manager.next_reconnect().is_none());
+            let failed = Err(FirmwareError::Protocol(ProtocolError::Timeout));
+            assert!(manager.finish_reconnect(0, failed).is_empty());
+        }
+        let seconds: Vec<f64> = delays.iter().map(Duration::as_secs_f64).collect();
+        assert_eq!(seconds, [0.5, 1.0, 2.0, 4.0, 8.0, 16.0, 30.0, 30.0]);
+    }
+
+    #[test]
+    fn reconnects_and_retunes() {
+        let mut manager = manager(2);
+        manager.handle_reading(0, Err(ProtocolError::Io(io::ErrorKind::Other.into())), None);
+        manager.take_link_events();
+        make_due(&mut manager);
+        manager.start_reconnects();
+
+        let mut backend = StubBackend::new(Instant::now());
+        let opened = open_device(&mut backend, Sampling::Poll);
+        let retune = manager.finish_reconnect(0, opened);
+        assert_eq!(retune, [(0, 5658), (1, 5658)]);
+        assert!(manager.next_reconnect().is_none());
+        assert!(manager
+            .take_link_events()
+            .iter()
+            .all(|event| matches!(event, LinkEvent::Reconnected { .. })));
+        assert!(manager.status().iter().all(|node| node.connected));
+    }
+
+    #[test]
+    fn refuses_firmware_that_cannot_drive_the_nodes() {
+        let mut manager = manager(2);
+        manager.handle_reading(0, Err(ProtocolError::Io(io::ErrorKind::Other.into())), None);
+        make_due(&mut manager);
+        manager.start_reconnects();
+
+        // Flashed back to firmware without receiver slots.
+        let legacy = OpenDevice {
+            firmware: Firmware::legacy(),
+            streaming: false,
+        };
+        assert!(manager.finish_reconnect(0, Ok(legacy)).is_empty());
+        assert_eq!(make_due(&mut manager), RECONNECT_DELAY * 2);
+        assert_eq!(
+            manager.devices[0].firmware,
+            manager.nodes[0].reader.firmware
+        );
+        assert!(manager.nodes[0].reader.firmware.capabilities.slots);
+    }
+}
@@ -0,0 +1,410 @@
+use crate::config::PassConfig;
+use crate::node_manager::Sample;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Samples kept of one pass; a drone parked by the gate stops adding more,
+/// and one hovering short of the enter threshold only keeps its latest.
+const MAX_PASS_SAMPLES: usize = 4096;
+
+/// Lap detected from a gate pass.
+#[derive(Debug, Clone, Copy)]
+pub struct HostLap {
+    /// Laps since the race start, counting from 1.
+    pub number: u32,
+    /// Estimated crossing time, see [`estimate_crossing`].
+    pub at: Instant,
+    /// Standard uncertainty of `at`.
+    pub uncertainty: Duration,
+    /// Time of the highest RSSI of the pass.
+    pub peak_at: Instant,
+}
+
+enum State {
+    /// RSSI at or below the exit threshold.
+    Clear,
+    /// Above the exit threshold, not yet at the enter one: the pass only
+    /// counts if it gets there before falling back.
+    Approaching(VecDeque<Sample>),
+    /// The pass reached the enter threshold and ends at the exit one.
+    Passing(Vec<Sample>),
+}
+
+/// Enter/exit hysteresis on a node's RSSI, run on every sample as it comes
+/// in. A lap is reported when its pass ends. A pass crossing sooner than the
+/// minimum lap time after the last lap is taken for the same one, e.g. a
+/// multipath notch dipping below the exit threshold, and dropped.
+pub struct PassDetector {
+    thresholds: PassConfig,
+    state: State,
+    laps: u32,
+    last_lap: Option<Instant>,
+}
+
+impl PassDetector {
+    pub fn new(thresholds: PassConfig) -> Self {
+        PassDetector {
+            thresholds,
+            state: State::Clear,
+            laps: 0,
+            last_lap: None,
+        }
+    }
+
+    pub fn thresholds(&self) -> PassConfig {
+        self.thresholds
+    }
+
+    /// Switch thresholds, e.g. to a calibrated profile. A pass in progress
+    /// carries on.
+    pub fn set_thresholds(&mut self, thresholds: PassConfig) {
+        self.thresholds = thresholds;
+    }
+
+    /// Forget the pass in progress and count laps from 1 again.
+    pub fn reset(&mut self) {
+        self.state = State::Clear;
+        self.laps = 0;
+        self.last_lap = None;
+    }
+
+    /// Feeds one sample and returns the lap whose pass it ended, if any.
+    pub fn update(&mut self, sample: Sample) -> Option<HostLap> {
+        let PassConfig {
+            enter_rssi,
+            exit_rssi,
+            min_lap_s,
+        } = self.thresholds;
+
+        self.state = match std::mem::replace(&mut self.state, State::Clear) {
+            State::Clear | State::Approaching(_) if sample.rssi <= exit_rssi => State::Clear,
+            State::Passing(samples) if sample.rssi <= exit_rssi => {
+                let (at, uncertainty) = estimate_crossing(&samples, exit_rssi);
+                // Out of range only past validation; then no pass is far enough.
+                let min_lap = Duration::try_from_secs_f64(min_lap_s).unwrap_or(Duration::MAX);
+                if self
+                    .last_lap
+                    .is_some_and(|last| at.saturating_duration_since(last) < min_lap)
+                {
+                    return None;
+                }
+                self.last_lap = Some(at);
+                self.laps += 1;
+                return Some(HostLap {
+                    number: self.laps,
+                    at,
+                    uncertainty,
+                    peak_at: peak(&samples).at,
+                });
+            }
+            State::Clear => approach(VecDeque::new(), sample, enter_rssi),
+            State::Approaching(samples) => approach(samples, sample, enter_rssi),
+            State::Passing(mut samples) => {
+                if samples.len() < MAX_PASS_SAMPLES {
+                    samples.push(sample);
+                }
+                State::Passing(samples)
+            }
+        };
+        None
+    }
+}
+
+/// Add a sample above the exit threshold to a pass not confirmed yet.
+fn approach(mut samples: VecDeque<Sample>, sample: Sample, enter_rssi: u32) -> State {
+    if samples.len() == MAX_PASS_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+    if sample.rssi >= enter_rssi {
+        State::Passing(samples.into())
+    } else {
+        State::Approaching(samples)
+    }
+}
+
+fn peak(samples: &[Sample]) -> Sample {
+    *samples
+        .iter()
+        .max_by_key(|sample| sample.rssi)
+        .expect("a pass has at least one sample")
+}
+
+/// Estimate when the drone crossed the gate from the samples of its pass,
+/// with the standard uncertainty of the estimate.
+///
+/// Above the noise floor a pass is close to a Gaussian in time, so the
+/// logarithm of the RSSI over the exit threshold is fitted with a parabola
+/// whose vertex is the crossing, placing it between samples. The uncertainty
+/// carries the fit residuals over to the vertex. Passes too short or too
+/// ragged to fit fall back to the centroid of the RSSI over the threshold, to
+/// within half the widest gap between samples.
+fn estimate_crossing(samples: &[Sample], exit_rssi: u32) -> (Instant, Duration) {
+    let origin = peak(samples).at;
+    let points: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|sample| {
+            let t = if sample.at >= origin {
+                sample.at.duration_since(origin).as_secs_f64()
+            } else {
+                -origin.duration_since(sample.at).as_secs_f64()
+            };
+            (t, sample.rssi.saturating_sub(exit_rssi) as f64)
+        })
+        .collect();
+    let first = points.first().map_or(0.0, |&(t, _)| t);
+    let last = points.last().map_or(0.0, |&(t, _)| t);
+
+    let (offset, sigma) = fit_gaussian(&points)
+        .filter(|&(offset, _)| (first..=last).contains(&offset))
+        .unwrap_or_else(|| centroid(&points));
+    let at = if offset >= 0.0 {
+        origin + Duration::from_secs_f64(offset)
+    } else {
+        origin
+            .checked_sub(Duration::from_secs_f64(-offset))
+            .unwrap_or(origin)
+    };
+    (at, Duration::from_secs_f64(sigma))
+}
+
+/// Least-squares fit of `ln(y) = a + b t + c t²`, returning the vertex
+/// `-b / 2c` and its standard error. Needs a dome, and more points than
+/// parameters to have residuals at all.
+fn fit_gaussian(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let n = points.len();
+    if n < 4 {
+        return None;
+    }
+    // Sums of the powers of t, and of ln(y) times them.
+    let mut s = [0.0; 5];
+    let mut r = [0.0; 3];
+    for &(t, y) in points {
+        let mut power = 1.0;
+        for (k, sum) in s.iter_mut().enumerate() {
+            *sum += power;
+            if let Some(r) = r.get_mut(k) {
+                *r += power * y.ln();
+            }
+            power *= t;
+        }
+    }
+    let inverse = invert([[s[0], s[1], s[2]], [s[1], s[2], s[3]], [s[2], s[3], s[4]]])?;
+    let coefficient = |row: usize| (0..3).map(|k| inverse[row][k] * r[k]).sum::<f64>();
+    let (a, b, c) = (coefficient(0), coefficient(1), coefficient(2));
+    if c >= 0.0 {
+        return None;
+    }
+
+    let residuals: f64 = points
+        .iter()
+        .map(|&(t, y)| (y.ln() - (a + b * t + c * t * t)).powi(2))
+        .sum();
+    let variance = residuals / (n - 3) as f64;
+    let vertex = -b / (2.0 * c);
+    // Gradient of the vertex in (b, c).
+    let (db, dc) = (-1.0 / (2.0 * c), b / (2.0 * c * c));
+    let vertex_variance = variance
+        * (db * db * inverse[1][1] + 2.0 * db * dc * inverse[1][2] + dc * dc * inverse[2][2]);
+    (vertex.is_finite() && vertex_variance.is_finite())
+        .then(|| (vertex, vertex_variance.max(0.0).sqrt()))
+}
+
+fn centroid(points: &[(f64, f64)]) -> (f64, f64) {
+    let weight: f64 = points.iter().map(|&(_, y)| y).sum();
+    let offset = points.iter().map(|&(t, y)| t * y).sum::<f64>() / weight;
+    let widest_gap = points
+        .windows(2)
+        .map(|pair| pair[1].0 - pair[0].0)
+        .fold(0.0, f64::max);
+    (offset, widest_gap / 2.0)
+}
+
+/// Inverse of a symmetric 3×3 matrix, unless it is singular.
+fn invert(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    if determinant == 0.0 || !determinant.is_finite() {
+        return None;
+    }
+    let mut inverse = [[0.0; 3]; 3];
+    for (r, row) in inverse.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = cofactor(c, r) / determinant;
+        }
+    }
+    Some(inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(start: Instant, at_ms: u64, rssi: u32) -> Sample {
+        Sample {
+            rssi,
+            at: start + Duration::from_millis(at_ms),
+            lap_id: 0,
+            ms_since_lap: 0,
+            latency: None,
+        }
+    }
+
+    fn detector() -> PassDetector {
+        PassDetector::new(PassConfig {
+            enter_rssi: 90,
+            exit_rssi: 80,
+            min_lap_s: 5.0,
+        })
+    }
+
+    /// Feed `rssi` at one sample every 10 ms from `from_ms`, returning the laps.
+    fn feed(
+        detector: &mut PassDetector,
+        start: Instant,
+        from_ms: u64,
+        rssi: &[u32],
+    ) -> Vec<HostLap> {
+        rssi.iter()
+            .enumerate()
+            .filter_map(|(i, &rssi)| detector.update(sample(start, from_ms + i as u64 * 10, rssi)))
+            .collect()
+    }
+
+    #[test]
+    fn counts_a_pass_once_it_ends() {
+        let start = Instant::now();
+        let mut detector = detector();
+
+        let laps = feed(&mut detector, start, 0, &[70, 85, 95, 100, 95, 85]);
+        assert!(laps.is_empty());
+        let laps = feed(&mut detector, start, 60, &[75]);
+        assert_eq!(laps.len(), 1);
+        assert_eq!(laps[0].number, 1);
+        assert_eq!(laps[0].peak_at, start + Duration::from_millis(30));
+    }
+
+    #[test]
+    fn ignores_noise_between_thresholds() {
+        let start = Instant::now();
+        let mut detector = detector();
+
+        // Rising over the exit threshold without reaching the enter one.
+        let laps = feed(&mut detector, start, 0, &[70, 85, 89, 85, 80, 86, 75]);
+        assert!(laps.is_empty());
+        // Dipping to the enter threshold and back inside a pass splits nothing.
+        let laps = feed(&mut detector, start, 100, &[95, 85, 95, 85, 70]);
+        assert_eq!(laps.len(), 1);
+    }
+
+    #[test]
+    fn drops_passes_within_min_lap() {
+        let start = Instant::now();
+        let mut detector = detector();
+
+        assert_eq!(feed(&mut detector, start, 0, &[95, 70]).len(), 1);
+        // A multipath notch one second on is the same lap.
+        assert!(feed(&mut detector, start, 1000, &[95, 70]).is_empty());
+        let laps = feed(&mut detector, start, 6000, &[95, 70]);
+        assert_eq!(laps.len(), 1);
+        assert_eq!(laps[0].number, 2);
+    }
+
+    #[test]
+    fn counts_from_one_after_reset() {
+        let start = Instant::now();
+        let mut detector = detector();
+
+        feed(&mut detector, start, 0, &[95, 100]);
+        detector.reset();
+        assert!(feed(&mut detector, start, 20, &[70]).is_empty());
+        let laps = feed(&mut detector, start, 10_000, &[95, 70]);
+        assert_eq!(laps[0].number, 1);
+    }
+
+    #[test]
+    fn caps_samples_of_a_hovering_drone() {
+        let start = Instant::now();
+        let mut detector = detector();
+
+        let hovering = vec![85; MAX_PASS_SAMPLES + 100];
+        assert!(feed(&mut detector, start, 0, &hovering).is_empty());
+        match &detector.state {
+            State::Approaching(samples) => assert_eq!(samples.len(), MAX_PASS_SAMPLES),
+            _ => panic!("expected an approach"),
+        }
+
+        let passing = vec![95; MAX_PASS_SAMPLES + 100];
+        feed(&mut detector, start, 100_000, &passing);
+        match &detector.state {
+            State::Passing(samples) => assert_eq!(samples.len(), MAX_PASS_SAMPLES),
+            _ => panic!("expected a pass"),
+        }
+    }
+
+    #[test]
+    fn fits_the_vertex_between_samples() {
+        // A Gaussian pass peaking 3.7 ms after the sample at 0.
+        let points: Vec<(f64, f64)> = (-10..=10)
+            .map(|i| {
+                let t = i as f64 * 0.01;
+                (
+                    t,
+                    60.0 * (-(t - 0.0037).powi(2) / (2.0 * 0.03f64.powi(2))).exp(),
+                )
+            })
+            .collect();
+
+        let (vertex, sigma) = fit_gaussian(&points).unwrap();
+        assert!((vertex - 0.0037).abs() < 1e-9, "{}", vertex);
+        assert!(sigma < 1e-6, "{}", sigma);
+    }
+
+    #[test]
+    fn rejects_passes_without_a_dome() {
+        let valley: Vec<(f64, f64)> = (-3..=3).map(|i| (i as f64, 1.0 + (i * i) as f64)).collect();
+        assert!(fit_gaussian(&valley).is_none());
+        assert!(fit_gaussian(&valley[..3]).is_none());
+    }
+
+    #[test]
+    fn estimates_crossing_from_rssi() {
+        let start = Instant::now();
+        // Peaking at 503 ms with 20 over the exit threshold, rounded as a
+        // node reports it.
+        let samples: Vec<Sample> = (40..=60)
+            .map(|i| {
+                let t = i as f64 * 0.01 - 0.503;
+                let rssi = 80.0 + 20.0 * (-t * t / (2.0 * 0.03f64.powi(2))).exp();
+                sample(start, i * 10, rssi.round() as u32)
+            })
+            .filter(|sample| sample.rssi > 80)
+            .collect();
+
+        let (at, uncertainty) = estimate_crossing(&samples, 80);
+        let error = at.duration_since(start).as_secs_f64() - 0.503;
+        assert!(error.abs() < 0.003, "{}", error);
+        assert!(uncertainty < Duration::from_millis(5), "{:?}", uncertainty);
+    }
+
+    #[test]
+    fn falls_back_to_centroid_on_short_passes() {
+        let start = Instant::now();
+        let samples = [
+            sample(start, 0, 85),
+            sample(start, 10, 95),
+            sample(start, 30, 95),
+        ];
+
+        // Weights 5, 15, 15 over the exit threshold.
+        let (at, uncertainty) = estimate_crossing(&samples, 80);
+        let expected = (10.0 * 15.0 + 30.0 * 15.0) / 35.0;
+        let offset = at.duration_since(start).as_secs_f64() * 1000.0;
+        assert!((offset - expected).abs() < 0.01, "{}", offset);
+        assert_eq!(uncertainty, Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::config::MAX_SECONDS;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How a race is decided and its pilots ranked, e.g.
+/// `{"kind": "first_to_laps", "laps": 3, "grace_s": 30}`. Once the race is
+/// decided, pilots have `grace_s` to finish the lap they are on; that lap
+/// counts, later ones do not.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RaceFormat {
+    /// Runs until stopped; most laps first.
+    #[default]
+    Open,
+    /// Decided after `duration_s`; most laps first.
+    Timed {
+        duration_s: f64,
+        #[serde(default)]
+        grace_s: f64,
+    },
+    /// Decided when a pilot completes `laps` laps; the first to get there
+    /// wins.
+    FirstToLaps {
+        laps: u32,
+        #[serde(default)]
+        grace_s: f64,
+    },
+    /// Decided after `duration_s`; fastest `laps` consecutive laps first.
+    BestConsecutive {
+        laps: u32,
+        duration_s: f64,
+        #[serde(default)]
+        grace_s: f64,
+    },
+    /// Decided after `duration_s`; fastest lap first.
+    BestLap {
+        duration_s: f64,
+        #[serde(default)]
+        grace_s: f64,
+    },
+}
+
+impl RaceFormat {
+    pub fn validate(&self) -> Result<(), String> {
+        let seconds = |value: f64| (0.0..=MAX_SECONDS).contains(&value);
+        let valid = match *self {
+            RaceFormat::Open => true,
+            RaceFormat::Timed {
+                duration_s,
+                grace_s,
+            }
+            | RaceFormat::BestLap {
+                duration_s,
+                grace_s,
+            } => duration_s > 0.0 && seconds(duration_s) && seconds(grace_s),
+            RaceFormat::FirstToLaps { laps, grace_s } => laps > 0 && seconds(grace_s),
+            RaceFormat::BestConsecutive {
+                laps,
+                duration_s,
+                grace_s,
+            } => laps > 0 && duration_s > 0.0 && seconds(duration_s) && seconds(grace_s),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("invalid race format {:?}", self))
+        }
+    }
+
+    /// Race time after which the race is decided, for timed formats.
+    pub fn duration(&self) -> Option<Duration> {
+        match *self {
+            RaceFormat::Timed { duration_s, .. }
+            | RaceFormat::BestConsecutive { duration_s, .. }
+            | RaceFormat::BestLap { duration_s, .. } => Some(seconds(duration_s)),
+            RaceFormat::Open | RaceFormat::FirstToLaps { .. } => None,
+        }
+    }
+
+    /// Laps that decide the race once a pilot completes them.
+    pub fn target_laps(&self) -> Option<u32> {
+        match *self {
+            RaceFormat::FirstToLaps { laps, .. } => Some(laps),
+            _ => None,
+        }
+    }
+
+    pub fn grace(&self) -> Duration {
+        match *self {
+            RaceFormat::Open => Duration::ZERO,
+            RaceFormat::Timed { grace_s, .. }
+            | RaceFormat::FirstToLaps { grace_s, .. }
+            | RaceFormat::BestConsecutive { grace_s, .. }
+            | RaceFormat::BestLap { grace_s, .. } => seconds(grace_s),
+        }
+    }
+}
+
+/// Seconds as a duration. Formats failing [`RaceFormat::validate`] can be out
+/// of range; they saturate, so deadlines computed from them fail instead.
+fn seconds(value: f64) -> Duration {
+    Duration::try_from_secs_f64(value).unwrap_or(Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decides_by_time_or_laps() {
+        let timed = RaceFormat::Timed {
+            duration_s: 90.0,
+            grace_s: 10.0,
+        };
+        assert_eq!(timed.duration(), Some(Duration::from_secs(90)));
+        assert_eq!(timed.target_laps(), None);
+        assert_eq!(timed.grace(), Duration::from_secs(10));
+
+        let first_to = RaceFormat::FirstToLaps {
+            laps: 3,
+            grace_s: 0.0,
+        };
+        assert_eq!(first_to.duration(), None);
+        assert_eq!(first_to.target_laps(), Some(3));
+
+        assert_eq!(RaceFormat::Open.duration(), None);
+        assert_eq!(RaceFormat::Open.grace(), Duration::ZERO);
+    }
+
+    #[test]
+    fn parses_with_default_grace() {
+        let format: RaceFormat =
+            serde_json::from_str(r#"{"kind": "best_lap", "duration_s": 120}"#).unwrap();
+        assert_eq!(
+            format,
+            RaceFormat::BestLap {
+                duration_s: 120.0,
+                grace_s: 0.0
+            }
+        );
+    }
+
+    #[test]
+    fn validates_parameters() {
+        assert!(RaceFormat::Open.validate().is_ok());
+        for invalid in [
+            RaceFormat::Timed {
+                duration_s: 0.0,
+                grace_s: 0.0,
+            },
+            RaceFormat::FirstToLaps {
+                laps: 0,
+                grace_s: 0.0,
+            },
+            RaceFormat::BestConsecutive {
+                laps: 3,
+                duration_s: 60.0,
+                grace_s: -1.0,
+            },
+            RaceFormat::BestLap {
+                duration_s: f64::NAN,
+                grace_s: 0.0,
+            },
+            RaceFormat::Timed {
+                duration_s: 1e20,
+                grace_s: 0.0,
+            },
+            RaceFormat::FirstToLaps {
+                laps: 3,
+                grace_s: MAX_SECONDS + 1.0,
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+}
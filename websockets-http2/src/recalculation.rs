@@ -0,0 +1,250 @@
+use crate::config::PassConfig;
+use crate::node_manager::Sample;
+use crate::pass_detector::PassDetector;
+use crate::structs::lap::{CreateLap, Lap, LapChange, LapDiff};
+use crate::structs::node::Node;
+use std::collections::BTreeMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Live and recalculated laps crossing within this many seconds of each
+/// other are taken for the same lap.
+const MATCH_WINDOW_S: f64 = 1.0;
+/// A lap moving less than this is unchanged: the stored RSSI is coarser than
+/// the live samples, so crossings estimated from it shift by a few
+/// milliseconds.
+const UNCHANGED_WITHIN_S: f64 = 0.05;
+
+/// Run host-side lap detection again over a race's stored RSSI. The `node`
+/// rows only keep the RSSI when it moved, already filtered, so each row
+/// becomes one sample at its start; the detector sees the same steps the
+/// live one saw, just without the samples in between.
+pub fn recalculate(race_id: i32, rows: &[Node], thresholds: PassConfig) -> Vec<CreateLap> {
+    let mut by_node: BTreeMap<i32, Vec<&Node>> = BTreeMap::new();
+    for row in rows {
+        by_node.entry(row.node_index).or_default().push(row);
+    }
+
+    let start = Instant::now();
+    let at = |seconds: f64| start + Duration::from_secs_f64(seconds.max(0.0));
+    let race_time = |instant: Instant| instant.duration_since(start).as_secs_f64();
+    let mut laps = Vec::new();
+    for (node_index, mut rows) in by_node {
+        rows.sort_by(|a, b| a.time.total_cmp(&b.time));
+        let mut detector = PassDetector::new(thresholds);
+        for row in rows {
+            let sample = Sample {
+                rssi: row.peak,
+                at: at(row.time),
+                lap_id: 0,
+                ms_since_lap: 0,
+                latency: None,
+            };
+            if let Some(lap) = detector.update(sample) {
+                laps.push(CreateLap {
+                    race_id,
+                    node_index,
+                    time: race_time(lap.at),
+                    host_time: Some(race_time(lap.peak_at)),
+                    uncertainty: Some(lap.uncertainty.as_secs_f64()),
+                });
+            }
+        }
+    }
+    laps
+}
+
+/// Pair each node's live laps with recalculated ones crossing close to them,
+/// in time order; what is left over was added or removed by the
+/// recalculation.
+pub fn diff(
+    race_id: i32,
+    live: &[Lap],
+    mut recalculated: Vec<CreateLap>,
+    hand_edits: usize,
+) -> LapDiff {
+    let mut live: Vec<&Lap> = live.iter().collect();
+    live.sort_by(|a, b| {
+        a.node_index
+            .cmp(&b.node_index)
+            .then(a.time.total_cmp(&b.time))
+    });
+    recalculated.sort_by(|a, b| {
+        a.node_index
+            .cmp(&b.node_index)
+            .then(a.time.total_cmp(&b.time))
+    });
+    let mut result = LapDiff {
+        race_id,
+        token: token(race_id, &live, &recalculated),
+        added: Vec::new(),
+        removed: Vec::new(),
+        changed: Vec::new(),
+        unchanged: 0,
+        hand_edits,
+        laps: recalculated.clone(),
+    };
+    let mut live = live.into_iter().peekable();
+    let mut recalculated = recalculated.into_iter().peekable();
+
+    loop {
+        match (live.peek(), recalculated.peek()) {
+            (Some(old), Some(new))
+                if old.node_index == new.node_index
+                    && (old.time - new.time).abs() <= MATCH_WINDOW_S =>
+            {
+                let (old, new) = (live.next().unwrap(), recalculated.next().unwrap());
+                if (old.time - new.time).abs() <= UNCHANGED_WITHIN_S {
+                    result.unchanged += 1;
+                } else {
+                    result.changed.push(LapChange {
+                        live: old.clone(),
+                        recalculated: new,
+                    });
+                }
+            }
+            (Some(old), Some(new)) if (old.node_index, old.time) < (new.node_index, new.time) => {
+                result.removed.push(live.next().unwrap().clone());
+            }
+            (Some(_), Some(_)) | (None, Some(_)) => {
+                result.added.push(recalculated.next().unwrap());
+            }
+            (Some(_), None) => result.removed.push(live.next().unwrap().clone()),
+            (None, None) => break,
+        }
+    }
+    result
+}
+
+/// Fingerprint of the laps a diff compared. Accepting a diff takes it back,
+/// so what is applied is what was reviewed: it no longer matches once the
+/// stored laps changed.
+fn token(race_id: i32, live: &[&Lap], recalculated: &[CreateLap]) -> String {
+    let mut hasher = DefaultHasher::new();
+    race_id.hash(&mut hasher);
+    for lap in live {
+        (lap.id, lap.node_index, lap.lap_number, lap.time.to_bits()).hash(&mut hasher);
+    }
+    for lap in recalculated {
+        (lap.node_index, lap.time.to_bits()).hash(&mut hasher);
+        (
+            lap.host_time.map(f64::to_bits),
+            lap.uncertainty.map(f64::to_bits),
+        )
+            .hash(&mut hasher);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live(id: i32, node_index: i32, time: f64) -> Lap {
+        Lap {
+            id,
+            race_id: 1,
+            node_index,
+            lap_number: 0,
+            time,
+            host_time: Some(time),
+            uncertainty: None,
+        }
+    }
+
+    fn recalculated(node_index: i32, time: f64) -> CreateLap {
+        CreateLap {
+            race_id: 1,
+            node_index,
+            time,
+            host_time: Some(time),
+            uncertainty: Some(0.01),
+        }
+    }
+
+    fn row(node_index: i32, time: f64, peak: u32) -> Node {
+        Node {
+            id: 0,
+            node_index,
+            peak,
+            time,
+            duration: 0.0,
+            race_id: 1,
+        }
+    }
+
+    #[test]
+    fn detects_laps_from_stored_rssi() {
+        let mut rows = Vec::new();
+        for time in [10.0, 20.0] {
+            rows.extend([
+                row(0, time - 1.0, 50),
+                row(0, time, 95),
+                row(0, time + 0.5, 50),
+            ]);
+        }
+        rows.extend([row(1, 5.0, 50), row(1, 6.0, 85), row(1, 7.0, 50)]);
+        let laps = recalculate(1, &rows, PassConfig::default());
+        // Node 1 never reached the enter threshold.
+        assert_eq!(laps.len(), 2);
+        for (lap, time) in laps.iter().zip([10.0, 20.0]) {
+            assert_eq!(lap.node_index, 0);
+            assert!((lap.time - time).abs() < 1e-6, "{:?}", lap);
+        }
+        // A stricter enter threshold loses both passes.
+        let strict = PassConfig {
+            enter_rssi: 100,
+            ..PassConfig::default()
+        };
+        assert!(recalculate(1, &rows, strict).is_empty());
+    }
+
+    #[test]
+    fn sorts_laps_into_added_removed_and_moved() {
+        let stored = [
+            live(1, 0, 10.0),
+            live(2, 0, 20.0),
+            live(3, 0, 30.0),
+            live(4, 1, 12.0),
+        ];
+        let found = vec![
+            recalculated(0, 40.0),
+            recalculated(0, 10.01),
+            recalculated(0, 20.5),
+        ];
+        let diff = diff(1, &stored, found, 0);
+
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].live.id, 2);
+        assert_eq!(diff.changed[0].recalculated.time, 20.5);
+        let removed: Vec<i32> = diff.removed.iter().map(|lap| lap.id).collect();
+        assert_eq!(removed, [3, 4]);
+        let added: Vec<f64> = diff.added.iter().map(|lap| lap.time).collect();
+        assert_eq!(added, [40.0]);
+        let laps: Vec<f64> = diff.laps.iter().map(|lap| lap.time).collect();
+        assert_eq!(laps, [10.01, 20.5, 40.0]);
+    }
+
+    #[test]
+    fn token_goes_stale_when_the_laps_change() {
+        let stored = vec![live(1, 0, 10.0), live(2, 0, 20.0)];
+        let found = vec![recalculated(0, 10.0), recalculated(0, 20.0)];
+        let reviewed = diff(1, &stored, found.clone(), 0).token;
+        assert_eq!(diff(1, &stored, found.clone(), 0).token, reviewed);
+
+        // A lap moved by hand after the review.
+        let mut moved = stored.clone();
+        moved[1].time = 21.0;
+        assert_ne!(diff(1, &moved, found.clone(), 0).token, reviewed);
+        // A lap crossed after the review.
+        let mut crossed = stored.clone();
+        crossed.push(live(3, 0, 30.0));
+        assert_ne!(diff(1, &crossed, found.clone(), 0).token, reviewed);
+        // Other thresholds found other laps.
+        let other = vec![recalculated(0, 10.0)];
+        assert_ne!(diff(1, &stored, other, 0).token, reviewed);
+        // Another race.
+        assert_ne!(diff(2, &stored, found, 0).token, reviewed);
+    }
+}
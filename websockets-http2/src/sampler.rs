@@ -0,0 +1,172 @@
+use crate::node_manager::{self, NodeReader, Sample};
+use rustimer::protocol::ProtocolError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use tokio::sync::mpsc;
+
+/// Results of one pass over a device's nodes, by node index.
+pub type Readings = Vec<(usize, Result<Sample, ProtocolError>)>;
+
+/// Passes a worker may fall behind by before the reader threads wait for it.
+pub const QUEUE_LEN: usize = 64;
+
+struct Control {
+    /// Nodes to read; empty while the device is down or there is nothing to
+    /// read it for.
+    readers: Vec<NodeReader>,
+    stop: bool,
+}
+
+/// Long-lived reader thread of one device. It reads the device's nodes over
+/// and over and queues the timestamped samples for the worker, so reads run
+/// whatever the worker is busy with, and the worker only takes finished
+/// results. Slots of one device share its port, so they share the thread.
+pub struct DeviceSampler {
+    control: Arc<(Mutex<Control>, Condvar)>,
+}
+
+impl DeviceSampler {
+    pub fn spawn(name: &str, sender: mpsc::Sender<Readings>) -> Self {
+        let control = Arc::new((
+            Mutex::new(Control {
+                readers: Vec::new(),
+                stop: false,
+            }),
+            Condvar::new(),
+        ));
+        let thread_control = Arc::clone(&control);
+        thread::Builder::new()
+            .name(format!("sampler {}", name))
+            .spawn(move || run(&thread_control, &sender))
+            .expect("failed to spawn a sampler thread");
+        DeviceSampler { control }
+    }
+
+    /// Replace the nodes to read. A read in progress finishes first.
+    pub fn set_readers(&self, readers: Vec<NodeReader>) {
+        let (control, wake) = &*self.control;
+        control.lock().unwrap().readers = readers;
+        wake.notify_one();
+    }
+}
+
+impl Drop for DeviceSampler {
+    fn drop(&mut self) {
+        let (control, wake) = &*self.control;
+        control.lock().unwrap().stop = true;
+        wake.notify_one();
+    }
+}
+
+fn run(control: &(Mutex<Control>, Condvar), sender: &mpsc::Sender<Readings>) {
+    let (control, wake) = control;
+    loop {
+        let readers = {
+            let mut control = control.lock().unwrap();
+            while control.readers.is_empty() && !control.stop {
+                control = wake.wait(control).unwrap();
+            }
+            if control.stop {
+                return;
+            }
+            control.readers.clone()
+        };
+        let readings = node_manager::read_all(&readers);
+        if sender.blocking_send(readings).is_err() {
+            // The worker is gone.
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BackendKind, Config, NodeConfig, SimulatorConfig};
+    use crate::node_manager::NodeManager;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Readers of two nodes on one simulated device.
+    fn readers() -> Vec<NodeReader> {
+        let config = Config {
+            backend: BackendKind::Simulator,
+            simulator: SimulatorConfig {
+                sample_interval_ms: 1,
+                seed: Some(1),
+                ..SimulatorConfig::default()
+            },
+            nodes: (0..2)
+                .map(|slot| NodeConfig {
+                    slot: Some(slot),
+                    ..NodeConfig::new("sim")
+                })
+                .collect(),
+            ..Config::default()
+        };
+        let nodes = NodeManager::open(&config, None).unwrap();
+        (0..2).map(|index| nodes.reader(index).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn reads_the_nodes_it_is_given() {
+        let (sender, mut receiver) = mpsc::channel(QUEUE_LEN);
+        let sampler = DeviceSampler::spawn("sim", sender);
+        sampler.set_readers(readers());
+
+        for _ in 0..3 {
+            let readings = timeout(WAIT, receiver.recv()).await.unwrap().unwrap();
+            let indices: Vec<usize> = readings.iter().map(|(index, _)| *index).collect();
+            assert_eq!(indices, [0, 1]);
+            assert!(readings.iter().all(|(_, result)| result.is_ok()));
+        }
+
+        sampler.set_readers(readers().split_off(1));
+        // A pass may still be under way with both.
+        let mut readings = timeout(WAIT, receiver.recv()).await.unwrap().unwrap();
+        if readings.len() == 2 {
+            readings = timeout(WAIT, receiver.recv()).await.unwrap().unwrap();
+        }
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].0, 1);
+    }
+
+    #[tokio::test]
+    async fn idles_without_nodes_and_stops_when_dropped() {
+        let (sender, mut receiver) = mpsc::channel(QUEUE_LEN);
+        let sampler = DeviceSampler::spawn("sim", sender);
+        assert!(timeout(Duration::from_millis(50), receiver.recv())
+            .await
+            .is_err());
+
+        sampler.set_readers(readers());
+        timeout(WAIT, receiver.recv()).await.unwrap().unwrap();
+        sampler.set_readers(Vec::new());
+        drop(sampler);
+        // The thread stops after the pass under way, dropping its sender.
+        let drained = timeout(WAIT, async { while receiver.recv().await.is_some() {} });
+        assert!(drained.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_worker_that_falls_behind() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let sampler = DeviceSampler::spawn("sim", sender);
+        sampler.set_readers(readers());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // One pass queued and one blocked on the full queue, both read
+        // before the worker caught up; the thread read no further.
+        let caught_up = std::time::Instant::now();
+        let mut at = Vec::new();
+        for _ in 0..3 {
+            let readings = timeout(WAIT, receiver.recv()).await.unwrap().unwrap();
+            at.push(readings[0].1.as_ref().unwrap().at);
+        }
+        assert!(at[0] < at[1] && at[1] < caught_up);
+        assert!(at[2] > caught_up);
+        drop(sampler);
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// Pass thresholds calibrated for a node, and for one pilot when `pilot` is
+/// not empty. The live detector uses the profile of the pilot flying the
+/// node, or the node's own when that pilot has none.
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct PassProfile {
+    pub node_index: i32,
+    pub pilot: String,
+    /// RSSI with the pilot idle, and its spread as a standard deviation.
+    pub noise_floor: f64,
+    pub noise_sd: f64,
+    /// Peak of the weakest recorded flyover.
+    pub weakest_peak: u32,
+    pub enter_rssi: u32,
+    pub exit_rssi: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StartCalibration {
+    /// Calibrate for this pilot rather than for the node.
+    pub pilot: Option<String>,
+    /// How long to measure the noise floor before recording flyovers, at
+    /// most ten minutes.
+    pub noise_s: Option<f64>,
+}
+
+/// Pilot flying a node; `None` to go back to the node's own profile.
+#[derive(Debug, Deserialize)]
+pub struct SetPilot {
+    pub pilot: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CalibrationStatus {
+    pub node_index: usize,
+    pub pilot: Option<String>,
+    /// `noise_floor` while the pilot should stay idle, then `flyovers`.
+    pub phase: &'static str,
+    pub noise_floor: Option<f64>,
+    pub noise_sd: Option<f64>,
+    /// Peak of every flyover recorded so far.
+    pub flyovers: Vec<u32>,
+    /// Thresholds finishing now would save.
+    pub proposal: Option<PassProfile>,
+    /// Why there is no proposal yet.
+    pub issue: Option<String>,
+}
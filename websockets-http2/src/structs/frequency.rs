@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct NodeFrequency {
+    pub node_index: i32,
+    pub frequency: u16,
+}
+
+/// Filter chain set on a node, as JSON.
+#[derive(Debug, FromRow, Clone)]
+pub struct NodeFilters {
+    pub node_index: i32,
+    pub filters: String,
+}
+
+/// Either an explicit frequency in MHz, or a band and 1-based channel such as
+/// `{"band": "R", "channel": 1}`.
+#[derive(Debug, Deserialize)]
+pub struct SetFrequency {
+    pub frequency: Option<u16>,
+    pub band: Option<String>,
+    pub channel: Option<u8>,
+}
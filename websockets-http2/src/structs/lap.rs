@@ -0,0 +1,117 @@
+use crate::structs::leaderboard::Standing;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// Lap counted by a node or detected on the host. `host_time` is the RSSI
+/// peak host-side detection saw around the pass, for checking the crossing
+/// time against; both times are in seconds since the race start.
+/// `uncertainty` is the standard uncertainty of `time` in seconds, for laps
+/// whose crossing the host estimated.
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct Lap {
+    pub id: i32,
+    pub race_id: i32,
+    pub node_index: i32,
+    pub lap_number: i32,
+    pub time: f64,
+    pub host_time: Option<f64>,
+    pub uncertainty: Option<f64>,
+}
+
+/// Lap to store. It has no number of its own: laps are numbered by their
+/// crossing order among the node's laps of the race as they are stored.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CreateLap {
+    pub race_id: i32,
+    pub node_index: i32,
+    pub time: f64,
+    pub host_time: Option<f64>,
+    pub uncertainty: Option<f64>,
+}
+
+/// Recalculated laps of a race against the stored ones.
+#[derive(Debug, Serialize)]
+pub struct LapDiff {
+    pub race_id: i32,
+    /// Identifies this diff when accepting it.
+    pub token: String,
+    /// Laps the recalculation found that were not stored.
+    pub added: Vec<CreateLap>,
+    /// Stored laps the recalculation did not find.
+    pub removed: Vec<Lap>,
+    /// Laps found by both, at another time.
+    pub changed: Vec<LapChange>,
+    pub unchanged: usize,
+    /// Corrections the race director made to the race's laps by hand; a
+    /// recalculation cannot be accepted over them.
+    pub hand_edits: usize,
+    /// Every recalculated lap.
+    pub laps: Vec<CreateLap>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LapChange {
+    pub live: Lap,
+    pub recalculated: CreateLap,
+}
+
+/// Lap the race director adds by hand, e.g. one a node missed.
+#[derive(Debug, Deserialize)]
+pub struct AddLap {
+    pub node_index: i32,
+    /// Crossing in seconds since the race start.
+    pub time: f64,
+    #[serde(flatten)]
+    pub note: EditNote,
+}
+
+/// New crossing time of a lap, e.g. when the pass was split or detected on
+/// the wrong peak.
+#[derive(Debug, Deserialize)]
+pub struct MoveLap {
+    pub time: f64,
+    #[serde(flatten)]
+    pub note: EditNote,
+}
+
+/// Who corrected the laps and why; every correction needs both.
+#[derive(Debug, Deserialize)]
+pub struct EditNote {
+    pub by: String,
+    pub reason: String,
+}
+
+/// Correction made to a race's laps. `action` is `add`, `move` or `delete`
+/// for those made by hand and `recalculate` for those of an accepted
+/// recalculation. `time` is the lap's crossing after it and `previous_time`
+/// before it; an added lap has no previous time, a deleted one no time.
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct LapEdit {
+    pub id: i32,
+    pub race_id: i32,
+    pub lap_id: i32,
+    pub node_index: i32,
+    pub action: String,
+    pub time: Option<f64>,
+    pub previous_time: Option<f64>,
+    pub by: String,
+    pub reason: String,
+    pub edited_at: String,
+}
+
+/// A correction with the race's laps and leaderboard after it.
+#[derive(Debug, Serialize, Clone)]
+pub struct LapCorrection {
+    pub edit: LapEdit,
+    pub laps: Vec<Lap>,
+    pub leaderboard: Vec<Standing>,
+}
+
+/// An accepted recalculation, with the race's laps and leaderboard after it.
+#[derive(Debug, Serialize, Clone)]
+pub struct LapRecalculation {
+    pub race_id: i32,
+    pub edits: Vec<LapEdit>,
+    pub laps: Vec<Lap>,
+    pub leaderboard: Vec<Standing>,
+}
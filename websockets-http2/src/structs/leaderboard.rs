@@ -0,0 +1,17 @@
+use serde::Serialize;
+
+/// A node's place in a race, from its stored laps as its format counts them.
+#[derive(Debug, Serialize, Clone)]
+pub struct Standing {
+    /// From 1, in the order of the race format: most laps first, then the
+    /// earliest to complete them, unless the format ranks by lap times.
+    pub position: usize,
+    pub node_index: i32,
+    pub laps: usize,
+    /// Crossing of the last lap, in seconds since the race start.
+    pub time: f64,
+    pub best_lap: Option<f64>,
+    pub last_lap: Option<f64>,
+    /// Fastest run of consecutive laps, when the format ranks by it.
+    pub consecutive: Option<f64>,
+}
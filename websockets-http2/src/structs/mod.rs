@@ -1,4 +1,11 @@
+pub mod calibration;
+pub mod frequency;
+pub mod lap;
+pub mod leaderboard;
 pub mod node;
+pub mod node_event;
+pub mod node_status;
 pub mod post;
 pub mod race;
+pub mod rssi;
 pub mod state;
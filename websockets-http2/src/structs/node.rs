@@ -4,6 +4,7 @@ use sqlx::FromRow;
 #[derive(Debug, Serialize, FromRow, Clone)]
 pub struct Node {
     pub id: i32,
+    pub node_index: i32,
     pub peak: u32,
     pub time: f64,
     pub duration: f64,
@@ -12,6 +13,7 @@ pub struct Node {
 
 #[derive(Debug, Deserialize)]
 pub struct CreateNode {
+    pub node_index: i32,
     pub peak: u32,
     pub time: f64,
     pub duration: f64,
@@ -21,6 +23,7 @@ pub struct CreateNode {
 #[derive(Debug, Serialize)]
 pub struct NodeJson {
     pub id: i32,
+    pub node_index: i32,
     pub peak: u32,
     pub time: f64,
     pub duration: f64,
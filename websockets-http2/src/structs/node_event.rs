@@ -0,0 +1,13 @@
+use serde::Serialize;
+use sqlx::FromRow;
+
+/// Link change of a node during a race.
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct NodeEvent {
+    pub id: i32,
+    pub race_id: i32,
+    pub node_index: i32,
+    pub event: String,
+    pub time: f64,
+    pub detail: Option<String>,
+}
@@ -0,0 +1,92 @@
+use crate::filter::FilterConfig;
+use rustimer::firmware::Firmware;
+use rustimer::stream::StreamStats;
+use serde::Serialize;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct NodeStatus {
+    pub index: usize,
+    pub port: String,
+    pub serial_number: Option<String>,
+    pub slot: Option<u8>,
+    pub connected: bool,
+    pub firmware: FirmwareStatus,
+    pub clock_drift_ppm: Option<f64>,
+    pub clock_round_trip_ms: Option<f64>,
+    pub frequency: Option<u16>,
+    pub rssi: Option<u32>,
+    /// Last RSSI after the filters, as detection sees it.
+    pub filtered_rssi: Option<f64>,
+    pub filters: Vec<FilterConfig>,
+    /// Round trip to network nodes.
+    pub latency_ms: Option<f64>,
+    pub samples: u64,
+    /// Samples per second over the last couple of seconds.
+    pub sample_rate: Option<f64>,
+    /// Whether the node pushes its samples instead of being polled.
+    pub streaming: bool,
+    /// Counters of the device stream, while streaming.
+    pub stream: Option<StreamStatus>,
+    pub pilot: Option<String>,
+    /// Thresholds of host-side lap detection, when it runs.
+    pub enter_rssi: Option<u32>,
+    pub exit_rssi: Option<u32>,
+    /// Whether the thresholds come from a calibration.
+    pub calibrated: bool,
+    pub calibrating: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct FirmwareStatus {
+    /// Version word as reported, `None` for firmware without the version
+    /// command.
+    pub version: Option<u16>,
+    pub api_level: u8,
+    /// Whether the server knows this version; newer ones run at the latest
+    /// API level it knows.
+    pub known: bool,
+    pub millis: bool,
+    pub lap_counter: bool,
+    pub slots: bool,
+    pub streaming: bool,
+    pub max_nodes: u8,
+}
+
+impl From<Firmware> for FirmwareStatus {
+    fn from(firmware: Firmware) -> Self {
+        FirmwareStatus {
+            version: firmware.version,
+            api_level: firmware.api_level,
+            known: firmware.known,
+            millis: firmware.capabilities.millis,
+            lap_counter: firmware.capabilities.lap_counter,
+            slots: firmware.capabilities.slots,
+            streaming: firmware.capabilities.streaming,
+            max_nodes: firmware.capabilities.max_nodes,
+        }
+    }
+}
+
+/// Counters of a device stream since it was opened.
+#[derive(Debug, Serialize, Clone)]
+pub struct StreamStatus {
+    pub frames: u64,
+    /// Frames missing from the sequence, corrupt ones included.
+    pub dropped: u64,
+    /// Frames that failed their checksum.
+    pub corrupt: u64,
+    /// Bytes that were not part of a valid frame.
+    pub skipped_bytes: u64,
+}
+
+impl From<StreamStats> for StreamStatus {
+    fn from(stats: StreamStats) -> Self {
+        StreamStatus {
+            frames: stats.frames,
+            dropped: stats.dropped,
+            corrupt: stats.corrupt,
+            skipped_bytes: stats.skipped_bytes,
+        }
+    }
+}
@@ -1,14 +1,105 @@
+use crate::race_format::RaceFormat;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
+/// `start_time` is when the race was staged and `end_time` when it finished
+/// or was aborted, both RFC 3339. `format` is the race format as JSON; races
+/// stored before formats have none and ran open.
 #[derive(Debug, Serialize, FromRow, Clone)]
 pub struct Race {
     pub id: i32,
-    pub start_time: Vec<u8>,
-    pub end_time: Option<Vec<u8>>,
+    pub start_time: String,
+    pub end_time: Option<String>,
+    pub format: Option<String>,
 }
 
+/// Body of a stage request; races staged without one get the configured
+/// format.
 #[derive(Debug, Deserialize)]
-pub struct CreateRace {
-    pub start_time: Vec<u8>,
+pub struct StageRace {
+    pub format: Option<RaceFormat>,
+}
+
+/// Where the current race is in its lifecycle:
+/// idle → staging → countdown → running → overtime → finished, or aborted
+/// from any stage before finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RaceState {
+    /// No race since the server started.
+    Idle,
+    /// Pilots at the gate; the race exists but nothing is timed.
+    Staging,
+    /// Started, the race clock not running yet.
+    Countdown,
+    Running,
+    /// The race is decided; pilots finish the lap they are on.
+    Overtime,
+    Finished,
+    Aborted,
+}
+
+impl RaceState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RaceState::Idle => "idle",
+            RaceState::Staging => "staging",
+            RaceState::Countdown => "countdown",
+            RaceState::Running => "running",
+            RaceState::Overtime => "overtime",
+            RaceState::Finished => "finished",
+            RaceState::Aborted => "aborted",
+        }
+    }
+}
+
+/// State of the current race, as answered to the API and broadcast on every
+/// change.
+#[derive(Debug, Serialize, Clone)]
+pub struct RaceStatus {
+    pub race_id: Option<i32>,
+    pub state: RaceState,
+    /// When the race entered its state, RFC 3339.
+    pub changed_at: Option<String>,
+    /// Race clock at that moment, in seconds, once the race has run.
+    pub time: Option<f64>,
+    /// Seconds until the race moves on by itself, e.g. the end of the
+    /// countdown. Unset during a countdown ending after a random delay.
+    pub next_in: Option<f64>,
+    pub false_starts: Vec<FalseStart>,
+    pub format: RaceFormat,
+}
+
+/// Pilot who crossed the gate during the countdown. `time` is in seconds
+/// after the countdown was armed, the start signal not having come yet.
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct FalseStart {
+    pub race_id: i32,
+    pub node_index: i32,
+    pub pilot: Option<String>,
+    pub time: f64,
+    pub rssi: u32,
+}
+
+/// Cue of the start sequence, for clients to play tones on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CountdownSignal {
+    /// The countdown started; pilots arm.
+    Arm,
+    /// A whole second of the fixed countdown is left; `remaining` says how
+    /// many.
+    Tick,
+    /// The race clock started.
+    Start,
+}
+
+/// A state change of a race as stored.
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct RaceTransition {
+    pub id: i32,
+    pub race_id: i32,
+    pub state: String,
+    pub changed_at: String,
+    pub time: Option<f64>,
 }
@@ -0,0 +1,11 @@
+use serde::Serialize;
+
+/// One sample as read and after the node's filters, for comparing filter
+/// settings live. `time` is in seconds since the server started.
+#[derive(Debug, Clone, Serialize)]
+pub struct RssiSample {
+    pub node_index: usize,
+    pub time: f64,
+    pub raw: u32,
+    pub filtered: f64,
+}
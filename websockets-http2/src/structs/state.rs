@@ -9,3 +9,17 @@ pub struct AppState {
     pub tx: broadcast::Sender<String>,
     pub db: SqlitePool,
 }
+
+#[cfg(test)]
+impl AppState {
+    /// State over an empty database in memory, with no worker to command.
+    pub async fn in_memory() -> Self {
+        let (command_sender, _) = mpsc::channel(1);
+        let (tx, _) = broadcast::channel(16);
+        AppState {
+            command_sender,
+            tx,
+            db: crate::db::memory_db().await,
+        }
+    }
+}
@@ -1,28 +1,49 @@
+use crate::config::Config;
+use crate::db;
 use crate::enums::command::Command;
+use crate::enums::event::Event;
+use crate::node::replay::ReplaySession;
+use crate::race_format::RaceFormat;
+use crate::sampler;
 use sqlx::sqlite::SqlitePool;
-use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
-use tokio::task;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::{self, JoinSet};
 
-use crate::node;
-use crate::structs::node::CreateNode;
+use crate::lifecycle::{Due, RaceLifecycle};
+use crate::node_manager::{self, LinkEvent, NodeManager};
+use crate::structs::frequency::{NodeFilters, NodeFrequency};
+use crate::structs::lap::CreateLap;
 use crate::structs::node::Node;
+use crate::structs::node_event::NodeEvent;
 use crate::structs::post::CreatePost;
 use crate::structs::post::Post;
+use crate::structs::race::{CountdownSignal, FalseStart, RaceState, RaceStatus, RaceTransition};
 
-pub async fn worker_task(mut command_receiver: mpsc::Receiver<Command>, db_pool: SqlitePool) {
+pub async fn worker_task(
+    mut command_receiver: mpsc::Receiver<Command>,
+    db_pool: SqlitePool,
+    tx: broadcast::Sender<String>,
+    config: Config,
+    replay: Option<Arc<ReplaySession>>,
+) {
     let mut counter: u32 = 0;
-    let mut is_listening = false;
-    let port_name = "/dev/cu.usbserial-11230";
-    let baud_rate = 115200;
-    let port = Arc::new(Mutex::new(node::open_port(port_name, baud_rate).unwrap()));
-
-    let mut last_peak = 0;
-    let mut current_race_id = 0;
-    let mut race_start_time = std::time::Instant::now();
-    let mut last_peak_time = std::time::Instant::now();
-    let threshold = 3;
+    let mut nodes = NodeManager::open(&config, replay).unwrap();
+    retune_nodes(&mut nodes, &db_pool).await;
+    load_pass_profiles(&mut nodes, &db_pool).await;
+    load_filters(&mut nodes, &db_pool).await;
+
+    // Nodes are read on their own threads; a full queue holds them back
+    // rather than dropping samples.
+    let (sample_sender, mut samples) = mpsc::channel(sampler::QUEUE_LEN);
+    nodes.start_sampling(sample_sender);
+
+    let mut race = RaceLifecycle::new(config.race);
+    let mut reconnects = JoinSet::new();
     loop {
+        let next_reconnect = nodes.next_reconnect();
+        let next_race_change = race.next();
         tokio::select! {
                 Some(command) = command_receiver.recv() => {
                 match command {
@@ -55,51 +76,64 @@ pub async fn worker_task(mut command_receiver: mpsc::Receiver<Command>, db_pool:
                         );
                         let _ = respond_to.send(counter);
                     }
-                    Command::StartRace { time, race_id } => {
-                        is_listening = true;
-                        race_start_time = time;
-                        current_race_id = race_id;
+                    Command::StageRace { format, respond_to } => {
+                        let format = format.unwrap_or(config.race.format);
+                        let _ = respond_to.send(stage_race(&mut race, format, &tx, &db_pool).await);
+                    }
+                    Command::StartRace { time, countdown, respond_to } => {
+                        let result = start_race(&mut race, &mut nodes, time, countdown, &tx, &db_pool);
+                        let _ = respond_to.send(result);
+                    }
+                    Command::StopRace { time, respond_to } => {
+                        let result =
+                            change_race(&mut race, &mut nodes, RaceState::Finished, time, &tx, &db_pool);
+                        let _ = respond_to.send(result);
+                    }
+                    Command::AbortRace { time, respond_to } => {
+                        let result =
+                            change_race(&mut race, &mut nodes, RaceState::Aborted, time, &tx, &db_pool);
+                        let _ = respond_to.send(result);
+                    }
+                    Command::GetRace { respond_to } => {
+                        let _ = respond_to.send(race.status());
+                    }
+                    Command::GetNodes { respond_to } => {
+                        let _ = respond_to.send(nodes.status());
                     }
-                    Command::StopRace => {
-                        is_listening = false;
+                    Command::SetFrequency { node_index, frequency, respond_to } => {
+                        let result = tune_node(&mut nodes, node_index, frequency).await;
+                        let _ = respond_to.send(result);
+                    }
+                    Command::StartCalibration { node_index, pilot, noise_duration, respond_to } => {
+                        let result = nodes.start_calibration(node_index, pilot, noise_duration);
+                        let _ = respond_to.send(result);
+                    }
+                    Command::GetCalibration { node_index, respond_to } => {
+                        let _ = respond_to.send(nodes.calibration_status(node_index));
+                    }
+                    Command::FinishCalibration { node_index, respond_to } => {
+                        let _ = respond_to.send(nodes.finish_calibration(node_index));
+                    }
+                    Command::SetFilters { node_index, filters, respond_to } => {
+                        let _ = respond_to.send(nodes.set_filters(node_index, filters));
+                    }
+                    Command::SetPilot { node_index, pilot, profile, respond_to } => {
+                        let _ = respond_to.send(nodes.set_pass_profile(node_index, pilot, profile));
                     }
                 }
             },
-            Ok(peak) = {
-                let port_clone = Arc::clone(&port);
-                task::spawn_blocking(move || {
-                    let mut port_guard = port_clone.lock().unwrap();
-                    node::read_peak(&mut *port_guard)
-                })
-            }, if is_listening => {
-                // let peak = peak_result.unwrap();
-                if last_peak == 0 {
-                    last_peak = peak;
-                    last_peak_time = std::time::Instant::now();
-                }
-                if peak < last_peak.saturating_sub(threshold) || peak > last_peak.saturating_add(threshold)
-                {
-                    let duration = last_peak_time.elapsed().as_secs_f64();
-                    let start_time = race_start_time.elapsed().as_secs_f64() - duration;
-                    // let now = Utc::now();
-                    println!(
-                        "Peak: {} during {} seconds, {} nanoseconds",
-                        last_peak, duration, start_time
-                    );
-
-                    // let bytes = start_time.to_be_bytes();
-                    let new_node = CreateNode {
-                        peak: last_peak,
-                        time: start_time,
-                        duration,
-                        race_id: current_race_id,
+            Some(readings) = samples.recv() => {
+                for (index, result) in readings {
+                    let Some(new_node) = nodes.handle_reading(index, result, race.window()) else {
+                        continue;
                     };
 
                     let db_pool = db_pool.clone();
                     tokio::spawn(async move {
                         match sqlx::query_as::<_, Node>(
-                            "INSERT INTO node (peak, time, duration, race_id) VALUES (?, ?, ?, ?) RETURNING id, peak, time, duration, race_id",
+                            "INSERT INTO node (node_index, peak, time, duration, race_id) VALUES (?, ?, ?, ?, ?) RETURNING id, node_index, peak, time, duration, race_id",
                         )
+                        .bind(new_node.node_index)
                         .bind(new_node.peak)
                         .bind(new_node.time)
                         .bind(new_node.duration)
@@ -110,21 +144,504 @@ pub async fn worker_task(mut command_receiver: mpsc::Receiver<Command>, db_pool:
                             Err(e) => eprintln!("Failed to save node: {}", e),
                         }
                     });
+                }
+                for lap in nodes.take_laps() {
+                    let change = race.record_lap(&lap);
+                    save_lap(lap, &tx, &db_pool);
+                    if let Some(to) = change {
+                        if let Err(e) = change_race(&mut race, &mut nodes, to, Instant::now(), &tx, &db_pool) {
+                            eprintln!("Worker: Failed to move the race on: {}", e);
+                        }
+                    }
+                }
+                for false_start in nodes.take_false_starts() {
+                    race.flag_false_start(false_start.clone());
+                    save_false_start(false_start, &tx, &db_pool);
+                }
+                let samples = nodes.take_rssi();
+                if tx.receiver_count() > 0 && !samples.is_empty() {
+                    let _ = tx.send(serde_json::to_string(&Event::Rssi { samples }).unwrap());
+                }
+            }
+            _ = tokio::time::sleep_until(
+                next_reconnect
+                    .unwrap_or_else(|| Instant::now() + Duration::from_secs(3600))
+                    .into(),
+            ), if next_reconnect.is_some() => {
+                for (device, backend) in nodes.start_reconnects() {
+                    let sampling = config.sampling;
+                    reconnects.spawn_blocking(move || {
+                        let result =
+                            node_manager::open_device(backend.lock().unwrap().as_mut(), sampling);
+                        (device, result)
+                    });
+                }
+            }
+            _ = tokio::time::sleep_until(
+                next_race_change
+                    .unwrap_or_else(|| Instant::now() + Duration::from_secs(3600))
+                    .into(),
+            ), if next_race_change.is_some() => {
+                while let Some(due) = race.take_due(Instant::now()) {
+                    match due {
+                        Due::Tick(remaining) => {
+                            send_countdown(&race, CountdownSignal::Tick, Some(remaining), &tx);
+                        }
+                        Due::Change(at, to) => {
+                            if let Err(e) = change_race(&mut race, &mut nodes, to, at, &tx, &db_pool) {
+                                eprintln!("Worker: Failed to move the race on: {}", e);
+                            }
+                        }
+                    }
+                }
+            }
+            Some(Ok((device, result))) = reconnects.join_next() => {
+                for (index, frequency) in nodes.finish_reconnect(device, result) {
+                    if let Err(e) = tune_node(&mut nodes, index, frequency).await {
+                        eprintln!("Worker: Failed to retune node {}: {}", index, e);
+                    }
+                }
+            }
+        }
+
+        let running = race.running().map(|race| (race.id, race.start));
+        for event in nodes.take_link_events() {
+            publish_link_event(event, running, &tx, &db_pool);
+        }
+    }
+}
+
+async fn stage_race(
+    race: &mut RaceLifecycle,
+    format: RaceFormat,
+    tx: &broadcast::Sender<String>,
+    db_pool: &SqlitePool,
+) -> Result<RaceStatus, String> {
+    race.check(RaceState::Staging)?;
+    let stored = db::create_race(db_pool, format)
+        .await
+        .map_err(|e| format!("failed to create the race: {}", e))?;
+    race.stage(stored.id, format, Instant::now())?;
+    Ok(publish_race(race, tx, db_pool))
+}
+
+/// Arm the staged race and watch the gate for false starts until the start
+/// signal.
+fn start_race(
+    race: &mut RaceLifecycle,
+    nodes: &mut NodeManager,
+    time: Instant,
+    countdown: Option<Duration>,
+    tx: &broadcast::Sender<String>,
+    db_pool: &SqlitePool,
+) -> Result<RaceStatus, String> {
+    race.start(time, countdown)?;
+    nodes.reset();
+    nodes.set_racing(true);
+    if let (Some(race_id), Some((start, _))) = (race.race_id(), race.next_change()) {
+        nodes.start_countdown(race_id, time, start);
+    }
+    send_countdown(race, CountdownSignal::Arm, None, tx);
+    Ok(publish_race(race, tx, db_pool))
+}
+
+fn send_countdown(
+    race: &RaceLifecycle,
+    signal: CountdownSignal,
+    remaining: Option<u32>,
+    tx: &broadcast::Sender<String>,
+) {
+    let message = Event::Countdown {
+        race_id: race.race_id().unwrap_or_default(),
+        signal,
+        remaining,
+    };
+    let _ = tx.send(serde_json::to_string(&message).unwrap());
+}
+
+/// Move the race on, starting or stopping the nodes' timing with its clock.
+fn change_race(
+    race: &mut RaceLifecycle,
+    nodes: &mut NodeManager,
+    to: RaceState,
+    time: Instant,
+    tx: &broadcast::Sender<String>,
+    db_pool: &SqlitePool,
+) -> Result<RaceStatus, String> {
+    race.check(to)?;
+    let race_id = race.race_id().unwrap_or_default();
+    if matches!(to, RaceState::Running | RaceState::Aborted) {
+        nodes.end_countdown();
+    }
+    let time = match to {
+        RaceState::Running => {
+            let start = nodes.record_race_start(race_id, race.format(), time);
+            nodes.reset();
+            nodes.set_racing(true);
+            start
+        }
+        RaceState::Finished | RaceState::Aborted if race.running().is_some() => {
+            nodes.set_racing(false);
+            nodes.record_race_stop(race_id, time)
+        }
+        RaceState::Aborted if race.state() == RaceState::Countdown => {
+            nodes.set_racing(false);
+            time
+        }
+        _ => time,
+    };
+    let stopping =
+        matches!(to, RaceState::Finished | RaceState::Aborted) && race.running().is_some();
+    race.transition(to, time)?;
+    if to == RaceState::Running {
+        send_countdown(race, CountdownSignal::Start, None, tx);
+    }
+    if let Some(window) = race.window().filter(|_| stopping) {
+        // Laps crossed in the last second are held back until samples move
+        // past them, and none will.
+        nodes.flush_laps(window);
+        for lap in nodes.take_laps() {
+            save_lap(lap, tx, db_pool);
+        }
+    }
+    Ok(publish_race(race, tx, db_pool))
+}
 
-                    last_peak = peak;
-                    last_peak_time = std::time::Instant::now();
+/// Store a false start against its race and broadcast it.
+fn save_false_start(false_start: FalseStart, tx: &broadcast::Sender<String>, db_pool: &SqlitePool) {
+    let _ = tx.send(serde_json::to_string(&Event::FalseStart(false_start.clone())).unwrap());
+    let db_pool = db_pool.clone();
+    tokio::spawn(async move {
+        let saved = sqlx::query(
+            "INSERT INTO false_start (race_id, node_index, pilot, time, rssi) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(false_start.race_id)
+        .bind(false_start.node_index)
+        .bind(&false_start.pilot)
+        .bind(false_start.time)
+        .bind(false_start.rssi)
+        .execute(&db_pool)
+        .await;
+        if let Err(e) = saved {
+            eprintln!("Failed to save false start: {}", e);
+        }
+    });
+}
+
+/// Broadcast the race's new state and store the change against the race.
+fn publish_race(
+    race: &RaceLifecycle,
+    tx: &broadcast::Sender<String>,
+    db_pool: &SqlitePool,
+) -> RaceStatus {
+    let status = race.status();
+    let _ = tx.send(serde_json::to_string(&Event::Race(status.clone())).unwrap());
+
+    let (Some(race_id), Some(changed_at)) = (status.race_id, status.changed_at.clone()) else {
+        return status;
+    };
+    let (state, time) = (status.state, status.time);
+    let db_pool = db_pool.clone();
+    tokio::spawn(async move {
+        let saved = sqlx::query_as::<_, RaceTransition>(
+            "INSERT INTO race_transition (race_id, state, changed_at, time) VALUES (?, ?, ?, ?) RETURNING id, race_id, state, changed_at, time",
+        )
+        .bind(race_id)
+        .bind(state.as_str())
+        .bind(&changed_at)
+        .bind(time)
+        .fetch_one(&db_pool)
+        .await;
+        if let Err(e) = saved {
+            eprintln!("Failed to save race transition: {}", e);
+            return;
+        }
+        if matches!(state, RaceState::Finished | RaceState::Aborted) {
+            let ended = sqlx::query("UPDATE race SET end_time = ? WHERE id = ?")
+                .bind(&changed_at)
+                .bind(race_id)
+                .execute(&db_pool)
+                .await;
+            if let Err(e) = ended {
+                eprintln!("Failed to save the end of race {}: {}", race_id, e);
+            }
+        }
+    });
+    status
+}
+
+/// Broadcast a link change and, during a race, store it against the race.
+fn publish_link_event(
+    event: LinkEvent,
+    race: Option<(i32, Instant)>,
+    tx: &broadcast::Sender<String>,
+    db_pool: &SqlitePool,
+) {
+    let (node_index, at, name, error) = match event {
+        LinkEvent::Disconnected {
+            node_index,
+            at,
+            error,
+        } => (node_index, at, "node_disconnected", Some(error)),
+        LinkEvent::Reconnected { node_index, at } => (node_index, at, "node_reconnected", None),
+    };
+    let race_id = race.map(|(race_id, _)| race_id);
+    let time = race.map(|(_, start)| at.saturating_duration_since(start).as_secs_f64());
+
+    let message = match error.clone() {
+        Some(error) => Event::NodeDisconnected {
+            node_index,
+            race_id,
+            time,
+            error,
+        },
+        None => Event::NodeReconnected {
+            node_index,
+            race_id,
+            time,
+        },
+    };
+    let _ = tx.send(serde_json::to_string(&message).unwrap());
+
+    let (Some(race_id), Some(time)) = (race_id, time) else {
+        return;
+    };
+    let db_pool = db_pool.clone();
+    tokio::spawn(async move {
+        match sqlx::query_as::<_, NodeEvent>(
+            "INSERT INTO node_event (race_id, node_index, event, time, detail) VALUES (?, ?, ?, ?, ?) RETURNING id, race_id, node_index, event, time, detail",
+        )
+        .bind(race_id)
+        .bind(node_index as i32)
+        .bind(name)
+        .bind(time)
+        .bind(error)
+        .fetch_one(&db_pool)
+        .await
+        {
+            Ok(event) => println!("Saved node event: {:?}", event),
+            Err(e) => eprintln!("Failed to save node event: {}", e),
+        }
+    });
+}
+
+/// Store a node lap and broadcast it once it has its id and number.
+fn save_lap(lap: CreateLap, tx: &broadcast::Sender<String>, db_pool: &SqlitePool) {
+    let tx = tx.clone();
+    let db_pool = db_pool.clone();
+    tokio::spawn(async move {
+        match db::insert_lap(&db_pool, &lap).await {
+            Ok(lap) => {
+                println!("Saved lap: {:?}", lap);
+                let _ = tx.send(serde_json::to_string(&Event::Lap(lap)).unwrap());
+            }
+            Err(e) => eprintln!("Failed to save lap: {}", e),
+        }
+    });
+}
+
+async fn tune_node(nodes: &mut NodeManager, index: usize, frequency: u16) -> Result<u16, String> {
+    let reader = nodes
+        .reader(index)
+        .ok_or_else(|| format!("unknown node {}", index))?;
+    let tuned = task::spawn_blocking(move || reader.set_frequency(frequency))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    println!("Worker: Node {} tuned to {} MHz", index, tuned);
+    nodes.set_frequency(index, tuned);
+    Ok(tuned)
+}
+
+/// Re-applies the frequencies saved through the API, since the receivers do
+/// not keep their tuning across a power cycle.
+async fn retune_nodes(nodes: &mut NodeManager, db_pool: &SqlitePool) {
+    let saved = sqlx::query_as::<_, NodeFrequency>(
+        "SELECT node_index, frequency FROM node_frequency ORDER BY node_index",
+    )
+    .fetch_all(db_pool)
+    .await;
+
+    match saved {
+        Ok(saved) => {
+            for entry in saved {
+                if let Err(e) = tune_node(nodes, entry.node_index as usize, entry.frequency).await {
+                    eprintln!("Worker: Failed to retune node {}: {}", entry.node_index, e);
                 }
-                // if last_peak_time.elapsed().as_secs_f64() > 1.0 {
-                //     let duration = last_peak_time.elapsed().as_secs_f64();
-                //     let start_time = race_start_time.elapsed().as_nanos();
-                //     println!(
-                //         "Peak: {} during {} seconds, {} nanoseconds",
-                //         last_peak, duration, start_time
-                //     );
-                //     last_peak_time = std::time::Instant::now();
-                // }
+            }
+        }
+        Err(e) => eprintln!("Worker: Failed to load node frequencies: {}", e),
+    }
+}
 
+/// Puts every node back on the pilot and calibrated profile it had.
+async fn load_pass_profiles(nodes: &mut NodeManager, db_pool: &SqlitePool) {
+    for index in 0..nodes.node_count() {
+        let loaded = async {
+            let pilot = db::node_pilot(db_pool, index as i32).await?;
+            let profile = db::pass_profile(db_pool, index as i32, pilot.as_deref()).await?;
+            Ok::<_, sqlx::Error>((pilot, profile))
+        };
+        match loaded.await {
+            Ok((None, None)) => {}
+            Ok((pilot, profile)) => {
+                let _ = nodes.set_pass_profile(index, pilot, profile);
             }
+            Err(e) => eprintln!(
+                "Worker: Failed to load the pass profile of node {}: {}",
+                index, e
+            ),
         }
     }
 }
+
+/// Puts back the filters set through the API, over the configured ones.
+async fn load_filters(nodes: &mut NodeManager, db_pool: &SqlitePool) {
+    let saved = sqlx::query_as::<_, NodeFilters>(
+        "SELECT node_index, filters FROM node_filters ORDER BY node_index",
+    )
+    .fetch_all(db_pool)
+    .await;
+
+    match saved {
+        Ok(saved) => {
+            for entry in saved {
+                let filters = serde_json::from_str(&entry.filters).map_err(|e| e.to_string());
+                if let Err(e) = filters
+                    .and_then(|filters| nodes.set_filters(entry.node_index as usize, filters))
+                {
+                    eprintln!(
+                        "Worker: Failed to restore the filters of node {}: {}",
+                        entry.node_index, e
+                    );
+                }
+            }
+        }
+        Err(e) => eprintln!("Worker: Failed to load node filters: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BackendKind, NodeConfig, RaceConfig};
+
+    fn nodes() -> NodeManager {
+        let config = Config {
+            backend: BackendKind::Mock,
+            nodes: vec![NodeConfig::new("mock")],
+            ..Config::default()
+        };
+        NodeManager::open(&config, None).unwrap()
+    }
+
+    /// Transitions stored for `race_id`, once there are `count` of them;
+    /// they are written from spawned tasks.
+    async fn transitions(pool: &SqlitePool, race_id: i32, count: usize) -> Vec<RaceTransition> {
+        for _ in 0..100 {
+            let stored = sqlx::query_as::<_, RaceTransition>(
+                "SELECT * FROM race_transition WHERE race_id = ? ORDER BY id",
+            )
+            .bind(race_id)
+            .fetch_all(pool)
+            .await
+            .unwrap();
+            if stored.len() >= count {
+                return stored;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("race {} has fewer than {} transitions", race_id, count);
+    }
+
+    async fn end_time(pool: &SqlitePool, race_id: i32) -> Option<String> {
+        for _ in 0..100 {
+            let (end_time,): (Option<String>,) =
+                sqlx::query_as("SELECT end_time FROM race WHERE id = ?")
+                    .bind(race_id)
+                    .fetch_one(pool)
+                    .await
+                    .unwrap();
+            if end_time.is_some() {
+                return end_time;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn stores_every_transition_and_the_end() {
+        let pool = db::memory_db().await;
+        let (tx, _rx) = broadcast::channel(64);
+        let mut nodes = nodes();
+        let mut race = RaceLifecycle::new(RaceConfig::default());
+
+        let staged = stage_race(&mut race, RaceFormat::Open, &tx, &pool)
+            .await
+            .unwrap();
+        let race_id = staged.race_id.unwrap();
+        let start = Instant::now();
+        let countdown = Some(Duration::from_secs(3));
+        start_race(&mut race, &mut nodes, start, countdown, &tx, &pool).unwrap();
+        let running = start + Duration::from_secs(3);
+        change_race(
+            &mut race,
+            &mut nodes,
+            RaceState::Running,
+            running,
+            &tx,
+            &pool,
+        )
+        .unwrap();
+        let finished = running + Duration::from_secs(7);
+        change_race(
+            &mut race,
+            &mut nodes,
+            RaceState::Finished,
+            finished,
+            &tx,
+            &pool,
+        )
+        .unwrap();
+
+        let stored = transitions(&pool, race_id, 4).await;
+        let states: Vec<&str> = stored.iter().map(|row| row.state.as_str()).collect();
+        assert_eq!(states, ["staging", "countdown", "running", "finished"]);
+        let times: Vec<Option<f64>> = stored.iter().map(|row| row.time).collect();
+        assert_eq!(times, [None, None, Some(0.0), Some(7.0)]);
+        let ended = end_time(&pool, race_id).await;
+        assert_eq!(ended.as_ref(), Some(&stored[3].changed_at));
+    }
+
+    #[tokio::test]
+    async fn ends_a_race_aborted_in_its_countdown() {
+        let pool = db::memory_db().await;
+        let (tx, _rx) = broadcast::channel(64);
+        let mut nodes = nodes();
+        let mut race = RaceLifecycle::new(RaceConfig::default());
+
+        let staged = stage_race(&mut race, RaceFormat::Open, &tx, &pool)
+            .await
+            .unwrap();
+        let race_id = staged.race_id.unwrap();
+        let start = Instant::now();
+        start_race(&mut race, &mut nodes, start, None, &tx, &pool).unwrap();
+        assert!(change_race(
+            &mut race,
+            &mut nodes,
+            RaceState::Finished,
+            start,
+            &tx,
+            &pool
+        )
+        .is_err());
+        change_race(&mut race, &mut nodes, RaceState::Aborted, start, &tx, &pool).unwrap();
+
+        let stored = transitions(&pool, race_id, 3).await;
+        let states: Vec<&str> = stored.iter().map(|row| row.state.as_str()).collect();
+        assert_eq!(states, ["staging", "countdown", "aborted"]);
+        assert!(end_time(&pool, race_id).await.is_some());
+    }
+}